@@ -0,0 +1,16 @@
+-- Add migration script here
+CREATE TABLE outbound_messages (
+    id BIGSERIAL PRIMARY KEY,
+    chat_id BIGINT NOT NULL,
+    text TEXT NOT NULL,
+    parse_mode VARCHAR(20),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    sent_at TIMESTAMPTZ,
+    failed_at TIMESTAMPTZ
+);
+
+CREATE INDEX outbound_messages_pending_idx
+    ON outbound_messages (id)
+    WHERE sent_at IS NULL AND failed_at IS NULL;
@@ -0,0 +1,20 @@
+-- Add migration script here
+CREATE TABLE scheduled_jobs (
+    name VARCHAR(64) PRIMARY KEY,
+    last_run_at TIMESTAMPTZ,
+    last_success BOOLEAN,
+    last_result TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE job_runs (
+    id BIGSERIAL PRIMARY KEY,
+    job_name VARCHAR(64) NOT NULL REFERENCES scheduled_jobs(name),
+    trigger VARCHAR(20) NOT NULL,
+    started_at TIMESTAMPTZ NOT NULL,
+    finished_at TIMESTAMPTZ NOT NULL,
+    success BOOLEAN NOT NULL,
+    result TEXT
+);
+
+CREATE INDEX job_runs_job_name_idx ON job_runs (job_name, started_at DESC);
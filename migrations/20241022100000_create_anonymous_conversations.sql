@@ -0,0 +1,16 @@
+-- Add migration script here
+CREATE TABLE anonymous_conversations (
+    id BIGSERIAL PRIMARY KEY,
+    sender_chat_id BIGINT NOT NULL,
+    recipient_chat_id BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_message_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE relayed_messages (
+    chat_id BIGINT NOT NULL,
+    message_id INTEGER NOT NULL,
+    conversation_id BIGINT NOT NULL REFERENCES anonymous_conversations(id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (chat_id, message_id)
+);
@@ -0,0 +1,12 @@
+-- Add migration script here
+CREATE TABLE deep_link_tokens (
+    token VARCHAR(64) PRIMARY KEY,
+    recipient_chat_id BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ,
+    max_uses INTEGER,
+    use_count INTEGER NOT NULL DEFAULT 0,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX deep_link_tokens_recipient_idx ON deep_link_tokens (recipient_chat_id);
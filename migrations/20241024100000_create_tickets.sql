@@ -0,0 +1,15 @@
+-- Add migration script here
+CREATE TABLE tickets (
+    id BIGSERIAL PRIMARY KEY,
+    conversation_id BIGINT NOT NULL UNIQUE REFERENCES anonymous_conversations(id) ON DELETE CASCADE,
+    status VARCHAR(20) NOT NULL DEFAULT 'open',
+    assigned_chat_id BIGINT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    waiting_since TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    first_response_at TIMESTAMPTZ,
+    closed_at TIMESTAMPTZ,
+    sla_alerted_at TIMESTAMPTZ
+);
+
+CREATE INDEX tickets_status_idx ON tickets (status);
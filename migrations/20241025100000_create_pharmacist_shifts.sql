@@ -0,0 +1,14 @@
+-- Add migration script here
+CREATE TABLE pharmacist_shifts (
+    id BIGSERIAL PRIMARY KEY,
+    pharmacist_chat_id BIGINT NOT NULL,
+    pharmacist_name VARCHAR(255) NOT NULL,
+    -- 0 = Monday ... 6 = Sunday, in the pharmacy's time zone
+    weekday SMALLINT NOT NULL CHECK (weekday BETWEEN 0 AND 6),
+    starts_at TIME NOT NULL,
+    ends_at TIME NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CHECK (starts_at < ends_at)
+);
+
+CREATE INDEX pharmacist_shifts_weekday_idx ON pharmacist_shifts (weekday, starts_at);
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE outbound_messages ADD COLUMN message_thread_id INTEGER;
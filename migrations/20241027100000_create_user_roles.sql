@@ -0,0 +1,7 @@
+-- Add migration script here
+CREATE TABLE user_roles (
+    user_id BIGINT PRIMARY KEY,
+    role VARCHAR(20) NOT NULL,
+    granted_by BIGINT,
+    granted_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
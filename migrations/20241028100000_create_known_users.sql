@@ -0,0 +1,10 @@
+-- Add migration script here
+CREATE TABLE known_users (
+    user_id BIGINT PRIMARY KEY,
+    username VARCHAR(32),
+    first_name VARCHAR(255) NOT NULL,
+    last_name VARCHAR(255),
+    last_seen_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX known_users_username_idx ON known_users (LOWER(username));
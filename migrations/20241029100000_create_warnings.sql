@@ -0,0 +1,11 @@
+-- Add migration script here
+CREATE TABLE warnings (
+    id BIGSERIAL PRIMARY KEY,
+    chat_id BIGINT NOT NULL,
+    user_id BIGINT NOT NULL,
+    reason TEXT,
+    issued_by BIGINT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX warnings_chat_user_idx ON warnings (chat_id, user_id);
@@ -0,0 +1,16 @@
+-- Add migration script here
+CREATE TABLE moderation_actions (
+    id BIGSERIAL PRIMARY KEY,
+    chat_id BIGINT NOT NULL,
+    chat_title VARCHAR(255),
+    actor_id BIGINT,
+    actor_name VARCHAR(255),
+    target_id BIGINT NOT NULL,
+    target_name VARCHAR(255) NOT NULL,
+    action VARCHAR(20) NOT NULL,
+    duration_seconds BIGINT,
+    reason TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX moderation_actions_target_idx ON moderation_actions (target_id, created_at DESC);
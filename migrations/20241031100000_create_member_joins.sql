@@ -0,0 +1,7 @@
+-- Add migration script here
+CREATE TABLE member_joins (
+    chat_id BIGINT NOT NULL,
+    user_id BIGINT NOT NULL,
+    joined_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (chat_id, user_id)
+);
@@ -0,0 +1,13 @@
+-- Add migration script here
+CREATE TABLE join_challenges (
+    id BIGSERIAL PRIMARY KEY,
+    chat_id BIGINT NOT NULL,
+    user_id BIGINT NOT NULL,
+    message_id INTEGER,
+    expected SMALLINT NOT NULL,
+    status VARCHAR(20) NOT NULL DEFAULT 'pending',
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX join_challenges_pending_idx ON join_challenges (chat_id, user_id) WHERE status = 'pending';
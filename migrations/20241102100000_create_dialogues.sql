@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TABLE dialogues (
+    chat_id BIGINT PRIMARY KEY,
+    state TEXT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX dialogues_updated_at_idx ON dialogues (updated_at);
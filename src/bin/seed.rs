@@ -1,23 +1,9 @@
 use chrono::NaiveDate;
 use sqlx::PgPool;
-
-#[derive(sqlx::FromRow, serde::Serialize, serde::Deserialize, Debug, Clone)]
-struct Medicine {
-    pub id: i32,
-    pub name: String,
-    pub stock: i32,
-    pub expiry_date: chrono::NaiveDate,
-}
-
-#[derive(sqlx::FromRow, serde::Serialize, serde::Deserialize, Debug, Clone)]
-struct Order {
-    pub id: i32,
-    pub user_id: String,
-    pub medicine_id: i32,
-    pub quantity: i32,
-    pub status: String,
-    pub created_at: chrono::NaiveDate,
-}
+use telepharma::{
+    models::{Medicine, Order},
+    repositories::Repositories,
+};
 
 fn get_seed_data() -> (Vec<Medicine>, Vec<Order>) {
     let seed_medicines = vec![
@@ -169,35 +155,17 @@ fn get_seed_data() -> (Vec<Medicine>, Vec<Order>) {
     (seed_medicines, seed_orders)
 }
 
-pub async fn seed_database(pool: &PgPool) -> Result<(), sqlx::Error> {
+pub async fn seed_database(repositories: &Repositories) -> Result<(), sqlx::Error> {
     let (medicines, orders) = get_seed_data();
 
     // Seed medicines
     for medicine in medicines {
-        sqlx::query!(
-            "INSERT INTO medicines (id, name, stock, expiry_date) VALUES ($1, $2, $3, $4)",
-            medicine.id,
-            medicine.name,
-            medicine.stock,
-            medicine.expiry_date
-        )
-        .execute(pool)
-        .await?;
+        repositories.medicines.insert(medicine).await?;
     }
 
     // Seed orders
     for order in orders {
-        sqlx::query!(
-            "INSERT INTO orders (id, user_id, medicine_id, quantity, status, created_at) VALUES ($1, $2, $3, $4, $5, $6)",
-            order.id,
-            order.user_id,
-            order.medicine_id,
-            order.quantity,
-            order.status,
-            order.created_at
-        )
-        .execute(pool)
-        .await?;
+        repositories.orders.insert(order).await?;
     }
 
     Ok(())
@@ -207,6 +175,6 @@ pub async fn seed_database(pool: &PgPool) -> Result<(), sqlx::Error> {
 async fn main() -> Result<(), sqlx::Error> {
     dotenvy::dotenv().ok();
     let pool = PgPool::connect(&std::env::var("DATABASE_URL").unwrap()).await?;
-    seed_database(&pool).await?;
+    seed_database(&Repositories::postgres(pool)).await?;
     Ok(())
 }
@@ -0,0 +1,394 @@
+use chrono::{DateTime, Duration, Utc};
+use rand::seq::SliceRandom;
+use sqlx::PgPool;
+use teloxide::{
+    prelude::*,
+    types::{ChatPermissions, InlineKeyboardButton, InlineKeyboardMarkup, MessageId, User},
+    RequestError,
+};
+
+use crate::{utils::format_duration, Error};
+
+/// The buttons a challenge is made of; one of them has to be tapped.
+const OPTIONS: [&str; 8] = ["💊", "🩺", "🌡", "💉", "🩹", "🧴", "🧪", "🦷"];
+
+/// The number of buttons shown with each challenge.
+const BUTTONS: usize = 4;
+
+/// Prefix of the callback data of challenge buttons.
+const CALLBACK_PREFIX: &str = "captcha";
+
+/// Where a join challenge stands.
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Eq)]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+pub enum ChallengeStatus {
+    /// Waiting for the new member to answer.
+    Pending,
+    /// Answered correctly; the member can talk.
+    Passed,
+    /// Answered wrongly, so the member was removed, or couldn't be enforced
+    /// or posted, so the member was let in.
+    Failed,
+    /// Not answered in time, or replaced by a newer challenge after the
+    /// member joined again.
+    Expired,
+}
+
+/// A challenge posted to a new member of a group.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct Challenge {
+    pub id: i64,
+    pub chat_id: i64,
+    pub user_id: i64,
+    /// The challenge message, once it has been posted.
+    pub message_id: Option<i32>,
+    /// Index into the options of the button to tap.
+    pub expected: i16,
+    pub status: ChallengeStatus,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Challenge {
+    pub fn chat(&self) -> ChatId {
+        ChatId(self.chat_id)
+    }
+
+    pub fn user(&self) -> UserId {
+        UserId(self.user_id as u64)
+    }
+}
+
+/// Verifies that people joining a group are people.
+///
+/// A new member is muted and asked to tap one of a few buttons. Tapping the
+/// right one lifts the mute; tapping a wrong one, or not answering before the
+/// timeout, removes them from the group, though they can join again.
+///
+/// Challenges are stored in the database and their timeouts rescheduled on
+/// start, so nobody stays muted because the bot restarted.
+#[derive(Clone)]
+pub struct Captcha {
+    bot: Bot,
+    pool: PgPool,
+    /// How long new members have to answer; `None` turns challenges off.
+    timeout: Option<Duration>,
+}
+
+impl Captcha {
+    /// Sets up join verification without touching pending challenges; see
+    /// [`Captcha::resume`].
+    ///
+    /// # Arguments
+    ///
+    /// * `bot` - The Bot instance used to restrict, release and remove members.
+    /// * `pool` - The database connection pool challenges are stored in.
+    /// * `timeout` - How long new members have to answer; `None` turns
+    ///   challenges off, though pending ones are still settled.
+    pub fn new(bot: Bot, pool: PgPool, timeout: Option<Duration>) -> Self {
+        Self { bot, pool, timeout }
+    }
+
+    /// Resumes the timeouts of challenges left pending by a previous run.
+    ///
+    /// Challenges that expired while the bot was down are settled right away.
+    pub async fn resume(&self) -> Result<(), sqlx::Error> {
+        let pending = sqlx::query_as::<_, Challenge>(
+            "SELECT * FROM join_challenges WHERE status = $1 ORDER BY expires_at",
+        )
+        .bind(ChallengeStatus::Pending)
+        .fetch_all(&self.pool)
+        .await?;
+
+        if !pending.is_empty() {
+            log::info!("Resuming {} pending join challenges", pending.len());
+        }
+
+        for challenge in pending {
+            self.schedule_expiry(challenge.id, challenge.expires_at);
+        }
+        Ok(())
+    }
+
+    /// Mutes a new member and posts their challenge.
+    ///
+    /// Does nothing when challenges are turned off. The challenge is stored
+    /// before the member is muted, so a database failure leaves them free to
+    /// talk. If the bot can't restrict members of the chat the member is let
+    /// in unchallenged, since they couldn't be held back anyway; if the
+    /// challenge can't be posted, the mute is lifted again.
+    pub async fn challenge(&self, chat: ChatId, user: &User) -> Result<(), Error> {
+        let Some(timeout) = self.timeout else {
+            return Ok(());
+        };
+
+        let (options, expected) = {
+            let mut rng = rand::thread_rng();
+            let mut options: Vec<usize> = (0..OPTIONS.len()).collect();
+            options.shuffle(&mut rng);
+            options.truncate(BUTTONS);
+            let expected = *options.choose(&mut rng).unwrap_or(&0);
+            (options, expected)
+        };
+        let expires_at = Utc::now() + timeout;
+
+        let mut tx = self.pool.begin().await?;
+        // A member who left and joined again only has to answer the new challenge
+        sqlx::query(
+            "UPDATE join_challenges SET status = $1
+             WHERE chat_id = $2 AND user_id = $3 AND status = $4",
+        )
+        .bind(ChallengeStatus::Expired)
+        .bind(chat.0)
+        .bind(user.id.0 as i64)
+        .bind(ChallengeStatus::Pending)
+        .execute(&mut *tx)
+        .await?;
+        let (id,): (i64,) = sqlx::query_as(
+            "INSERT INTO join_challenges (chat_id, user_id, expected, expires_at)
+             VALUES ($1, $2, $3, $4) RETURNING id",
+        )
+        .bind(chat.0)
+        .bind(user.id.0 as i64)
+        .bind(expected as i16)
+        .bind(expires_at)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        // Stored and scheduled before the member is muted, so they aren't left
+        // muted if a later step fails
+        self.schedule_expiry(id, expires_at);
+
+        if let Err(e) = self
+            .bot
+            .restrict_chat_member(chat, user.id, ChatPermissions::empty())
+            .await
+        {
+            log::warn!(
+                "Failed to restrict new member {} in chat {}: {}",
+                user.id,
+                chat,
+                e
+            );
+            self.settle(id, ChallengeStatus::Failed).await?;
+            return Ok(());
+        }
+
+        let buttons = options.iter().map(|&option| {
+            InlineKeyboardButton::callback(
+                OPTIONS[option],
+                format!("{}:{}:{}", CALLBACK_PREFIX, id, option),
+            )
+        });
+        let sent = self
+            .bot
+            .send_message(
+                chat,
+                format!(
+                    "Welcome, {}! To show you're not a bot, tap {} within {}. Until then you can't send messages.",
+                    user.full_name(),
+                    OPTIONS[expected],
+                    format_duration(timeout)
+                ),
+            )
+            .reply_markup(InlineKeyboardMarkup::new([buttons]))
+            .await;
+        let message = match sent {
+            Ok(message) => message,
+            Err(e) => {
+                // A challenge nobody can see can't be answered, so let the member in
+                if self.settle(id, ChallengeStatus::Failed).await? {
+                    self.release(chat, user.id).await?;
+                }
+                return Err(e.into());
+            }
+        };
+
+        if let Err(e) = sqlx::query("UPDATE join_challenges SET message_id = $1 WHERE id = $2")
+            .bind(message.id.0)
+            .bind(id)
+            .execute(&self.pool)
+            .await
+        {
+            // The timeout still settles the challenge, only the message stays
+            log::error!(
+                "Failed to record the message of join challenge {}: {}",
+                id,
+                e
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether a callback query comes from a challenge button.
+    pub fn is_challenge_answer(query: &CallbackQuery) -> bool {
+        query
+            .data
+            .as_deref()
+            .is_some_and(|data| data.starts_with(CALLBACK_PREFIX))
+    }
+
+    /// Handles a tap on a challenge button.
+    ///
+    /// Only the member being challenged can answer. The right button lifts
+    /// the mute, restoring the chat's default permissions as `/unmute` does;
+    /// a wrong one removes the member.
+    pub async fn answer(&self, query: &CallbackQuery) -> Result<(), Error> {
+        let Some((id, option)) = query.data.as_deref().and_then(parse_callback_data) else {
+            self.bot.answer_callback_query(&query.id).await?;
+            return Ok(());
+        };
+
+        let challenge =
+            sqlx::query_as::<_, Challenge>("SELECT * FROM join_challenges WHERE id = $1")
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?;
+        let Some(challenge) = challenge.filter(|c| c.status == ChallengeStatus::Pending) else {
+            self.bot
+                .answer_callback_query(&query.id)
+                .text("This challenge is over.")
+                .await?;
+            return Ok(());
+        };
+        if challenge.user() != query.from.id {
+            self.bot
+                .answer_callback_query(&query.id)
+                .text("This challenge isn't for you.")
+                .await?;
+            return Ok(());
+        }
+
+        let passed = option == challenge.expected as usize;
+        let status = if passed {
+            ChallengeStatus::Passed
+        } else {
+            ChallengeStatus::Failed
+        };
+        if !self.settle(challenge.id, status).await? {
+            // Settled in the meantime, e.g. by the timeout
+            self.bot.answer_callback_query(&query.id).await?;
+            return Ok(());
+        }
+
+        if passed {
+            self.release(challenge.chat(), challenge.user()).await?;
+            self.bot
+                .answer_callback_query(&query.id)
+                .text("Thanks, welcome to the group!")
+                .await?;
+        } else {
+            self.bot
+                .answer_callback_query(&query.id)
+                .text("That wasn't the right button. You can join again to retry.")
+                .await?;
+            self.remove(&challenge).await?;
+        }
+        self.delete_message(&challenge).await;
+        Ok(())
+    }
+
+    /// Removes the member of an unanswered challenge once it times out.
+    async fn expire(&self, id: i64) -> Result<(), Error> {
+        if !self.settle(id, ChallengeStatus::Expired).await? {
+            return Ok(());
+        }
+
+        let challenge =
+            sqlx::query_as::<_, Challenge>("SELECT * FROM join_challenges WHERE id = $1")
+                .bind(id)
+                .fetch_one(&self.pool)
+                .await?;
+        log::info!(
+            "User {} didn't answer the join challenge in chat {}",
+            challenge.user(),
+            challenge.chat()
+        );
+        self.remove(&challenge).await?;
+        self.delete_message(&challenge).await;
+        Ok(())
+    }
+
+    /// Moves a pending challenge to its final status.
+    ///
+    /// # Returns
+    ///
+    /// Returns `false` if the challenge was no longer pending, so that a tap
+    /// and the timeout can't both act on it.
+    async fn settle(&self, id: i64, status: ChallengeStatus) -> Result<bool, sqlx::Error> {
+        let result =
+            sqlx::query("UPDATE join_challenges SET status = $1 WHERE id = $2 AND status = $3")
+                .bind(status)
+                .bind(id)
+                .bind(ChallengeStatus::Pending)
+                .execute(&self.pool)
+                .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Lifts a new member's mute, restoring the chat's default permissions as
+    /// `/unmute` does.
+    async fn release(&self, chat: ChatId, user: UserId) -> Result<(), RequestError> {
+        let permissions = self
+            .bot
+            .get_chat(chat)
+            .await?
+            .permissions()
+            .unwrap_or_else(ChatPermissions::all);
+        self.bot
+            .restrict_chat_member(chat, user, permissions)
+            .await?;
+        Ok(())
+    }
+
+    /// Removes a member from the chat with a ban that is lifted right away,
+    /// so they can join again.
+    async fn remove(&self, challenge: &Challenge) -> Result<(), RequestError> {
+        self.bot
+            .ban_chat_member(challenge.chat(), challenge.user())
+            .await?;
+        self.bot
+            .unban_chat_member(challenge.chat(), challenge.user())
+            .only_if_banned(true)
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes the message of a settled challenge; failures are only logged.
+    async fn delete_message(&self, challenge: &Challenge) {
+        let Some(message_id) = challenge.message_id else {
+            return;
+        };
+        if let Err(e) = self
+            .bot
+            .delete_message(challenge.chat(), MessageId(message_id))
+            .await
+        {
+            log::warn!("Failed to delete join challenge {}: {}", challenge.id, e);
+        }
+    }
+
+    /// Settles a challenge when it expires, in the background.
+    fn schedule_expiry(&self, id: i64, expires_at: DateTime<Utc>) {
+        let captcha = self.clone();
+        tokio::spawn(async move {
+            let delay = (expires_at - Utc::now()).to_std().unwrap_or_default();
+            tokio::time::sleep(delay).await;
+            if let Err(e) = captcha.expire(id).await {
+                log::error!("Failed to expire join challenge {}: {}", id, e);
+            }
+        });
+    }
+}
+
+/// Parses the callback data of a challenge button into the challenge ID and
+/// the option tapped.
+fn parse_callback_data(data: &str) -> Option<(i64, usize)> {
+    let mut parts = data.split(':');
+    if parts.next()? != CALLBACK_PREFIX {
+        return None;
+    }
+    let id = parts.next()?.parse().ok()?;
+    let option = parts.next()?.parse().ok()?;
+    (option < OPTIONS.len()).then_some((id, option))
+}
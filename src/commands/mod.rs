@@ -0,0 +1,520 @@
+use chrono::{DateTime, Duration, Utc};
+use std::{fmt, str::FromStr};
+use teloxide::utils::command::{BotCommands, ParseError};
+
+use crate::{
+    moderation::ModerationArgs,
+    roles::{Restricted, Role},
+    utils,
+};
+
+#[derive(BotCommands, Debug, Clone)]
+#[command(
+    rename_rule = "lowercase",
+    description = "Available commands:",
+    parse_with = "split"
+)]
+pub enum Command {
+    #[command(description = "Start interacting with the pharmacy bot.")]
+    Start(String),
+    #[command(description = "Check the pharmacy inventory.")]
+    Inventory,
+    #[command(description = "Place a medicine order.")]
+    Order,
+    #[command(description = "Display the main menu.")]
+    Menu,
+    #[command(description = "Display help information about available commands.")]
+    Help,
+    #[command(description = "Get a link for receiving anonymous messages.")]
+    Message,
+    #[command(description = "List your active anonymous message links.")]
+    Links,
+    #[command(
+        description = "Revoke an anonymous message link, or \"all\" of them.",
+        parse_with = parse_optional_arg
+    )]
+    Revoke(Option<String>),
+    #[command(description = "Show your user ID and role.")]
+    WhoAmI,
+    #[command(
+        description = "Kick a user from the chat",
+        parse_with = parse_moderation_args
+    )]
+    Kick(ModerationArgs),
+    #[command(
+        description = "Ban a user from the chat",
+        parse_with = parse_timed_moderation_args
+    )]
+    Ban {
+        time: RestrictTime,
+        args: ModerationArgs,
+    },
+    #[command(
+        description = "Mute a user in the chat",
+        parse_with = parse_timed_moderation_args
+    )]
+    Mute {
+        time: RestrictTime,
+        args: ModerationArgs,
+    },
+    #[command(
+        description = "Lift a user's ban so they can rejoin the chat",
+        parse_with = parse_moderation_args
+    )]
+    Unban(ModerationArgs),
+    #[command(
+        description = "Let a muted user talk again",
+        parse_with = parse_moderation_args
+    )]
+    Unmute(ModerationArgs),
+    #[command(
+        description = "Warn a user; enough warnings mute or ban them",
+        parse_with = parse_moderation_args
+    )]
+    Warn(ModerationArgs),
+    #[command(
+        description = "List a user's warnings in this chat",
+        parse_with = parse_moderation_args
+    )]
+    Warnings(ModerationArgs),
+    #[command(
+        description = "Remove all of a user's warnings in this chat",
+        parse_with = parse_moderation_args
+    )]
+    ClearWarns(ModerationArgs),
+}
+
+/// Commands for pharmacy staff, kept apart from the customer-facing
+/// `Command` so they don't show up in `/help`.
+#[derive(BotCommands, Debug, Clone)]
+#[command(
+    rename_rule = "lowercase",
+    description = "Staff commands:",
+    parse_with = "split"
+)]
+pub enum StaffCommand {
+    #[command(description = "List scheduled jobs.")]
+    Jobs,
+    #[command(description = "Run a scheduled job now.")]
+    RunJob(String),
+    #[command(description = "List open consultation tickets.")]
+    Tickets,
+    #[command(description = "Close a consultation ticket.")]
+    Close(i64),
+    #[command(description = "Show the pharmacist rota.")]
+    Rota,
+    #[command(description = "Add a shift: <weekday> <HH:MM> <HH:MM>, replying to the pharmacist.")]
+    AddShift(String, String, String),
+    #[command(description = "Remove a shift from the rota.")]
+    RemoveShift(i64),
+    #[command(description = "List pharmacy staff and their roles.")]
+    Staff,
+    #[command(description = "Change a user's role: <user_id> <role>.")]
+    SetRole { user_id: u64, role: Role },
+    #[command(
+        description = "Show recent moderation actions against a user.",
+        parse_with = parse_moderation_args
+    )]
+    ModLog(ModerationArgs),
+}
+
+impl Restricted for Command {
+    fn required_role(&self) -> Role {
+        match self {
+            Command::Kick(_)
+            | Command::Ban { .. }
+            | Command::Mute { .. }
+            | Command::Unban(_)
+            | Command::Unmute(_)
+            | Command::Warn(_)
+            | Command::Warnings(_)
+            | Command::ClearWarns(_) => Role::Pharmacist,
+            _ => Role::Customer,
+        }
+    }
+}
+
+impl Restricted for StaffCommand {
+    fn required_role(&self) -> Role {
+        match self {
+            StaffCommand::Tickets
+            | StaffCommand::Close(_)
+            | StaffCommand::Rota
+            | StaffCommand::ModLog(_) => Role::Pharmacist,
+            StaffCommand::Jobs
+            | StaffCommand::RunJob(_)
+            | StaffCommand::AddShift(..)
+            | StaffCommand::RemoveShift(_)
+            | StaffCommand::Staff => Role::Manager,
+            StaffCommand::SetRole { .. } => Role::Admin,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnitOfTime {
+    Seconds,
+    Minutes,
+    Hours,
+    Days,
+    Weeks,
+}
+
+impl FromStr for UnitOfTime {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "w" | "week" | "weeks" => Ok(UnitOfTime::Weeks),
+            "d" | "day" | "days" => Ok(UnitOfTime::Days),
+            "h" | "hour" | "hours" => Ok(UnitOfTime::Hours),
+            "m" | "min" | "minute" | "minutes" => Ok(UnitOfTime::Minutes),
+            "s" | "sec" | "second" | "seconds" => Ok(UnitOfTime::Seconds),
+            _ => Err(format!(
+                "Unknown unit \"{}\". Allowed units: w, d, h, m, s",
+                s
+            )),
+        }
+    }
+}
+
+/// The shortest restriction Telegram doesn't treat as permanent.
+const MIN_RESTRICTION: Duration = Duration::seconds(30);
+
+/// Time allowed for a restriction request to reach Telegram.
+const REQUEST_LEEWAY: Duration = Duration::seconds(10);
+
+/// How long a ban or mute lasts.
+///
+/// Telegram treats restrictions shorter than 30 seconds or longer than 366
+/// days as permanent, so timed restrictions are kept within those bounds and
+/// a permanent one has to be asked for explicitly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RestrictTime {
+    For(Duration),
+    Permanent,
+}
+
+impl RestrictTime {
+    /// How long the restriction lasts, or `None` if it doesn't end.
+    pub fn duration(&self) -> Option<Duration> {
+        match self {
+            RestrictTime::For(duration) => Some(*duration),
+            RestrictTime::Permanent => None,
+        }
+    }
+
+    /// When a restriction applied at `now` ends, or `None` if it doesn't.
+    ///
+    /// The end is kept far enough ahead of `now` that the request still
+    /// arrives with more than 30 seconds left, since Telegram would make the
+    /// restriction permanent otherwise.
+    pub fn until(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let earliest = now + MIN_RESTRICTION + REQUEST_LEEWAY;
+        self.duration()
+            .map(|duration| (now + duration).max(earliest))
+    }
+}
+
+impl FromStr for RestrictTime {
+    type Err = String;
+
+    /// Parses `permanent`, `forever` or a duration such as `90s`, `2h` or
+    /// `1d12h`, made of one or more numbers each followed by a unit.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("permanent") || s.eq_ignore_ascii_case("forever") {
+            return Ok(RestrictTime::Permanent);
+        }
+        if s.is_empty() {
+            return Err("Give a duration such as 30m, 2h or 1d12h, or \"permanent\".".to_string());
+        }
+
+        let mut total = Duration::zero();
+        let mut rest = s;
+        while !rest.is_empty() {
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            if digits == 0 {
+                return Err(format!(
+                    "\"{}\" isn't a duration. Use a number followed by a unit, e.g. 30m, 2h or 1d12h.",
+                    s
+                ));
+            }
+            let (number, after) = rest.split_at(digits);
+            let letters = after
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(after.len());
+            if letters == 0 {
+                return Err(format!(
+                    "\"{}\" needs a unit, e.g. {}m or {}h. Allowed units: w, d, h, m, s",
+                    number, number, number
+                ));
+            }
+            let (unit, after) = after.split_at(letters);
+
+            let time = number
+                .parse::<u64>()
+                .map_err(|_| format!("{} is too large.", number))?;
+            total = total
+                .checked_add(&calc_restrict_time(time, unit.parse()?))
+                .unwrap_or(Duration::max_value());
+            rest = after;
+        }
+
+        if total < MIN_RESTRICTION {
+            return Err(
+                "A ban or mute must last at least 30 seconds. Use \"permanent\" for one that doesn't end."
+                    .to_string(),
+            );
+        }
+        if total > Duration::days(366) {
+            return Err(
+                "A ban or mute can last at most 366 days. Use \"permanent\" for one that doesn't end."
+                    .to_string(),
+            );
+        }
+        Ok(RestrictTime::For(total))
+    }
+}
+
+impl fmt::Display for RestrictTime {
+    /// Formats the restriction for confirmations, e.g. `for 1d 12h` or
+    /// `permanently`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestrictTime::For(duration) => write!(f, "for {}", utils::format_duration(*duration)),
+            RestrictTime::Permanent => f.write_str("permanently"),
+        }
+    }
+}
+
+/// Parses a command's optional argument, so that the bare command still
+/// parses and can be answered with its usage.
+fn parse_optional_arg(input: String) -> Result<(Option<String>,), ParseError> {
+    let arg = input.trim();
+    Ok(((!arg.is_empty()).then(|| arg.to_string()),))
+}
+
+/// Parses the arguments of `/kick`, `/unban` and `/unmute`: an optional
+/// target followed by an optional reason.
+fn parse_moderation_args(input: String) -> Result<(ModerationArgs,), ParseError> {
+    Ok((input.parse().unwrap_or_default(),))
+}
+
+/// Parses the arguments of `/ban` and `/mute`: a duration, then an optional
+/// target and reason, e.g. `1d12h @someone posting ads`.
+///
+/// The older form with the number and the unit apart, as in `2 h`, is still
+/// accepted.
+fn parse_timed_moderation_args(
+    input: String,
+) -> Result<(RestrictTime, ModerationArgs), ParseError> {
+    let mut parts = input.split_whitespace().peekable();
+    let Some(mut time) = parts.next().map(str::to_string) else {
+        return Err(ParseError::TooFewArguments {
+            expected: 1,
+            found: 0,
+            message: "Give a duration such as 30m, 2h or 1d12h, or \"permanent\".".to_string(),
+        });
+    };
+    if time.bytes().all(|b| b.is_ascii_digit()) {
+        if let Some(unit) = parts.next_if(|unit| unit.parse::<UnitOfTime>().is_ok()) {
+            time.push_str(unit);
+        }
+    }
+
+    let time = time
+        .parse::<RestrictTime>()
+        .map_err(|e| ParseError::IncorrectFormat(e.into()))?;
+    let args = parts
+        .collect::<Vec<_>>()
+        .join(" ")
+        .parse()
+        .unwrap_or_default();
+    Ok((time, args))
+}
+
+/// Calculates the restriction time based on the given time and unit.
+///
+/// This function takes a time value and a unit of time, and returns a Duration
+/// object representing the total restriction time.
+///
+/// # Arguments
+///
+/// * `time` - An unsigned 64-bit integer representing the amount of time.
+/// * `unit` - A UnitOfTime enum value specifying the unit of the given time (Weeks, Days, Hours,
+///   Minutes, or Seconds).
+///
+/// # Returns
+///
+/// Returns a Duration object representing the calculated restriction time.
+///
+/// # Note
+///
+/// Values too large for a Duration saturate at `Duration::max_value()` instead of overflowing, so
+/// that the bounds check in `RestrictTime::from_str` can reject them with a clear message.
+fn calc_restrict_time(time: u64, unit: UnitOfTime) -> Duration {
+    let seconds_per_unit: u64 = match unit {
+        UnitOfTime::Weeks => 604_800,
+        UnitOfTime::Days => 86_400,
+        UnitOfTime::Hours => 3_600,
+        UnitOfTime::Minutes => 60,
+        UnitOfTime::Seconds => 1,
+    };
+    time.checked_mul(seconds_per_unit)
+        .and_then(|seconds| i64::try_from(seconds).ok())
+        .and_then(Duration::try_seconds)
+        .unwrap_or(Duration::max_value())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::moderation;
+    use teloxide::types::UserId;
+
+    #[test]
+    fn calc_restrict_time_converts_every_unit() {
+        assert_eq!(
+            calc_restrict_time(45, UnitOfTime::Seconds),
+            Duration::seconds(45)
+        );
+        assert_eq!(
+            calc_restrict_time(30, UnitOfTime::Minutes),
+            Duration::minutes(30)
+        );
+        assert_eq!(calc_restrict_time(2, UnitOfTime::Hours), Duration::hours(2));
+        assert_eq!(calc_restrict_time(3, UnitOfTime::Days), Duration::days(3));
+        assert_eq!(calc_restrict_time(2, UnitOfTime::Weeks), Duration::weeks(2));
+        assert_eq!(calc_restrict_time(0, UnitOfTime::Weeks), Duration::zero());
+    }
+
+    #[test]
+    fn calc_restrict_time_saturates_instead_of_overflowing() {
+        assert_eq!(
+            calc_restrict_time(u64::MAX, UnitOfTime::Weeks),
+            Duration::max_value()
+        );
+        assert_eq!(
+            calc_restrict_time(u64::MAX / 2, UnitOfTime::Seconds),
+            Duration::max_value()
+        );
+    }
+
+    #[test]
+    fn restrict_time_parses_simple_and_compound_durations() {
+        let parse = |s: &str| s.parse::<RestrictTime>().unwrap();
+        assert_eq!(parse("90s"), RestrictTime::For(Duration::seconds(90)));
+        assert_eq!(parse("2h"), RestrictTime::For(Duration::hours(2)));
+        assert_eq!(parse("1w"), RestrictTime::For(Duration::weeks(1)));
+        assert_eq!(
+            parse("1d12h"),
+            RestrictTime::For(Duration::days(1) + Duration::hours(12))
+        );
+        assert_eq!(parse("1h30min"), RestrictTime::For(Duration::minutes(90)));
+        assert_eq!(parse("3Days"), RestrictTime::For(Duration::days(3)));
+        assert_eq!(parse("permanent"), RestrictTime::Permanent);
+        assert_eq!(parse("Forever"), RestrictTime::Permanent);
+    }
+
+    #[test]
+    fn restrict_time_enforces_telegram_bounds() {
+        assert!("29s"
+            .parse::<RestrictTime>()
+            .unwrap_err()
+            .contains("at least 30 seconds"));
+        assert!("0m".parse::<RestrictTime>().is_err());
+        assert_eq!(
+            "30s".parse::<RestrictTime>(),
+            Ok(RestrictTime::For(Duration::seconds(30)))
+        );
+        assert_eq!(
+            "366d".parse::<RestrictTime>(),
+            Ok(RestrictTime::For(Duration::days(366)))
+        );
+        assert!("366d1s"
+            .parse::<RestrictTime>()
+            .unwrap_err()
+            .contains("at most 366 days"));
+        assert!("99999999999999999999w"
+            .parse::<RestrictTime>()
+            .unwrap_err()
+            .contains("too large"));
+    }
+
+    #[test]
+    fn restrict_time_ends_safely_after_now() {
+        let now = Utc::now();
+        let short = RestrictTime::For(Duration::seconds(30));
+        assert!(short.until(now).unwrap() - now > Duration::seconds(30));
+        assert_eq!(
+            RestrictTime::For(Duration::hours(2)).until(now),
+            Some(now + Duration::hours(2))
+        );
+        assert_eq!(RestrictTime::Permanent.until(now), None);
+    }
+
+    #[test]
+    fn restrict_time_explains_malformed_durations() {
+        assert!("3x"
+            .parse::<RestrictTime>()
+            .unwrap_err()
+            .contains("Allowed units"));
+        assert!("30"
+            .parse::<RestrictTime>()
+            .unwrap_err()
+            .contains("needs a unit"));
+        assert!("h"
+            .parse::<RestrictTime>()
+            .unwrap_err()
+            .contains("isn't a duration"));
+        assert!("1h-2m"
+            .parse::<RestrictTime>()
+            .unwrap_err()
+            .contains("isn't a duration"));
+    }
+
+    #[test]
+    fn restrict_time_is_described_in_confirmations() {
+        let time = RestrictTime::For(Duration::days(1) + Duration::hours(12));
+        assert_eq!(time.to_string(), "for 1d 12h");
+        assert_eq!(RestrictTime::For(Duration::weeks(2)).to_string(), "for 2w");
+        assert_eq!(RestrictTime::Permanent.to_string(), "permanently");
+    }
+
+    #[test]
+    fn revoke_parses_with_or_without_a_token() {
+        assert!(matches!(
+            Command::parse("/revoke", "bot"),
+            Ok(Command::Revoke(None))
+        ));
+        assert!(matches!(
+            Command::parse("/revoke  all ", "bot"),
+            Ok(Command::Revoke(Some(token))) if token == "all"
+        ));
+    }
+
+    #[test]
+    fn ban_arguments_accept_both_duration_forms() {
+        let (time, args) =
+            parse_timed_moderation_args("2 h @someone posting ads".to_string()).unwrap();
+        assert_eq!(time, RestrictTime::For(Duration::hours(2)));
+        assert_eq!(
+            args.target,
+            Some(moderation::TargetSpec::Username("someone".to_string()))
+        );
+        assert_eq!(args.reason.as_deref(), Some("posting ads"));
+
+        let (time, args) = parse_timed_moderation_args("1d12h 123456".to_string()).unwrap();
+        assert_eq!(time, RestrictTime::For(Duration::hours(36)));
+        assert_eq!(
+            args.target,
+            Some(moderation::TargetSpec::Id(UserId(123456)))
+        );
+
+        // A number followed by a user ID isn't mistaken for the old form
+        assert!(parse_timed_moderation_args("30 123456".to_string()).is_err());
+        assert!(parse_timed_moderation_args(String::new()).is_err());
+    }
+}
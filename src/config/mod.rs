@@ -0,0 +1,395 @@
+use chrono::Duration;
+use chrono_tz::Tz;
+use envconfig::Envconfig;
+use sqlx::PgPool;
+use std::net::SocketAddr;
+use teloxide::{prelude::*, types::ThreadId};
+
+use crate::{
+    dialogues::StorageKind,
+    filters::{self, FilterError, FilterRules, Penalty},
+    jobs::JobContext,
+    links::TokenPolicy,
+    modlog::AuditLog,
+    queue::{MessageQueue, QueueSettings},
+    relay::MediaPolicy,
+    repositories::{ModLog, Repositories},
+    topics::{StaffTopic, StaffTopics},
+    warnings::WarnPolicy,
+    webhook::{WebhookError, WebhookSettings},
+};
+
+#[derive(Envconfig, Clone)]
+pub struct Config {
+    #[envconfig(from = "TELEGRAM_BOT_TOKEN")]
+    pub telegram_bot_token: String,
+
+    #[envconfig(from = "DATABASE_URL")]
+    pub database_url: String,
+
+    /// Chat of the pharmacy staff group, which receives alerts and reports
+    #[envconfig(from = "PHARMACY_GROUP_CHAT_ID")]
+    pub pharmacy_group_chat_id: i64,
+
+    /// Telegram user made admin when nobody has the admin role yet; 0 means none
+    #[envconfig(from = "ADMIN_USER_ID", default = "0")]
+    pub admin_user_id: u64,
+
+    /// Forum topic of the staff group for orders; 0 means the general thread
+    #[envconfig(from = "ORDERS_TOPIC_ID", default = "0")]
+    pub orders_topic_id: i32,
+
+    /// Forum topic of the staff group for expiry alerts
+    #[envconfig(from = "EXPIRY_TOPIC_ID", default = "0")]
+    pub expiry_topic_id: i32,
+
+    /// Forum topic of the staff group for low-stock alerts
+    #[envconfig(from = "INVENTORY_TOPIC_ID", default = "0")]
+    pub inventory_topic_id: i32,
+
+    /// Forum topic of the staff group for anonymous consultations
+    #[envconfig(from = "CONSULTATIONS_TOPIC_ID", default = "0")]
+    pub consultations_topic_id: i32,
+
+    /// Forum topic of the staff group for moderation logs
+    #[envconfig(from = "MODERATION_TOPIC_ID", default = "0")]
+    pub moderation_topic_id: i32,
+
+    /// Chat or channel moderation actions are logged to; 0 means the staff group
+    #[envconfig(from = "MODERATION_LOG_CHAT_ID", default = "0")]
+    pub moderation_log_chat_id: i64,
+
+    /// Stock level below which the low-stock job reports a medicine
+    #[envconfig(from = "LOW_STOCK_THRESHOLD", default = "50")]
+    pub low_stock_threshold: i32,
+
+    /// IANA time zone of the pharmacy, used for job schedules, the rota and dates
+    /// shown to users; an unknown name stops the bot at startup
+    #[envconfig(from = "PHARMACY_TIMEZONE", default = "Africa/Cairo")]
+    pub pharmacy_timezone: Tz,
+
+    /// Optional `<job>=<cron>` overrides separated by semicolons
+    #[envconfig(from = "JOB_SCHEDULES", default = "")]
+    pub job_schedules: String,
+
+    /// Minimum delay in milliseconds between any two outbound messages
+    #[envconfig(from = "QUEUE_GLOBAL_INTERVAL_MS", default = "35")]
+    pub queue_global_interval_ms: u64,
+
+    /// Minimum delay in milliseconds between two messages to the same private chat
+    #[envconfig(from = "QUEUE_PRIVATE_CHAT_INTERVAL_MS", default = "1000")]
+    pub queue_private_chat_interval_ms: u64,
+
+    /// Minimum delay in milliseconds between two messages to the same group
+    #[envconfig(from = "QUEUE_GROUP_CHAT_INTERVAL_MS", default = "3000")]
+    pub queue_group_chat_interval_ms: u64,
+
+    /// Number of failed attempts after which an outbound message is dropped
+    #[envconfig(from = "QUEUE_MAX_ATTEMPTS", default = "5")]
+    pub queue_max_attempts: i32,
+
+    /// Seconds to keep sending queued messages after shutdown is requested
+    #[envconfig(from = "QUEUE_DRAIN_TIMEOUT_SECS", default = "10")]
+    pub queue_drain_timeout_secs: u64,
+
+    /// Hours an anonymous message link stays valid; 0 means it never expires
+    #[envconfig(from = "DEEP_LINK_TTL_HOURS", default = "720")]
+    pub deep_link_ttl_hours: i64,
+
+    /// Number of conversations a single link can start; 0 means unlimited
+    #[envconfig(from = "DEEP_LINK_MAX_USES", default = "0")]
+    pub deep_link_max_uses: i32,
+
+    /// Largest file, in megabytes, that can be sent through an anonymous conversation
+    #[envconfig(from = "RELAY_MAX_FILE_SIZE_MB", default = "10")]
+    pub relay_max_file_size_mb: u32,
+
+    /// Comma separated MIME types of documents that can be sent anonymously
+    #[envconfig(
+        from = "RELAY_ALLOWED_DOCUMENT_TYPES",
+        default = "application/pdf,image/jpeg,image/png"
+    )]
+    pub relay_allowed_document_types: String,
+
+    /// Longest voice message, in seconds, that can be sent anonymously
+    #[envconfig(from = "RELAY_MAX_VOICE_SECONDS", default = "300")]
+    pub relay_max_voice_seconds: u32,
+
+    /// Minutes a patient may wait for a pharmacist's answer before staff are alerted
+    #[envconfig(from = "TICKET_SLA_MINUTES", default = "60")]
+    pub ticket_sla_minutes: i64,
+
+    /// Warnings after which each further warning mutes the user; 0 never mutes
+    #[envconfig(from = "WARN_MUTE_THRESHOLD", default = "3")]
+    pub warn_mute_threshold: i64,
+
+    /// Hours a user is muted for when warnings reach the mute threshold
+    #[envconfig(from = "WARN_MUTE_HOURS", default = "24")]
+    pub warn_mute_hours: i64,
+
+    /// Warnings after which the user is banned; 0 never bans
+    #[envconfig(from = "WARN_BAN_THRESHOLD", default = "5")]
+    pub warn_ban_threshold: i64,
+
+    /// Comma separated domains whose links are removed from groups
+    #[envconfig(from = "FILTER_BLOCKED_DOMAINS", default = "")]
+    pub filter_blocked_domains: String,
+
+    /// Comma separated words and phrases whose messages are removed from groups
+    #[envconfig(from = "FILTER_KEYWORDS", default = "")]
+    pub filter_keywords: String,
+
+    /// Regular expressions separated by semicolons whose matches are removed from groups
+    #[envconfig(from = "FILTER_PATTERNS", default = "")]
+    pub filter_patterns: String,
+
+    /// What happens to members whose messages break a filter rule: delete, mute or ban
+    #[envconfig(from = "FILTER_ACTION", default = "mute")]
+    pub filter_action: String,
+
+    /// Hours a member is muted for by the filter when FILTER_ACTION is mute
+    #[envconfig(from = "FILTER_MUTE_HOURS", default = "24")]
+    pub filter_mute_hours: i64,
+
+    /// Messages a member may send within FLOOD_WINDOW_SECS; 0 turns flood detection off
+    #[envconfig(from = "FLOOD_MAX_MESSAGES", default = "5")]
+    pub flood_max_messages: usize,
+
+    /// Seconds over which messages are counted for flood detection
+    #[envconfig(from = "FLOOD_WINDOW_SECS", default = "10")]
+    pub flood_window_secs: i64,
+
+    /// Hours after joining during which members can't post links, media or forwards; 0 means none
+    #[envconfig(from = "NEW_MEMBER_RESTRICT_HOURS", default = "24")]
+    pub new_member_restrict_hours: i64,
+
+    /// Seconds new group members have to answer the join challenge; 0 turns it off
+    #[envconfig(from = "CAPTCHA_TIMEOUT_SECS", default = "300")]
+    pub captcha_timeout_secs: i64,
+
+    /// Where conversation states are kept: "postgres" to survive restarts, or "memory"
+    #[envconfig(from = "DIALOGUE_STORAGE", default = "postgres")]
+    pub dialogue_storage: String,
+
+    /// Hours after which an idle conversation is forgotten; 0 means never
+    #[envconfig(from = "DIALOGUE_TTL_HOURS", default = "24")]
+    pub dialogue_ttl_hours: i64,
+
+    /// Public HTTPS URL Telegram posts updates to; empty uses long polling instead
+    #[envconfig(from = "WEBHOOK_URL", default = "")]
+    pub webhook_url: String,
+
+    /// Local address the webhook server listens on, e.g. behind a reverse proxy
+    #[envconfig(from = "WEBHOOK_LISTEN_ADDR", default = "0.0.0.0:8080")]
+    pub webhook_listen_addr: String,
+
+    /// Secret Telegram sends with every webhook request; empty generates one on start
+    #[envconfig(from = "WEBHOOK_SECRET_TOKEN", default = "")]
+    pub webhook_secret_token: String,
+
+    /// Whether to register the webhook with Telegram on start; off to post updates yourself
+    #[envconfig(from = "WEBHOOK_REGISTER", default = "true")]
+    pub webhook_register: bool,
+
+    /// Address serving /healthz, /readyz and /metrics; empty turns the endpoints off
+    #[envconfig(from = "HEALTH_LISTEN_ADDR", default = "0.0.0.0:9090")]
+    pub health_listen_addr: String,
+}
+
+impl Config {
+    /// Builds the outbound queue settings from the configured limits.
+    pub fn queue_settings(&self) -> QueueSettings {
+        QueueSettings {
+            global_interval: std::time::Duration::from_millis(self.queue_global_interval_ms),
+            private_chat_interval: std::time::Duration::from_millis(
+                self.queue_private_chat_interval_ms,
+            ),
+            group_chat_interval: std::time::Duration::from_millis(
+                self.queue_group_chat_interval_ms,
+            ),
+            max_attempts: self.queue_max_attempts,
+            drain_timeout: std::time::Duration::from_secs(self.queue_drain_timeout_secs),
+        }
+    }
+
+    /// Builds the limits on media sent through anonymous conversations.
+    pub fn media_policy(&self) -> MediaPolicy {
+        MediaPolicy {
+            max_file_size: self.relay_max_file_size_mb.saturating_mul(1024 * 1024),
+            allowed_document_types: self
+                .relay_allowed_document_types
+                .split(',')
+                .map(|mime| mime.trim().to_string())
+                .filter(|mime| !mime.is_empty())
+                .collect(),
+            max_voice_duration: self.relay_max_voice_seconds,
+        }
+    }
+
+    /// Builds the forum topics of the staff group from the configured IDs.
+    pub fn staff_topics(&self) -> StaffTopics {
+        StaffTopics::from_ids(
+            self.orders_topic_id,
+            self.expiry_topic_id,
+            self.inventory_topic_id,
+            self.consultations_topic_id,
+            self.moderation_topic_id,
+        )
+    }
+
+    /// Returns the forum topic to post `topic` messages to in `chat_id`.
+    ///
+    /// Only the staff group has topics; any other chat gets `None`.
+    pub fn staff_thread(&self, chat_id: ChatId, topic: StaffTopic) -> Option<ThreadId> {
+        if chat_id == ChatId(self.pharmacy_group_chat_id) {
+            self.staff_topics().thread(topic)
+        } else {
+            None
+        }
+    }
+
+    /// Builds the audit log of moderation actions.
+    ///
+    /// Actions are posted to the configured log chat, or to the moderation
+    /// topic of the staff group when there is none.
+    pub fn audit_log(&self, modlog: ModLog, queue: MessageQueue) -> AuditLog {
+        if self.moderation_log_chat_id != 0 {
+            AuditLog::new(modlog, queue, ChatId(self.moderation_log_chat_id), None)
+        } else {
+            let staff_chat = ChatId(self.pharmacy_group_chat_id);
+            let thread = self.staff_thread(staff_chat, StaffTopic::Moderation);
+            AuditLog::new(modlog, queue, staff_chat, thread)
+        }
+    }
+
+    /// Builds the context handed to scheduled jobs, which report to the
+    /// staff group.
+    pub fn job_context(
+        &self,
+        pool: PgPool,
+        repositories: Repositories,
+        queue: MessageQueue,
+    ) -> JobContext {
+        JobContext {
+            pool,
+            repositories,
+            queue,
+            staff_chat_id: ChatId(self.pharmacy_group_chat_id),
+            topics: self.staff_topics(),
+            low_stock_threshold: self.low_stock_threshold,
+            ticket_sla: Duration::minutes(self.ticket_sla_minutes),
+            dialogue_ttl: self.dialogue_ttl(),
+        }
+    }
+
+    /// The pharmacy's time zone.
+    ///
+    /// There is one for the whole bot; pharmacies in different zones need a
+    /// bot each.
+    pub fn timezone(&self) -> Tz {
+        self.pharmacy_timezone
+    }
+
+    /// Builds the thresholds at which warnings turn into a mute or a ban.
+    ///
+    /// The mute is kept within the 30 seconds to 366 days Telegram honours.
+    pub fn warn_policy(&self) -> WarnPolicy {
+        WarnPolicy {
+            mute_after: (self.warn_mute_threshold > 0).then_some(self.warn_mute_threshold),
+            mute_for: Duration::hours(self.warn_mute_hours.clamp(1, 366 * 24)),
+            ban_after: (self.warn_ban_threshold > 0).then_some(self.warn_ban_threshold),
+        }
+    }
+
+    /// Builds the spam filter rules, validating the patterns and the action.
+    ///
+    /// As with warnings, the mute is kept within the range Telegram honours.
+    pub fn filter_rules(&self) -> Result<FilterRules, FilterError> {
+        Ok(FilterRules {
+            blocked_domains: filters::parse_list(&self.filter_blocked_domains),
+            keywords: filters::parse_list(&self.filter_keywords),
+            patterns: filters::parse_patterns(&self.filter_patterns)?,
+            flood_max_messages: (self.flood_max_messages > 0).then_some(self.flood_max_messages),
+            flood_window: Duration::seconds(self.flood_window_secs.max(1)),
+            new_member_period: (self.new_member_restrict_hours > 0)
+                .then(|| Duration::hours(self.new_member_restrict_hours)),
+            penalty: Penalty::from_action(
+                &self.filter_action,
+                Duration::hours(self.filter_mute_hours.clamp(1, 366 * 24)),
+            )?,
+        })
+    }
+
+    /// How long new group members have to answer the join challenge, or
+    /// `None` if they aren't challenged.
+    pub fn captcha_timeout(&self) -> Option<Duration> {
+        (self.captcha_timeout_secs > 0).then(|| Duration::seconds(self.captcha_timeout_secs))
+    }
+
+    /// Where conversation states are kept.
+    pub fn dialogue_storage(&self) -> Result<StorageKind, String> {
+        self.dialogue_storage.parse()
+    }
+
+    /// How long an idle conversation is kept, or `None` if it is kept until
+    /// it ends.
+    pub fn dialogue_ttl(&self) -> Option<Duration> {
+        (self.dialogue_ttl_hours > 0).then(|| Duration::hours(self.dialogue_ttl_hours))
+    }
+
+    /// Builds the webhook settings, or `None` to receive updates by long polling.
+    pub fn webhook(&self) -> Result<Option<WebhookSettings>, WebhookError> {
+        WebhookSettings::from_config(
+            &self.webhook_url,
+            &self.webhook_listen_addr,
+            &self.webhook_secret_token,
+            self.webhook_register,
+        )
+    }
+
+    /// The address of the health and metrics endpoints, or `None` if they are off.
+    pub fn health_address(&self) -> Result<Option<SocketAddr>, String> {
+        let address = self.health_listen_addr.trim();
+        if address.is_empty() {
+            return Ok(None);
+        }
+        address.parse().map(Some).map_err(|_| {
+            format!(
+                "invalid health listen address {:?}; expected e.g. 0.0.0.0:9090",
+                address
+            )
+        })
+    }
+
+    /// Builds the limits applied to new anonymous message links.
+    pub fn token_policy(&self) -> TokenPolicy {
+        TokenPolicy {
+            ttl: (self.deep_link_ttl_hours > 0).then(|| Duration::hours(self.deep_link_ttl_hours)),
+            max_uses: (self.deep_link_max_uses > 0).then_some(self.deep_link_max_uses),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config(timezone: &str) -> Result<Config, envconfig::Error> {
+        Config::init_from_hashmap(&HashMap::from([
+            ("TELEGRAM_BOT_TOKEN".to_string(), "1234:TEST".to_string()),
+            (
+                "DATABASE_URL".to_string(),
+                "postgres://localhost/test".to_string(),
+            ),
+            ("PHARMACY_GROUP_CHAT_ID".to_string(), "-100".to_string()),
+            ("PHARMACY_TIMEZONE".to_string(), timezone.to_string()),
+        ]))
+    }
+
+    #[test]
+    fn unknown_time_zones_are_rejected() {
+        assert_eq!(config("Asia/Dubai").unwrap().timezone(), Tz::Asia__Dubai);
+        assert!(config("Mars/Olympus").is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use chrono::{DateTime, Duration, Utc};
+use futures::future::BoxFuture;
+use serde::{de::DeserializeOwned, Serialize};
+use sqlx::PgPool;
+use std::{fmt, marker::PhantomData, str::FromStr, sync::Arc};
+use teloxide::{
+    dispatching::dialogue::{Dialogue, ErasedStorage, InMemStorage, Storage},
+    types::ChatId,
+};
+use thiserror::Error;
+
+use crate::metrics;
+
+#[derive(Clone, PartialEq, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub enum State {
+    #[default]
+    Start,
+    WriteToPharmacist {
+        id: ChatId,
+    },
+}
+
+pub type MyDialogue = Dialogue<State, ErasedStorage<State>>;
+
+/// Where dialogue states are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    /// In memory; every conversation is forgotten when the bot restarts.
+    Memory,
+    /// In the `dialogues` table, so conversations survive restarts.
+    Postgres,
+}
+
+impl fmt::Display for StorageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StorageKind::Memory => "memory",
+            StorageKind::Postgres => "postgres",
+        })
+    }
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "memory" => Ok(StorageKind::Memory),
+            "postgres" => Ok(StorageKind::Postgres),
+            _ => Err(format!(
+                "unknown dialogue storage {:?}; expected memory or postgres",
+                s
+            )),
+        }
+    }
+}
+
+/// Creates the dialogue storage of the given kind.
+///
+/// # Arguments
+///
+/// * `kind` - Where dialogue states are kept.
+/// * `pool` - The database connection pool, used by the Postgres storage.
+/// * `ttl` - How long an untouched dialogue is kept by the Postgres storage;
+///   `None` keeps dialogues until they end.
+pub fn storage<D>(kind: StorageKind, pool: PgPool, ttl: Option<Duration>) -> Arc<ErasedStorage<D>>
+where
+    D: Serialize + DeserializeOwned + Clone + Send + Sync + 'static,
+{
+    match kind {
+        StorageKind::Memory => InMemStorage::<D>::new().erase(),
+        StorageKind::Postgres => Arc::new(PgStorage::new(pool, ttl)).erase(),
+    }
+}
+
+/// An error of the Postgres dialogue storage.
+#[derive(Debug, Error)]
+pub enum PgStorageError {
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("malformed dialogue state: {0}")]
+    Serde(#[from] serde_json::Error),
+    /// Returned by `remove_dialogue` when there is nothing to remove, as
+    /// `InMemStorage` does.
+    #[error("no dialogue for chat {0}")]
+    DialogueNotFound(ChatId),
+}
+
+/// A dialogue storage backed by the `dialogues` table.
+///
+/// States are stored as JSON text. A dialogue that hasn't changed for longer
+/// than the TTL is treated as gone, and removed for good by the
+/// `dialogue_cleanup` job.
+pub struct PgStorage<D> {
+    pool: PgPool,
+    ttl: Option<Duration>,
+    _state: PhantomData<fn() -> D>,
+}
+
+impl<D> PgStorage<D> {
+    pub fn new(pool: PgPool, ttl: Option<Duration>) -> Self {
+        Self {
+            pool,
+            ttl,
+            _state: PhantomData,
+        }
+    }
+
+    /// The oldest update time of a dialogue that is still current.
+    fn cutoff(&self) -> Option<DateTime<Utc>> {
+        self.ttl.map(|ttl| Utc::now() - ttl)
+    }
+}
+
+impl<D> Storage<D> for PgStorage<D>
+where
+    D: Serialize + DeserializeOwned + Send + 'static,
+{
+    type Error = PgStorageError;
+
+    fn remove_dialogue(
+        self: Arc<Self>,
+        chat_id: ChatId,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        Box::pin(async move {
+            let delete = sqlx::query("DELETE FROM dialogues WHERE chat_id = $1")
+                .bind(chat_id.0)
+                .execute(&self.pool);
+            let result = metrics::global()
+                .time_query("remove_dialogue", delete)
+                .await?;
+            if result.rows_affected() == 0 {
+                return Err(PgStorageError::DialogueNotFound(chat_id));
+            }
+            Ok(())
+        })
+    }
+
+    fn update_dialogue(
+        self: Arc<Self>,
+        chat_id: ChatId,
+        dialogue: D,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        Box::pin(async move {
+            let state = serde_json::to_string(&dialogue)?;
+            let upsert = sqlx::query(
+                "INSERT INTO dialogues (chat_id, state) VALUES ($1, $2)
+                 ON CONFLICT (chat_id) DO UPDATE SET state = EXCLUDED.state, updated_at = NOW()",
+            )
+            .bind(chat_id.0)
+            .bind(state)
+            .execute(&self.pool);
+            metrics::global()
+                .time_query("update_dialogue", upsert)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn get_dialogue(
+        self: Arc<Self>,
+        chat_id: ChatId,
+    ) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
+        Box::pin(async move {
+            let select = sqlx::query_as(
+                "SELECT state FROM dialogues
+                 WHERE chat_id = $1 AND ($2::TIMESTAMPTZ IS NULL OR updated_at > $2)",
+            )
+            .bind(chat_id.0)
+            .bind(self.cutoff())
+            .fetch_optional(&self.pool);
+            let state: Option<(String,)> =
+                metrics::global().time_query("get_dialogue", select).await?;
+            Ok(match state {
+                Some((state,)) => Some(serde_json::from_str(&state)?),
+                None => None,
+            })
+        })
+    }
+}
+
+/// Deletes dialogues that haven't changed for longer than `ttl`.
+///
+/// # Returns
+///
+/// Returns the number of dialogues deleted.
+pub async fn remove_stale(pool: &PgPool, ttl: Duration) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM dialogues WHERE updated_at <= $1")
+        .bind(Utc::now() - ttl)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
@@ -0,0 +1,269 @@
+use dptree::{
+    di::{DependencyMap, DependencySupplier},
+    HandlerDescription,
+};
+use futures::future::BoxFuture;
+use std::{error::Error as StdError, ops::ControlFlow, sync::Arc};
+use teloxide::{
+    dispatching::{DpHandlerDescription, UpdateHandler},
+    error_handlers::ErrorHandler,
+    prelude::*,
+    types::{UpdateId, User},
+    RequestError,
+};
+use thiserror::Error;
+
+use crate::{metrics, Error};
+
+/// Something a user asked for that doesn't exist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entity {
+    Medicine,
+    Ticket,
+    Shift,
+}
+
+/// Why a request from a user failed.
+///
+/// Each error maps to a reply in the user's language with
+/// [`AppError::user_message`]; internal details such as database errors are
+/// only logged.
+#[derive(Debug, Error)]
+pub enum AppError {
+    #[error("{0:?} not found")]
+    NotFound(Entity),
+    #[error("insufficient stock of {medicine}: {available} left, {requested} requested")]
+    InsufficientStock {
+        medicine: String,
+        available: i32,
+        requested: i32,
+    },
+    /// The reason is only logged; the user is told they aren't allowed.
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+    #[error("database error")]
+    Db(#[from] sqlx::Error),
+    #[error("Telegram error")]
+    Telegram(#[from] RequestError),
+    /// The message is already phrased for the user and shown as is.
+    #[error("invalid request: {0}")]
+    Validation(String),
+}
+
+/// The languages replies are available in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Lang {
+    #[default]
+    English,
+    Arabic,
+}
+
+impl Lang {
+    /// Picks the language of a user from their Telegram settings, falling
+    /// back to English.
+    pub fn of(user: Option<&User>) -> Self {
+        match user.and_then(|user| user.language_code.as_deref()) {
+            Some(code) if code.starts_with("ar") => Lang::Arabic,
+            _ => Lang::English,
+        }
+    }
+}
+
+impl AppError {
+    /// Whether the error is the bot's fault rather than the user's.
+    pub fn is_internal(&self) -> bool {
+        matches!(self, AppError::Db(_) | AppError::Telegram(_))
+    }
+
+    /// The reply telling the user what went wrong, in their language.
+    pub fn user_message(&self, lang: Lang) -> String {
+        match (self, lang) {
+            (AppError::NotFound(entity), Lang::English) => match entity {
+                Entity::Medicine => "Medicine not found.".to_string(),
+                Entity::Ticket => "No ticket with that number.".to_string(),
+                Entity::Shift => "No shift with that number.".to_string(),
+            },
+            (AppError::NotFound(entity), Lang::Arabic) => match entity {
+                Entity::Medicine => "الدواء غير موجود.".to_string(),
+                Entity::Ticket => "لا توجد تذكرة بهذا الرقم.".to_string(),
+                Entity::Shift => "لا توجد وردية بهذا الرقم.".to_string(),
+            },
+            (
+                AppError::InsufficientStock {
+                    medicine,
+                    available,
+                    ..
+                },
+                Lang::English,
+            ) => format!(
+                "Insufficient stock: only {} left of {}.",
+                available, medicine
+            ),
+            (
+                AppError::InsufficientStock {
+                    medicine,
+                    available,
+                    ..
+                },
+                Lang::Arabic,
+            ) => format!(
+                "الكمية غير كافية: المتبقي من {} هو {} فقط.",
+                medicine, available
+            ),
+            (AppError::Forbidden(_), Lang::English) => {
+                "You don't have permission to do that.".to_string()
+            }
+            (AppError::Forbidden(_), Lang::Arabic) => "ليس لديك صلاحية للقيام بذلك.".to_string(),
+            (AppError::Db(_) | AppError::Telegram(_), Lang::English) => {
+                "Something went wrong on our side. Please try again later.".to_string()
+            }
+            (AppError::Db(_) | AppError::Telegram(_), Lang::Arabic) => {
+                "حدث خطأ من جانبنا. يرجى المحاولة مرة أخرى لاحقًا.".to_string()
+            }
+            (AppError::Validation(message), _) => message.clone(),
+        }
+    }
+}
+
+/// Tells the sender of `msg` why their request failed, if it did.
+///
+/// Errors that are the bot's fault are logged with the chat, user and cause;
+/// the others are the expected outcome of a bad request and only noted.
+///
+/// # Returns
+///
+/// Returns an error only if the reply itself couldn't be sent.
+pub async fn reply_on_error(
+    bot: &Bot,
+    msg: &Message,
+    result: Result<(), AppError>,
+) -> Result<(), RequestError> {
+    let Err(error) = result else {
+        return Ok(());
+    };
+
+    let user = msg.from.as_ref().map(|user| user.id.to_string());
+    if error.is_internal() {
+        log::error!(
+            "Failed to handle message {} in chat {} from user {}: {}",
+            msg.id,
+            msg.chat.id,
+            user.as_deref().unwrap_or("unknown"),
+            describe(&error)
+        );
+        if let AppError::Telegram(e) = &error {
+            metrics::global().api_error(e);
+        }
+    } else {
+        log::info!(
+            "Refused message {} in chat {} from user {}: {}",
+            msg.id,
+            msg.chat.id,
+            user.as_deref().unwrap_or("unknown"),
+            error
+        );
+    }
+
+    let lang = Lang::of(msg.from.as_ref());
+    bot.send_message(msg.chat.id, error.user_message(lang))
+        .await?;
+    Ok(())
+}
+
+/// Formats an error followed by the chain of errors that caused it.
+pub fn describe(error: &(dyn StdError + 'static)) -> String {
+    let mut description = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        description.push_str(": caused by: ");
+        description.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    description
+}
+
+/// An error returned by an update handler, along with the update it was
+/// handling.
+///
+/// [`with_update_context`] wraps handler errors in one, so [`ErrorLogger`] can
+/// tell which update, chat and user the error concerns.
+#[derive(Debug, Error)]
+#[error("failed to handle update {}", update_id.0)]
+pub struct UpdateError {
+    pub update_id: UpdateId,
+    pub chat: Option<ChatId>,
+    pub user: Option<UserId>,
+    #[source]
+    pub source: Error,
+}
+
+/// Wraps errors returned further down the handler tree in an [`UpdateError`]
+/// describing the update that was being handled.
+///
+/// It goes first in the tree, before any filter, and doesn't change which
+/// updates are received.
+pub fn with_update_context() -> UpdateHandler<Error> {
+    dptree::from_fn_with_description(
+        DpHandlerDescription::entry(),
+        |deps: DependencyMap, cont| async move {
+            let update: Arc<Update> = deps.get();
+            match cont(deps).await {
+                ControlFlow::Break(Err(source)) => ControlFlow::Break(Err(Box::new(UpdateError {
+                    update_id: update.id,
+                    chat: update.chat().map(|chat| chat.id),
+                    user: update.from().map(|user| user.id),
+                    source,
+                })
+                    as Error)),
+                flow => flow,
+            }
+        },
+    )
+}
+
+/// The dispatcher's handler of errors returned by update handlers.
+///
+/// Handlers reply to users themselves, so this only logs the error with its
+/// causes and the update it came from, and counts failed Bot API requests;
+/// nothing is allowed to take the dispatcher down.
+pub struct ErrorLogger;
+
+impl ErrorHandler<Error> for ErrorLogger {
+    fn handle_error(self: Arc<Self>, error: Error) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            let (context, error) = match error.downcast::<UpdateError>() {
+                Ok(failed) => {
+                    let context = format!(
+                        " on update {} in chat {} from user {}",
+                        failed.update_id.0,
+                        failed
+                            .chat
+                            .map_or_else(|| "unknown".to_string(), |chat| chat.to_string()),
+                        failed
+                            .user
+                            .map_or_else(|| "unknown".to_string(), |user| user.to_string()),
+                    );
+                    (context, failed.source)
+                }
+                Err(error) => (String::new(), error),
+            };
+
+            metrics::global().handler_error(&error);
+            let kind = if error.is::<RequestError>() {
+                "Telegram"
+            } else if error.is::<sqlx::Error>() {
+                "database"
+            } else if error.is::<AppError>() {
+                "application"
+            } else {
+                "unexpected"
+            };
+            log::error!(
+                "An update handler failed{} with a {} error: {}",
+                context,
+                kind,
+                describe(error.as_ref())
+            );
+        })
+    }
+}
@@ -0,0 +1,533 @@
+use chrono::{DateTime, Duration, Utc};
+use regex::{Regex, RegexBuilder};
+use sqlx::PgPool;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::Arc,
+};
+use teloxide::types::{ChatId, Message, MessageEntityKind, UserId};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use url::Url;
+
+use crate::utils::format_duration;
+
+/// Users whose recent messages are tracked before old entries are swept.
+const MAX_TRACKED_USERS: usize = 10_000;
+
+/// An invalid filter configuration, reported at startup.
+#[derive(Debug, Error)]
+pub enum FilterError {
+    #[error("invalid filter pattern {pattern:?}: {reason}")]
+    InvalidPattern { pattern: String, reason: String },
+    #[error("unknown filter action {0:?}; expected delete, mute or ban")]
+    UnknownAction(String),
+}
+
+/// What happens to a user whose message breaks a rule, on top of the
+/// message being deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Penalty {
+    /// Only the message is deleted.
+    None,
+    /// The user is muted for a while.
+    Mute(Duration),
+    /// The user is banned for good.
+    Ban,
+}
+
+/// A filter rule a message broke.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// A link to a blocked domain.
+    BlockedLink(String),
+    /// A blocked word or phrase.
+    Keyword(String),
+    /// Text matching a blocked regular expression.
+    Pattern(String),
+    /// Too many messages in a short time.
+    Flood { messages: usize, window: Duration },
+    /// A link, media or a forward from someone who joined recently.
+    NewMember,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::BlockedLink(domain) => write!(f, "link to {}", domain),
+            Violation::Keyword(keyword) => write!(f, "blocked phrase \"{}\"", keyword),
+            Violation::Pattern(_) => f.write_str("blocked content"),
+            Violation::Flood { messages, window } => write!(
+                f,
+                "flooding ({} messages within {})",
+                messages,
+                format_duration(*window)
+            ),
+            Violation::NewMember => f.write_str("links, media or forwards from a new member"),
+        }
+    }
+}
+
+/// The rules messages in groups are checked against.
+#[derive(Debug, Clone)]
+pub struct FilterRules {
+    /// Domains whose links are removed, subdomains included.
+    pub blocked_domains: Vec<String>,
+    /// Lowercase words and phrases whose messages are removed.
+    pub keywords: Vec<String>,
+    /// Case-insensitive patterns whose matches are removed.
+    pub patterns: Vec<Regex>,
+    /// Messages a user may send within `flood_window`; `None` allows any number.
+    pub flood_max_messages: Option<usize>,
+    /// The period over which messages are counted for flood detection.
+    pub flood_window: Duration,
+    /// How long after joining a member may not post links, media or
+    /// forwards; `None` lets them right away.
+    pub new_member_period: Option<Duration>,
+    /// What happens to users who break a rule.
+    pub penalty: Penalty,
+}
+
+/// Parses a comma separated setting into lowercase items, skipping empty ones.
+pub fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim().trim_start_matches('.').to_lowercase())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Parses regular expressions separated by semicolons, matched ignoring case.
+pub fn parse_patterns(patterns: &str) -> Result<Vec<Regex>, FilterError> {
+    patterns
+        .split(';')
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .map(|pattern| {
+            RegexBuilder::new(pattern)
+                .case_insensitive(true)
+                .build()
+                .map_err(|e| FilterError::InvalidPattern {
+                    pattern: pattern.to_string(),
+                    reason: e.to_string(),
+                })
+        })
+        .collect()
+}
+
+impl Penalty {
+    /// Parses the configured action: `delete`, `mute` or `ban`.
+    ///
+    /// # Arguments
+    ///
+    /// * `action` - The configured action, ignoring case.
+    /// * `mute_for` - How long the `mute` action lasts.
+    pub fn from_action(action: &str, mute_for: Duration) -> Result<Self, FilterError> {
+        match action.trim().to_lowercase().as_str() {
+            "delete" => Ok(Penalty::None),
+            "mute" => Ok(Penalty::Mute(mute_for)),
+            "ban" => Ok(Penalty::Ban),
+            _ => Err(FilterError::UnknownAction(action.to_string())),
+        }
+    }
+}
+
+impl FilterRules {
+    /// Checks the text and links of a message against the content rules.
+    fn check_content(&self, msg: &Message) -> Option<Violation> {
+        for host in link_hosts(msg) {
+            let blocked = self
+                .blocked_domains
+                .iter()
+                .find(|domain| host == **domain || host.ends_with(&format!(".{}", domain)));
+            if let Some(domain) = blocked {
+                return Some(Violation::BlockedLink(domain.clone()));
+            }
+        }
+
+        let text = msg.text().or(msg.caption())?;
+        let lowercase = text.to_lowercase();
+        if let Some(keyword) = self
+            .keywords
+            .iter()
+            .find(|k| lowercase.contains(k.as_str()))
+        {
+            return Some(Violation::Keyword(keyword.clone()));
+        }
+        self.patterns
+            .iter()
+            .find(|pattern| pattern.is_match(text))
+            .map(|pattern| Violation::Pattern(pattern.to_string()))
+    }
+
+    /// Whether a message sent at `sent` is too old to be filtered at `now`,
+    /// being from before the current flood window.
+    fn is_stale(&self, sent: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        now - sent > self.flood_window
+    }
+}
+
+/// Returns the lowercase host of every link in a message or its caption.
+fn link_hosts(msg: &Message) -> Vec<String> {
+    let entities = msg
+        .parse_entities()
+        .or_else(|| msg.parse_caption_entities())
+        .unwrap_or_default();
+
+    entities
+        .iter()
+        .filter_map(|entity| match entity.kind() {
+            MessageEntityKind::Url => {
+                let text = entity.text();
+                // Telegram also marks links written without a scheme
+                Url::parse(text)
+                    .or_else(|_| Url::parse(&format!("http://{}", text)))
+                    .ok()
+            }
+            MessageEntityKind::TextLink { url } => Some(url.clone()),
+            _ => None,
+        })
+        .filter_map(|url| url.host_str().map(str::to_lowercase))
+        .collect()
+}
+
+/// Whether a message carries a link, media or a forward, which new members
+/// aren't allowed to post.
+fn has_links_or_media(msg: &Message) -> bool {
+    !link_hosts(msg).is_empty()
+        || msg.forward_origin().is_some()
+        || msg.photo().is_some()
+        || msg.video().is_some()
+        || msg.document().is_some()
+        || msg.animation().is_some()
+        || msg.audio().is_some()
+        || msg.voice().is_some()
+        || msg.sticker().is_some()
+}
+
+/// The times of each user's recent messages, per chat.
+type RecentMessages = HashMap<(ChatId, UserId), VecDeque<DateTime<Utc>>>;
+
+/// Checks group messages against the filter rules.
+///
+/// Content rules are checked in memory. Flood detection keeps the times of
+/// each user's recent messages in memory, so it starts afresh after a
+/// restart; join times of new members are kept in the database.
+#[derive(Clone)]
+pub struct SpamFilter {
+    rules: Arc<FilterRules>,
+    recent: Arc<Mutex<RecentMessages>>,
+}
+
+impl SpamFilter {
+    pub fn new(rules: FilterRules) -> Self {
+        Self {
+            rules: Arc::new(rules),
+            recent: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// What happens to the sender of a message that broke a rule.
+    ///
+    /// Messages older than the flood window, such as the backlog Telegram
+    /// delivers after the bot was down, are only deleted, so their senders
+    /// aren't muted or banned long after the fact.
+    pub fn penalty_for(&self, msg: &Message) -> Penalty {
+        if self.rules.is_stale(msg.date, Utc::now()) {
+            Penalty::None
+        } else {
+            self.rules.penalty
+        }
+    }
+
+    /// Checks a group message from `user` against every rule.
+    ///
+    /// Every message counts towards flood detection, including the ones that
+    /// break another rule, except for messages older than the flood window,
+    /// which would only be counted against a window that has passed.
+    ///
+    /// # Returns
+    ///
+    /// Returns the first rule the message broke, if any.
+    pub async fn check(
+        &self,
+        pool: &PgPool,
+        msg: &Message,
+        user: UserId,
+    ) -> Result<Option<Violation>, sqlx::Error> {
+        let flood = if self.rules.is_stale(msg.date, Utc::now()) {
+            None
+        } else {
+            self.track(msg.chat.id, user, msg.date).await
+        };
+
+        if let Some(violation) = self.rules.check_content(msg) {
+            return Ok(Some(violation));
+        }
+
+        if let Some(period) = self.rules.new_member_period {
+            if has_links_or_media(msg) {
+                let joined = joined_at(pool, msg.chat.id, user).await?;
+                if joined.is_some_and(|joined| msg.date - joined < period) {
+                    return Ok(Some(Violation::NewMember));
+                }
+            }
+        }
+
+        Ok(flood)
+    }
+
+    /// Records a message sent at `at` and checks the user's message rate.
+    async fn track(&self, chat: ChatId, user: UserId, at: DateTime<Utc>) -> Option<Violation> {
+        let max_messages = self.rules.flood_max_messages?;
+        let window = self.rules.flood_window;
+        let cutoff = at - window;
+
+        let mut recent = self.recent.lock().await;
+        if recent.len() >= MAX_TRACKED_USERS {
+            recent.retain(|_, times| times.back().is_some_and(|last| *last > cutoff));
+        }
+
+        let times = recent.entry((chat, user)).or_default();
+        times.push_back(at);
+        while times.front().is_some_and(|first| *first <= cutoff) {
+            times.pop_front();
+        }
+
+        (times.len() > max_messages).then_some(Violation::Flood {
+            messages: times.len(),
+            window,
+        })
+    }
+}
+
+/// Records that a user joined a chat, restarting their new member period if
+/// they had joined before.
+pub async fn record_join(
+    pool: &PgPool,
+    chat: ChatId,
+    user: UserId,
+    at: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO member_joins (chat_id, user_id, joined_at) VALUES ($1, $2, $3)
+         ON CONFLICT (chat_id, user_id) DO UPDATE SET joined_at = EXCLUDED.joined_at",
+    )
+    .bind(chat.0)
+    .bind(user.0 as i64)
+    .bind(at)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Looks up when a user last joined a chat, if the bot saw them join.
+async fn joined_at(
+    pool: &PgPool,
+    chat: ChatId,
+    user: UserId,
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    let joined: Option<(DateTime<Utc>,)> =
+        sqlx::query_as("SELECT joined_at FROM member_joins WHERE chat_id = $1 AND user_id = $2")
+            .bind(chat.0)
+            .bind(user.0 as i64)
+            .fetch_optional(pool)
+            .await?;
+    Ok(joined.map(|(joined_at,)| joined_at))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    fn rules() -> FilterRules {
+        FilterRules {
+            blocked_domains: parse_list("bit.ly, .Example.com"),
+            keywords: parse_list("cheap pills"),
+            patterns: parse_patterns(r"(buy|sell)\s+tramadol").unwrap(),
+            flood_max_messages: Some(3),
+            flood_window: Duration::seconds(10),
+            new_member_period: None,
+            penalty: Penalty::None,
+        }
+    }
+
+    /// Builds a group message with the given text and entities.
+    fn message(text: &str, entities: serde_json::Value) -> Message {
+        let msg = json!({
+            "message_id": 1,
+            "date": 1_700_000_000,
+            "chat": { "id": -1001, "type": "supergroup", "title": "Pharmacy" },
+            "from": { "id": 42, "is_bot": false, "first_name": "Sam" },
+            "text": text,
+            "entities": entities,
+        });
+        serde_json::from_str(&msg.to_string()).unwrap()
+    }
+
+    fn url(offset: usize, length: usize) -> serde_json::Value {
+        json!({ "type": "url", "offset": offset, "length": length })
+    }
+
+    fn utc(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    #[test]
+    fn lists_and_patterns_are_parsed_from_settings() {
+        assert_eq!(
+            parse_list(" Bit.ly,,.example.com , "),
+            vec!["bit.ly", "example.com"]
+        );
+        assert!(parse_list("").is_empty());
+
+        let patterns = parse_patterns(" buy\\s+pills ; ;SELL").unwrap();
+        assert_eq!(patterns.len(), 2);
+        assert!(patterns[0].is_match("BUY   pills"));
+        assert!(patterns[1].is_match("sell"));
+        assert!(matches!(
+            parse_patterns("ok;(unclosed"),
+            Err(FilterError::InvalidPattern { pattern, .. }) if pattern == "(unclosed"
+        ));
+    }
+
+    #[test]
+    fn penalty_is_parsed_from_the_action() {
+        let hours = Duration::hours(24);
+        assert_eq!(
+            Penalty::from_action("delete", hours).unwrap(),
+            Penalty::None
+        );
+        assert_eq!(
+            Penalty::from_action(" Mute ", hours).unwrap(),
+            Penalty::Mute(hours)
+        );
+        assert_eq!(Penalty::from_action("BAN", hours).unwrap(), Penalty::Ban);
+        assert!(matches!(
+            Penalty::from_action("kick", hours),
+            Err(FilterError::UnknownAction(action)) if action == "kick"
+        ));
+    }
+
+    #[test]
+    fn link_hosts_include_bare_and_text_links() {
+        let msg = message(
+            "see www.Bit.ly/x and https://shop.example.com/a or here",
+            json!([
+                url(4, 13),
+                url(22, 28),
+                { "type": "text_link", "offset": 54, "length": 4, "url": "https://t.me/spam" },
+            ]),
+        );
+        assert_eq!(
+            link_hosts(&msg),
+            vec!["www.bit.ly", "shop.example.com", "t.me"]
+        );
+        assert!(link_hosts(&message("no links here", json!([]))).is_empty());
+    }
+
+    #[test]
+    fn content_rules_match_domains_keywords_and_patterns() {
+        let rules = rules();
+
+        let subdomain = message("shop.example.com", json!([url(0, 16)]));
+        assert_eq!(
+            rules.check_content(&subdomain),
+            Some(Violation::BlockedLink("example.com".to_string()))
+        );
+        // Only whole domain labels match
+        let lookalike = message("notexample.com", json!([url(0, 14)]));
+        assert_eq!(rules.check_content(&lookalike), None);
+
+        let keyword = message("Get CHEAP PILLS now", json!([]));
+        assert_eq!(
+            rules.check_content(&keyword),
+            Some(Violation::Keyword("cheap pills".to_string()))
+        );
+
+        let pattern = message("I can sell  Tramadol", json!([]));
+        assert!(matches!(
+            rules.check_content(&pattern),
+            Some(Violation::Pattern(_))
+        ));
+
+        let harmless = message("Is paracetamol in stock?", json!([]));
+        assert_eq!(rules.check_content(&harmless), None);
+    }
+
+    #[test]
+    fn messages_older_than_the_flood_window_are_stale() {
+        let rules = rules();
+        assert!(!rules.is_stale(utc(0), utc(10)));
+        assert!(rules.is_stale(utc(0), utc(11)));
+    }
+
+    #[tokio::test]
+    async fn stale_messages_are_deleted_without_a_penalty() {
+        let filter = SpamFilter::new(FilterRules {
+            penalty: Penalty::Ban,
+            ..rules()
+        });
+        // Never connects, as checking content doesn't need the database
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/unused")
+            .unwrap();
+
+        // Sent in 2023, long before the flood window
+        let msg = message("shop.example.com", json!([url(0, 16)]));
+        assert_eq!(
+            filter.check(&pool, &msg, UserId(42)).await.unwrap(),
+            Some(Violation::BlockedLink("example.com".to_string()))
+        );
+        assert_eq!(filter.penalty_for(&msg), Penalty::None);
+        assert!(filter.recent.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn flood_is_detected_within_the_window() {
+        let filter = SpamFilter::new(rules());
+        let (chat, user) = (ChatId(-1001), UserId(42));
+
+        for secs in 0..3 {
+            assert_eq!(filter.track(chat, user, utc(secs)).await, None);
+        }
+        assert_eq!(
+            filter.track(chat, user, utc(3)).await,
+            Some(Violation::Flood {
+                messages: 4,
+                window: Duration::seconds(10)
+            })
+        );
+        // Other users and chats are counted separately
+        assert_eq!(filter.track(chat, UserId(7), utc(3)).await, None);
+        assert_eq!(filter.track(ChatId(-1002), user, utc(3)).await, None);
+
+        // Once the window has passed, only recent messages count
+        assert_eq!(filter.track(chat, user, utc(12)).await, None);
+    }
+
+    #[tokio::test]
+    async fn tracked_users_are_swept_once_the_limit_is_reached() {
+        let filter = SpamFilter::new(rules());
+        let chat = ChatId(-1001);
+
+        {
+            let mut recent = filter.recent.lock().await;
+            for id in 0..MAX_TRACKED_USERS as u64 {
+                recent.insert((chat, UserId(id)), VecDeque::from([utc(0)]));
+            }
+            recent.insert((chat, UserId(u64::MAX)), VecDeque::from([utc(25)]));
+        }
+
+        filter.track(chat, UserId(42), utc(30)).await;
+
+        // Users idle for longer than the window are forgotten
+        let recent = filter.recent.lock().await;
+        assert_eq!(recent.len(), 2);
+        assert!(recent.contains_key(&(chat, UserId(u64::MAX))));
+        assert!(recent.contains_key(&(chat, UserId(42))));
+    }
+}
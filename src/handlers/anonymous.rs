@@ -0,0 +1,350 @@
+use std::sync::Arc;
+use teloxide::{prelude::*, types::Me};
+
+use crate::{
+    config::Config, dialogues::MyDialogue, relay, repositories::Repositories, rota,
+    topics::StaffTopic, Error,
+};
+
+/// Sends an anonymous message from a user to a pharmacist.
+///
+/// This function handles the process of sending an anonymous message from a user to a pharmacist.
+/// It's designed to be used in a state where the user has already initiated the process of sending
+/// a message to a pharmacist.
+///
+/// # Arguments
+///
+/// * `bot` - The Telegram Bot instance used to send messages.
+/// * `id` - The ChatId the link points to: a pharmacist, or the staff group
+///   for links that reach whoever is on duty.
+/// * `msg` - The Message object containing the user's message.
+/// * `repositories` - The repositories, used to look up who is on duty and to
+///   store the conversation and its ticket.
+/// * `config` - The bot configuration, which holds the media limits and the staff chat.
+/// * `dialogue` - The MyDialogue instance managing the conversation state.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+///
+/// # Function flow
+///
+/// 1. Check that the message is text, a photo, a document or a voice message within the media limits.
+/// 2. If it isn't, explain why and keep waiting for a message.
+/// 3. For staff group links, look up the pharmacist on duty; outside working
+///    hours the message goes to the staff group instead.
+/// 4. Deliver the message to the pharmacist. Media is copied rather than
+///    forwarded so the sender stays anonymous.
+/// 5. Once delivered, start an anonymous conversation with the delivered
+///    message, so the pharmacist can reply to it; failed deliveries store nothing.
+/// 6. Open a consultation ticket assigned to the pharmacist.
+/// 7. Notify the user of the result (success or failure), and tell them when
+///    the pharmacy reopens if it is closed.
+/// 8. Exit the dialogue state.
+///
+/// # Error handling
+///
+/// - If sending the message to the pharmacist fails, the user is notified of the error.
+/// - Any errors during the process are propagated up the call stack.
+pub async fn send_message_to_pharmacist(
+    bot: Bot,
+    id: ChatId,
+    msg: Message,
+    repositories: Repositories,
+    config: Arc<Config>,
+    dialogue: MyDialogue,
+) -> Result<(), Error> {
+    // Check the message against the media limits before anything is stored
+    let content = match relay::check_content(&msg, &config.media_policy()) {
+        Ok(content) => content,
+        Err(reason) => {
+            // Keep the dialogue open so the user can try again
+            bot.send_message(msg.chat.id, reason).await?;
+            return Ok(());
+        }
+    };
+
+    // Links created in the staff group reach whoever is on duty; personal
+    // links always reach their owner
+    let staff_chat = ChatId(config.pharmacy_group_chat_id);
+    let now = chrono::Utc::now();
+    let duty = if id == staff_chat {
+        repositories.rota.on_duty(config.timezone(), now).await?
+    } else {
+        rota::Duty::OnDuty(id)
+    };
+    let (target, assignee, closed_notice) = match duty {
+        rota::Duty::OnDuty(pharmacist) => (pharmacist, Some(pharmacist), None),
+        rota::Duty::Closed { reopens_at } => (
+            staff_chat,
+            None,
+            Some(format!(
+                "We're closed right now, we'll reply {}.",
+                rota::describe_reopening(reopens_at, now)
+            )),
+        ),
+        rota::Duty::NoRota => (staff_chat, None, None),
+    };
+
+    // Attempt to deliver the message to the pharmacist
+    let sent_result = relay::deliver(
+        &bot,
+        &msg,
+        content,
+        target,
+        config.staff_thread(target, StaffTopic::Consultations),
+        "You have a new anonymous message:",
+        "Reply to this message to answer anonymously.",
+    )
+    .await;
+
+    // Notify the user based on the result
+    if let Ok(sent) = sent_result {
+        // Every anonymous message opens its own conversation, stored together
+        // with the delivered message so the pharmacist can reply to it
+        let conversation = repositories
+            .relay
+            .start_conversation(msg.chat.id, target, sent)
+            .await?;
+
+        // Track the consultation as a ticket; outside working hours it stays
+        // open for whoever picks it up in the staff group
+        let ticket = repositories.tickets.open(conversation.id, assignee).await?;
+        bot.send_message(
+            msg.chat.id,
+            format!(
+                "Message sent to the pharmacist! Your consultation number is #{}. Their reply will appear here.",
+                ticket.id
+            ),
+        )
+        .await?;
+        if let Some(notice) = closed_notice {
+            bot.send_message(msg.chat.id, notice).await?;
+        }
+    } else {
+        bot.send_message(
+            msg.chat.id,
+            "Error sending message. The pharmacist may have blocked the bot.",
+        )
+        .await?;
+    }
+
+    // Exit the dialogue state
+    dialogue.exit().await?;
+    Ok(())
+}
+
+/// Finds the anonymous conversation a message replies to.
+///
+/// Used as a dispatcher filter: the update only reaches `relay_reply` when
+/// the message is a reply to a message the bot relayed into this chat.
+pub async fn find_relayed_conversation(
+    msg: Message,
+    repositories: Repositories,
+) -> Option<relay::Conversation> {
+    let replied = msg.reply_to_message()?;
+    match repositories
+        .relay
+        .find_by_message(msg.chat.id, replied.id)
+        .await
+    {
+        Ok(conversation) => conversation,
+        Err(e) => {
+            log::error!("Failed to look up relayed message: {}", e);
+            None
+        }
+    }
+}
+
+/// Passes a reply on to the other side of an anonymous conversation.
+///
+/// Replies from the pharmacist go back to the person who wrote anonymously and
+/// vice versa. The bot re-sends the text itself, so neither side learns who
+/// the other is. Photos, documents and voice messages are relayed under the
+/// same limits as the first message.
+///
+/// # Arguments
+///
+/// * `bot` - The Telegram Bot instance used to send messages.
+/// * `msg` - The reply to relay.
+/// * `conversation` - The conversation the replied-to message belongs to.
+/// * `repositories` - The repositories, used to record the reply and move the
+///   ticket along.
+/// * `config` - The bot configuration, which holds the media limits.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+pub async fn relay_reply(
+    bot: Bot,
+    msg: Message,
+    conversation: relay::Conversation,
+    repositories: Repositories,
+    config: Arc<Config>,
+) -> Result<(), Error> {
+    let Some(target) = conversation.counterpart(msg.chat.id) else {
+        return Ok(());
+    };
+
+    let content = match relay::check_content(&msg, &config.media_policy()) {
+        Ok(content) => content,
+        Err(reason) => {
+            bot.send_message(msg.chat.id, reason).await?;
+            return Ok(());
+        }
+    };
+
+    // Label the message depending on which side it comes from
+    let (header, footer) = if msg.chat.id == conversation.recipient() {
+        (
+            "💬 The pharmacist replied:",
+            "Reply to this message to continue the conversation.",
+        )
+    } else {
+        (
+            "💬 New anonymous reply:",
+            "Reply to this message to answer anonymously.",
+        )
+    };
+
+    let thread_id = config.staff_thread(target, StaffTopic::Consultations);
+    match relay::deliver(&bot, &msg, content, target, thread_id, header, footer).await {
+        Ok(sent) => {
+            repositories
+                .relay
+                .record_delivery(conversation.id, target, sent)
+                .await?;
+
+            // Move the ticket along: an answer from the pharmacist waits for the
+            // patient, a message from the patient waits for the pharmacist
+            let tickets = &repositories.tickets;
+            if let Some(ticket) = tickets.find_by_conversation(conversation.id).await? {
+                if msg.chat.id == conversation.recipient() {
+                    tickets.record_pharmacist_reply(ticket.id).await?;
+                } else {
+                    tickets.record_patient_reply(ticket.id).await?;
+                }
+            }
+            bot.send_message(msg.chat.id, "Reply sent!").await?;
+        }
+        Err(e) => {
+            log::warn!(
+                "Failed to relay reply in conversation {}: {}",
+                conversation.id,
+                e
+            );
+            bot.send_message(
+                msg.chat.id,
+                "Error sending reply. The other side may have blocked the bot.",
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Lists the sender's anonymous message links that can still be used.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to send messages.
+/// * `msg` - The message that triggered this command.
+/// * `repositories` - The repositories, holding the links.
+/// * `me` - Information about the bot itself, used to build the links.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+pub async fn list_links(
+    bot: Bot,
+    msg: Message,
+    repositories: Repositories,
+    me: Me,
+) -> Result<(), Error> {
+    let tokens = repositories.links.list_active(msg.chat.id).await?;
+
+    if tokens.is_empty() {
+        bot.send_message(
+            msg.chat.id,
+            "You have no active links. Use /message to create one.",
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let entries = tokens
+        .iter()
+        .map(|token| {
+            let uses = match token.max_uses {
+                Some(max_uses) => format!("{}/{} uses", token.use_count, max_uses),
+                None => format!("{} uses", token.use_count),
+            };
+            let expiry = match token.expires_at {
+                Some(at) => format!("expires {}", at.format("%d-%m-%Y %H:%M UTC")),
+                None => "never expires".to_string(),
+            };
+            format!(
+                "🔗 {}?start={}\n   {}, {}",
+                me.tme_url(),
+                token.token,
+                uses,
+                expiry
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n\n");
+
+    bot.send_message(
+        msg.chat.id,
+        format!(
+            "Your active links:\n\n{}\n\nUse /revoke <token> to disable one.",
+            entries
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Revokes one or all of the sender's anonymous message links.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to send messages.
+/// * `msg` - The message that triggered this command.
+/// * `repositories` - The repositories, holding the links.
+/// * `token` - The token to revoke, or `all`; `None` replies with the usage.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+pub async fn revoke_links(
+    bot: Bot,
+    msg: Message,
+    repositories: Repositories,
+    token: Option<String>,
+) -> Result<(), Error> {
+    // Accept either the bare token or the whole link
+    let token = token
+        .as_deref()
+        .and_then(|token| token.rsplit("start=").next())
+        .unwrap_or_default();
+
+    let reply = match token {
+        "" => "Usage: /revoke <token> or /revoke all".to_string(),
+        "all" => {
+            let count = repositories.links.revoke_all(msg.chat.id).await?;
+            format!("Revoked {} link(s).", count)
+        }
+        token => {
+            if repositories.links.revoke(msg.chat.id, token).await? {
+                "Link revoked.".to_string()
+            } else {
+                "No active link with that token.".to_string()
+            }
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply).await?;
+    Ok(())
+}
@@ -0,0 +1,1114 @@
+mod anonymous;
+mod orders;
+mod sanctions;
+mod staff;
+
+use dptree::{case, di::DependencyMap};
+use sqlx::PgPool;
+use std::sync::Arc;
+use teloxide::{
+    dispatching::{
+        dialogue::{self, ErasedStorage},
+        UpdateFilterExt, UpdateHandler,
+    },
+    prelude::*,
+    types::{Me, ReplyMarkup},
+    utils::command::{BotCommands, ParseError},
+};
+
+use crate::{
+    captcha::Captcha,
+    commands::{Command, RestrictTime, StaffCommand},
+    config::Config,
+    dialogues::{MyDialogue, State},
+    error::{self, AppError},
+    filters::{self, Penalty, SpamFilter, Violation},
+    jobs::JobRegistry,
+    keyboards, metrics,
+    moderation::Target,
+    modlog::{ModAction, NewAction},
+    queue::MessageQueue,
+    repositories::Repositories,
+    roles::{Restricted, Role},
+    Error,
+};
+
+use anonymous::{
+    find_relayed_conversation, list_links, relay_reply, revoke_links, send_message_to_pharmacist,
+};
+use orders::{list_inventory, place_order};
+use sanctions::{
+    apply_ban, apply_mute, ban_user, clear_user_warnings, kick_user, list_user_warnings, mute_user,
+    show_modlog, unban_user, unmute_user, warn_user,
+};
+use staff::{
+    add_shift, close_ticket, list_jobs, list_rota, list_staff, list_tickets, remove_shift, run_job,
+    set_user_role, who_am_i,
+};
+
+/// Everything the handlers depend on besides the bot, `Me` and the update.
+///
+/// `main` and the end-to-end tests both hand these to [`schema`] through
+/// [`Services::dependencies`], so the tests run the handlers wired exactly as
+/// the bot does.
+#[derive(Clone)]
+pub struct Services {
+    pub config: Arc<Config>,
+    pub pool: PgPool,
+    pub repositories: Repositories,
+    pub queue: MessageQueue,
+    pub jobs: JobRegistry,
+    pub spam_filter: SpamFilter,
+    pub captcha: Captcha,
+    pub storage: Arc<ErasedStorage<State>>,
+}
+
+impl Services {
+    /// Builds the dependency map the handlers are dispatched with. The
+    /// dispatcher adds the bot, `Me` and the update itself.
+    pub fn dependencies(self) -> DependencyMap {
+        dptree::deps![
+            self.config,
+            self.pool,
+            self.repositories,
+            self.queue,
+            self.jobs,
+            self.spam_filter,
+            self.captcha,
+            self.storage
+        ]
+    }
+}
+
+/// Builds the tree of handlers every update goes through.
+///
+/// The dependencies it expects are the [`Services`], along with the bot,
+/// `Me` and the update itself.
+pub fn schema() -> UpdateHandler<Error> {
+    // Explanation of each line:
+    // 0. Attach the update to errors
+    //    - with_update_context() wraps any error returned below with the update id,
+    //      chat and user, which ErrorLogger logs along with the error
+
+    // 0a. Remember the sender of every update
+    //    - inspect_async(remember_sender) records the user in the known_users table
+    //      and lets the update through unchanged, so moderation commands can
+    //      target users by @username
+
+    // 0b. Handle taps on join challenge buttons
+    //    - Callback queries from challenge buttons go to 'answer_challenge', which lets
+    //      the new member talk or removes them
+
+    // 1. Create a handler using dialogue::enter
+    //    This sets up a dialogue system to manage different conversation states
+    //    The generic types specify:
+    //    - Update: Represents an update from the Telegram API
+    //    - ErasedStorage<State>: The configured dialogue storage, in memory or in Postgres
+    //    - State: Our custom State enum for tracking conversation state
+    //    - _: Placeholder for the return type of the handler
+
+    // 2. Record members joining groups
+    //    - Service messages listing new chat members go to 'record_new_members',
+    //      which stores their join time for the spam filter and posts their join
+    //      challenge
+
+    // 3. Remove spam
+    //    - filter_map_async(screen_message) checks group messages against the spam
+    //      filter; staff and chat administrators are exempt
+    //    - Messages that break a rule go to 'enforce_filter', which deletes them and
+    //      applies the configured penalty; everything else carries on below
+
+    // 4. Handle command messages
+    //    - Uses Update::filter_message() to only process message updates
+    //    - Further filters with filter_command::<Command>() to handle bot commands
+    //    - Routes these to the 'answer' function
+    //    - Staff commands are parsed separately with filter_command::<StaffCommand>()
+    //      and routed to the 'answer_staff' function
+    //    - Before either, the caller's role is looked up with filter_map_async(caller_role);
+    //      commands the role doesn't allow go to 'deny_command' instead
+    //    - Allowed commands are counted for the metrics with inspect(count_command)
+
+    // 5. Handle messages in the WriteToPharmacist state
+    //    - Again uses Update::filter_message() to process only message updates
+    //    - Uses case![State::WriteToPharmacist { id }] to match the specific state
+    //    - Routes these to the 'send_message_to_pharmacist' function
+
+    // 6. Handle replies to relayed anonymous messages
+    //    - Looks up the conversation of the message being replied to
+    //    - Routes these to the 'relay_reply' function, which passes the reply
+    //      on to the other side of the conversation
+
+    // 7. Handle all other messages
+    //    - Catches any remaining message updates
+    //    - Routes these to the 'handle_message' function
+
+    // This structure allows the bot to handle different types of interactions,
+    // maintaining state when necessary and providing a catch-all for general messages.
+
+    dptree::entry()
+        // Say which update an error came from when it is logged
+        .chain(error::with_update_context())
+        // Remember who sent each update, so they can be named by @username later
+        .inspect_async(remember_sender)
+        // Handle taps on join challenge buttons
+        .branch(
+            Update::filter_callback_query()
+                .filter(|query: CallbackQuery| Captcha::is_challenge_answer(&query))
+                .endpoint(answer_challenge),
+        )
+        .branch(
+            dialogue::enter::<Update, ErasedStorage<State>, State, _>()
+                // Record members joining groups
+                .branch(
+                    Update::filter_message()
+                        .filter(|msg: Message| msg.new_chat_members().is_some())
+                        .endpoint(record_new_members),
+                )
+                // Remove spam before anything else sees it
+                .branch(
+                    Update::filter_message()
+                        .filter_map_async(screen_message)
+                        .endpoint(enforce_filter),
+                )
+                // Handle command messages
+                .branch(
+                    Update::filter_message()
+                        .branch(
+                            dptree::entry()
+                                .filter_command::<Command>()
+                                .filter_map_async(caller_role)
+                                .branch(
+                                    dptree::filter(has_required_role::<Command>)
+                                        .inspect(count_command)
+                                        .endpoint(answer),
+                                )
+                                .endpoint(deny_command),
+                        )
+                        .branch(
+                            dptree::entry()
+                                .filter_command::<StaffCommand>()
+                                .filter_map_async(caller_role)
+                                .branch(
+                                    dptree::filter(has_required_role::<StaffCommand>)
+                                        .inspect(count_command)
+                                        .endpoint(answer_staff),
+                                )
+                                .endpoint(deny_command),
+                        ),
+                )
+                // Handle messages in the WriteToPharmacist state
+                .branch(Update::filter_message().branch(
+                    case![State::WriteToPharmacist { id }].endpoint(send_message_to_pharmacist),
+                ))
+                // Handle replies to relayed anonymous messages
+                .branch(
+                    Update::filter_message()
+                        .filter_map_async(find_relayed_conversation)
+                        .endpoint(relay_reply),
+                )
+                // Handle all other messages
+                .branch(Update::filter_message().endpoint(handle_message)),
+        )
+}
+
+/// Looks up the role of the user who sent a command.
+///
+/// Used as a dispatcher filter in front of the command endpoints. Users that
+/// can't be identified, and any lookup failure, count as customers.
+async fn caller_role(msg: Message, repositories: Repositories) -> Option<Role> {
+    let Some(user) = msg.from.as_ref() else {
+        return Some(Role::Customer);
+    };
+    match repositories.roles.role_of(user.id).await {
+        Ok(role) => Some(role),
+        Err(e) => {
+            log::error!("Failed to look up the role of user {}: {}", user.id, e);
+            Some(Role::Customer)
+        }
+    }
+}
+
+/// Records the sender of an update, so staff can later name them by username.
+///
+/// Failures are only logged; the update is handled either way.
+async fn remember_sender(update: Update, repositories: Repositories) {
+    let Some(user) = update.from() else {
+        return;
+    };
+    if let Err(e) = repositories.users.remember(user).await {
+        log::error!("Failed to remember user {}: {}", user.id, e);
+    }
+}
+
+/// Records members joining a group, so the spam filter can hold back their
+/// first links and media, and challenges them to show they aren't bots.
+///
+/// Failures are logged per member, so one of them doesn't leave the others
+/// unchecked.
+async fn record_new_members(msg: Message, pool: PgPool, captcha: Captcha) -> Result<(), Error> {
+    for user in msg.new_chat_members().unwrap_or_default() {
+        if user.is_bot {
+            continue;
+        }
+        if let Err(e) = filters::record_join(&pool, msg.chat.id, user.id, msg.date).await {
+            log::error!(
+                "Failed to record user {} joining chat {}: {}",
+                user.id,
+                msg.chat.id,
+                e
+            );
+        }
+        if let Err(e) = captcha.challenge(msg.chat.id, user).await {
+            log::error!(
+                "Failed to challenge user {} in chat {}: {}",
+                user.id,
+                msg.chat.id,
+                e
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Handles a tap on a join challenge button.
+async fn answer_challenge(query: CallbackQuery, captcha: Captcha) -> Result<(), Error> {
+    captcha.answer(&query).await
+}
+
+/// Checks a group message against the spam filter.
+///
+/// Used as a dispatcher filter in front of `enforce_filter`. Staff and chat
+/// administrators are trusted, as are bots and anonymous admins, which post
+/// through a bot account. Failures are logged and let the message through.
+///
+/// # Returns
+///
+/// Returns the rule the message broke, or `None` to handle it as usual.
+async fn screen_message(
+    bot: Bot,
+    msg: Message,
+    pool: PgPool,
+    repositories: Repositories,
+    filter: SpamFilter,
+) -> Option<Violation> {
+    if msg.chat.is_private() {
+        return None;
+    }
+    let user = msg.from.as_ref().filter(|user| !user.is_bot)?;
+
+    let violation = match filter.check(&pool, &msg, user.id).await {
+        Ok(violation) => violation?,
+        Err(e) => {
+            log::error!(
+                "Failed to check message {} against the filter: {}",
+                msg.id,
+                e
+            );
+            return None;
+        }
+    };
+
+    match repositories.roles.role_of(user.id).await {
+        Ok(role) if role >= Role::Pharmacist => return None,
+        Ok(_) => {}
+        Err(e) => log::error!("Failed to look up the role of user {}: {}", user.id, e),
+    }
+    match bot.get_chat_member(msg.chat.id, user.id).await {
+        Ok(member) if member.kind.is_privileged() => None,
+        Ok(_) => Some(violation),
+        Err(e) => {
+            log::error!("Failed to look up chat member {}: {}", user.id, e);
+            None
+        }
+    }
+}
+
+/// Deletes a message that broke a spam filter rule and penalizes its sender.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to interact with the Telegram API.
+/// * `msg` - The offending message.
+/// * `repositories` - The repositories, whose moderation log the action is recorded in.
+/// * `config` - The bot configuration, which holds the audit log settings.
+/// * `queue` - The outbound message queue the audit log posts through.
+/// * `filter` - The spam filter, which holds the penalty.
+/// * `violation` - The rule the message broke.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+///
+/// # Function flow
+///
+/// 1. Delete the message.
+/// 2. Mute or ban the sender if the filter is configured to, unless the
+///    message is older than the flood window.
+/// 3. Record what was done in the audit log, with the bot as the moderator.
+/// 4. Tell the chat about a mute or ban; plain deletions stay quiet so that
+///    flooding doesn't produce even more messages.
+///
+/// # Note
+///
+/// Telegram failures, such as the bot not being allowed to delete messages,
+/// are logged rather than returned, so the remaining steps still happen.
+async fn enforce_filter(
+    bot: Bot,
+    msg: Message,
+    repositories: Repositories,
+    config: Arc<Config>,
+    queue: MessageQueue,
+    filter: SpamFilter,
+    violation: Violation,
+) -> Result<(), Error> {
+    let Some(user) = msg.from.as_ref() else {
+        return Ok(());
+    };
+    let target = Target {
+        id: user.id,
+        name: user.full_name(),
+    };
+    log::info!(
+        "Filtered message {} from user {} in chat {}: {}",
+        msg.id,
+        target.id,
+        msg.chat.id,
+        violation
+    );
+
+    // 1. Delete the message
+    if let Err(e) = bot.delete_message(msg.chat.id, msg.id).await {
+        log::warn!("Failed to delete filtered message {}: {}", msg.id, e);
+    }
+
+    // 2. Apply the penalty, falling back to the deletion alone if it fails
+    let penalty = match filter.penalty_for(&msg) {
+        Penalty::None => None,
+        Penalty::Mute(duration) => Some((ModAction::Mute, RestrictTime::For(duration))),
+        Penalty::Ban => Some((ModAction::Ban, RestrictTime::Permanent)),
+    };
+    let applied = match penalty {
+        Some((action, time)) => {
+            let result = match action {
+                ModAction::Ban => apply_ban(&bot, &msg, target.id, time).await,
+                _ => apply_mute(&bot, &msg, target.id, time).await,
+            };
+            match result {
+                Ok(()) => Some((action, time)),
+                Err(e) => {
+                    log::warn!("Failed to penalize user {}: {}", target.id, e);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+
+    // 3. Record the action, taken by the bot itself
+    let reason = format!("Automatic filter: {}", violation);
+    let (action, duration) = match applied {
+        Some((action, time)) => (action, time.duration()),
+        None => (ModAction::Delete, None),
+    };
+    config
+        .audit_log(repositories.modlog, queue)
+        .record(NewAction {
+            chat: &msg.chat,
+            actor: None,
+            target: target.id,
+            target_name: &target.name,
+            action,
+            duration,
+            reason: Some(&reason),
+        })
+        .await?;
+
+    // 4. Explain a mute or ban in the chat
+    if let Some((action, time)) = applied {
+        let outcome = match action {
+            ModAction::Ban => "banned".to_string(),
+            _ => format!("muted {}", time),
+        };
+        bot.send_message(
+            msg.chat.id,
+            format!(
+                "🚫 Removed a message from {}: {}. They have been {}.",
+                target.name, violation, outcome
+            ),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Returns `true` if `role` is allowed to use `cmd`.
+fn has_required_role<C: Restricted>(cmd: C, role: Role) -> bool {
+    role >= cmd.required_role()
+}
+
+/// Counts a command for the metrics, by its name without the slash or the
+/// bot's username.
+fn count_command(msg: Message) {
+    let name = msg
+        .text()
+        .and_then(|text| text.split_whitespace().next())
+        .map(|command| command.trim_start_matches('/'))
+        .map(|command| command.split('@').next().unwrap_or(command))
+        .unwrap_or_default();
+    metrics::global().command_handled(&name.to_lowercase());
+}
+
+/// Replies to a command the caller isn't allowed to use.
+async fn deny_command(bot: Bot, msg: Message) -> Result<(), Error> {
+    let refusal = AppError::Forbidden("the caller's role doesn't allow the command".to_string());
+    error::reply_on_error(&bot, &msg, Err(refusal)).await?;
+    Ok(())
+}
+
+/// Handles bot commands and responds accordingly.
+///
+/// This function is responsible for processing various bot commands and executing
+/// the appropriate actions based on the received command.
+///
+/// # Arguments
+///
+/// * `bot` - The Telegram Bot instance used to send messages.
+/// * `msg` - The received message containing the command.
+/// * `cmd` - The parsed command enum.
+/// * `pool` - The database connection pool.
+/// * `repositories` - The repositories of the bot.
+/// * `config` - The bot configuration.
+/// * `queue` - The outbound message queue.
+/// * `dialogue` - The dialogue state for managing conversation flow.
+/// * `me` - Information about the bot itself.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the command handling.
+#[allow(clippy::too_many_arguments)]
+async fn answer(
+    bot: Bot,
+    msg: Message,
+    cmd: Command,
+    pool: PgPool,
+    repositories: Repositories,
+    config: Arc<Config>,
+    queue: MessageQueue,
+    dialogue: MyDialogue,
+    me: Me,
+) -> Result<(), Error> {
+    match cmd {
+        Command::Start(start_param) => {
+            // This block handles the /start command with an optional parameter
+            if start_param.is_empty() {
+                // Case 1: No start parameter provided
+                // Log the received command and send a welcome message
+                log::info!("Received start command without parameter");
+                bot.send_message(msg.chat.id, "Welcome to the pharmacy bot!")
+                    .await?;
+            } else {
+                // Case 2 & 3: Start parameter provided (could be valid or invalid)
+                // Look the start parameter up as a deep-link token, and only count a
+                // use once the conversation has been set up
+                let recipient = match repositories.links.recipient(&start_param).await? {
+                    Some(id) => {
+                        dialogue.update(State::WriteToPharmacist { id }).await?;
+                        let redeemed = repositories.links.redeem(&start_param).await?;
+                        if redeemed.is_none() {
+                            // Used up or revoked in the meantime
+                            dialogue.exit().await?;
+                        }
+                        redeemed
+                    }
+                    None => None,
+                };
+                match recipient {
+                    Some(_) => {
+                        // Case 2: Valid token
+                        // Prompt the user to send a message to the pharmacist; the
+                        // dialogue is already in the WriteToPharmacist state
+                        log::info!("Received start command with a valid message link");
+                        bot.send_message(msg.chat.id, "Send your message to the pharmacist:")
+                            .await?;
+                    }
+                    None => {
+                        // Case 3: Unknown, expired, revoked or used-up token
+                        // Inform the user that the link can't be used
+                        log::warn!("Received start command with an unusable message link");
+                        bot.send_message(
+                            msg.chat.id,
+                            "This link is invalid, has expired or has been revoked.",
+                        )
+                        .await?;
+                    }
+                }
+            }
+
+            // To test:
+            // 1. Send "/start" command to the bot
+            // 2. Use a deep link created with /message (e.g., "t.me/YourBot?start=<token>")
+            // 3. Use a deep link with a made-up, revoked or expired token
+        }
+        Command::Message => {
+            // Generate a fresh token and send the link for anonymous communication
+            let token = repositories
+                .links
+                .create_token(msg.chat.id, config.token_policy())
+                .await?;
+            let message_link = format!("{}?start={}", me.tme_url(), token.token);
+
+            let mut reply = format!(
+                "Share this link to receive anonymous messages: {}",
+                message_link
+            );
+            if let Some(expires_at) = token.expires_at {
+                reply.push_str(&format!(
+                    "\nValid until {}.",
+                    expires_at.format("%d-%m-%Y %H:%M UTC")
+                ));
+            }
+            if let Some(max_uses) = token.max_uses {
+                reply.push_str(&format!("\nCan be used {} time(s).", max_uses));
+            }
+            reply.push_str("\nUse /revoke to disable it.");
+
+            bot.send_message(msg.chat.id, reply).await?;
+
+            // Test case: Send "/message" command to the bot and verify the response
+            // Expected behavior:
+            // 1. A new random token is stored for the sender's chat
+            // 2. The link contains the token, never the chat ID
+        }
+        Command::Links => {
+            log::info!("Received links command");
+            list_links(bot, msg, repositories, me).await?
+
+            // Test case: Send "/links" after creating a few links with /message
+            // Expected behavior:
+            // 1. Every link that is still usable is listed with its usage and expiry
+            // 2. Revoked, expired and used-up links are not listed
+        }
+        Command::Revoke(token) => {
+            log::info!("Received revoke command");
+            revoke_links(bot, msg, repositories, token).await?
+
+            // Test case: Send "/revoke <token>" and then open the revoked link
+            // Expected behavior:
+            // 1. The bot confirms the link was revoked
+            // 2. Opening the link afterwards replies that it was revoked
+            // 3. "/revoke all" disables every link of the sender
+        }
+        Command::Inventory => {
+            // Handle inventory command
+            log::info!("Received inventory command");
+            let result = list_inventory(bot.clone(), msg.clone(), repositories).await;
+            error::reply_on_error(&bot, &msg, result).await?;
+
+            // Test case: Send "/inventory" command to the bot
+            // Expected behavior:
+            // 1. The bot should log the received command
+            // 2. The list_inventory function should be called with the correct parameters
+            // 3. The function should return without errors
+            // 4. Verify that the inventory list is displayed to the user
+        }
+        Command::Order => {
+            // Handle order command
+            log::info!("Received order command");
+            let result = place_order(bot.clone(), msg.clone(), repositories, config, queue).await;
+            error::reply_on_error(&bot, &msg, result).await?;
+
+            // Test case: Send "/order" command to the bot
+            // Expected behavior:
+            // 1. The bot should log the received command
+            // 2. The place_order function should be called with the correct parameters
+            // 3. The function should return without errors
+            // 4. Verify that the order placement process is initiated for the user
+        }
+        Command::Menu => {
+            // Log the received menu command
+            log::info!("Received menu command");
+
+            // Create a custom keyboard with three options
+            let keyboard = keyboards::main_menu();
+
+            // Define the welcome message
+            let menu_text = "Welcome to the Pharmacy Bot! Please choose an option:";
+
+            // Send the message with the custom keyboard
+            bot.send_message(msg.chat.id, menu_text)
+                .reply_markup(ReplyMarkup::Keyboard(keyboard))
+                .await?;
+
+            // This code creates a custom keyboard menu for the Pharmacy Bot.
+            // It displays three options: Check Inventory, Place Order, and Help.
+            // The keyboard is resizable and disappears after one use.
+            // The bot sends a welcome message along with this custom keyboard.
+
+            // Test case: Send "/menu" command to the bot
+            // Expected behavior:
+            // 1. The bot should log the received command
+            // 2. A custom keyboard should be displayed with the specified options
+            // 3. The welcome message should be sent along with the keyboard
+            // 4. Verify that the keyboard is resizable and one-time use
+        }
+        Command::Help => {
+            // Display help information
+            log::info!("Received help command");
+            let help_text = [
+                "*Pharmacy Bot Help*",
+                "",
+                "Here are the available commands:",
+                "",
+                "/start \\- Start interacting with the pharmacy bot",
+                "/inventory \\- Check the pharmacy inventory",
+                "/order \\- Place a medicine order",
+                "/menu \\- Display the main menu",
+                "/help \\- Display this help information",
+                "",
+                "To use a command, simply type it or tap on it\\.",
+            ]
+            .join("\n");
+
+            bot.send_message(msg.chat.id, help_text)
+                .parse_mode(teloxide::types::ParseMode::MarkdownV2)
+                .await?;
+
+            // Test case: Send "/help" command to the bot
+            // Expected behavior:
+            // 1. The bot should log the received command
+            // 2. The help text should be constructed with the correct commands and formatting
+            // 3. The message should be sent to the user with Markdown parsing
+            // 4. Verify that the help information is displayed correctly to the user
+        }
+        Command::WhoAmI => {
+            log::info!("Received whoami command");
+            who_am_i(bot, msg, pool).await?
+
+            // Test case: Send "/whoami" to the bot
+            // Expected behavior:
+            // 1. The bot replies with the sender's user ID and role
+            // 2. Users without a role are shown as customers
+        }
+        Command::Kick(args) => {
+            log::info!("Received kick command");
+            kick_user(
+                bot,
+                msg,
+                me,
+                pool,
+                config.audit_log(repositories.modlog, queue),
+                args,
+            )
+            .await?
+
+            // This code handles the Kick command:
+            // 1. It logs that a kick command was received.
+            // 2. It calls the kick_user function with the bot, message and the target and reason.
+            // 3. The result of kick_user is propagated up with the ? operator.
+
+            // Test case: Send "/kick" command as a reply to another user's message
+            // Expected behavior:
+            // 1. The bot should log "Received kick command"
+            // 2. The kick_user function should be called with correct arguments
+            // 3. If kick_user succeeds, the command handler should return Ok(())
+            // 4. If kick_user fails, the error should be propagated up
+
+            // Additional test cases:
+            // - Send "/kick" without replying to a message or naming a user
+            // - Send "/kick @username spamming" to kick by username, with a reason
+            // - Send "/kick 123456789" to kick by user ID
+            // - Send "/kick" with a text mention of a user without a username
+            // - Send "/kick" as a non-admin user
+            // - Send "/kick" targeting an admin user
+            // - Send "/kick" in a private chat (should be refused)
+            // - Send "/kick" while the bot isn't an admin (should explain the missing right)
+        }
+        Command::Ban { time, args } => {
+            log::info!("Received ban command: {:?}", time);
+            ban_user(
+                bot,
+                msg,
+                me,
+                pool,
+                config.audit_log(repositories.modlog, queue),
+                time,
+                args,
+            )
+            .await?
+
+            // This code handles the Ban command:
+            // 1. It logs that a ban command was received, including the parsed duration.
+            // 2. It calls the ban_user function with the bot, message, duration, target and reason.
+            // 3. The result of ban_user is propagated up with the ? operator.
+
+            // Test case: Send "/ban 2h" command as a reply to another user's message
+            // Expected behavior:
+            // 1. The bot should log "Received ban command: For(TimeDelta { secs: 7200, nanos: 0 })"
+            // 2. The ban_user function should be called with correct arguments
+            // 3. The confirmation should say the user was banned "for 2h"
+            // 4. If ban_user succeeds, the command handler should return Ok(())
+            // 5. If ban_user fails, the error should be propagated up
+
+            // Additional test cases:
+            // - Send "/ban 30m" or the older "/ban 30 m" to ban for 30 minutes
+            // - Send "/ban 1d12h" to ban for a day and a half
+            // - Send "/ban permanent" or "/ban forever" to ban without an end date
+            // - Send "/ban 10s" or "/ban 400d" (should explain the 30 seconds to 366 days bounds)
+            // - Send "/ban 3x" (should list the allowed units)
+            // - Send "/ban 1h @username selling prescription drugs" to ban by username, with a reason
+            // - Send "/ban" without a duration (should ask for one)
+            // - Send "/ban" as a non-admin user (should be rejected)
+            // - Send "/ban" targeting an admin user (should be rejected)
+            // - Send "/ban" in a private chat (should be refused)
+            // - Send "/ban" while the bot isn't an admin (should explain the missing right)
+        }
+        Command::Mute { time, args } => {
+            log::info!("Received mute command: {:?}", time);
+            mute_user(
+                bot,
+                msg,
+                me,
+                pool,
+                config.audit_log(repositories.modlog, queue),
+                time,
+                args,
+            )
+            .await?
+
+            // This code handles the Mute command:
+            // 1. It logs that a mute command was received, including the parsed duration.
+            // 2. It calls the mute_user function with the bot, message, duration, target and reason.
+            // 3. The result of mute_user is propagated up with the ? operator.
+
+            // Test case: Send "/mute 5m" command as a reply to another user's message
+            // Expected behavior:
+            // 1. The bot should log "Received mute command: For(TimeDelta { secs: 300, nanos: 0 })"
+            // 2. The mute_user function should be called with correct arguments
+            // 3. The confirmation should say the user was muted "for 5m"
+            // 4. If mute_user succeeds, the command handler should return Ok(())
+            // 5. If mute_user fails, the error should be propagated up
+
+            // Additional test cases:
+            // - Send "/mute 1h" to mute for 1 hour
+            // - Send "/mute 2w" to mute for two weeks
+            // - Send "/mute permanent" to mute until /unmute
+            // - Send "/mute 10m 123456789 flooding" to mute by user ID, with a reason
+            // - Send "/mute" without a duration (should ask for one)
+            // - Send "/mute" as a non-admin user (should be rejected)
+            // - Send "/mute" targeting an admin user (should be rejected)
+            // - Send "/mute" in a private chat (should be refused)
+            // - Send "/mute" while the bot isn't an admin (should explain the missing right)
+            // - Verify that the muted user cannot send messages for the specified duration
+            // - Verify that the mute is automatically lifted after the specified duration
+        }
+        Command::Unban(args) => {
+            log::info!("Received unban command");
+            unban_user(
+                bot,
+                msg,
+                me,
+                pool,
+                config.audit_log(repositories.modlog, queue),
+                args,
+            )
+            .await?
+
+            // Test case: Send "/unban @username" or "/unban <user_id>" for a banned user
+            // Expected behavior:
+            // 1. The ban is lifted and the user can rejoin through an invite link
+            // 2. Unbanning a user who isn't banned does nothing to them
+        }
+        Command::Unmute(args) => {
+            log::info!("Received unmute command");
+            unmute_user(
+                bot,
+                msg,
+                me,
+                pool,
+                config.audit_log(repositories.modlog, queue),
+                args,
+            )
+            .await?
+
+            // Test case: Send "/unmute" as a reply to a message of a muted user
+            // Expected behavior:
+            // 1. The user gets the chat's default permissions back and can send messages again
+        }
+        Command::Warn(args) => {
+            log::info!("Received warn command");
+            warn_user(
+                bot,
+                msg,
+                me,
+                pool,
+                config.audit_log(repositories.modlog, queue),
+                config.warn_policy(),
+                args,
+            )
+            .await?
+
+            // Test case: Send "/warn spamming" as a reply to a message of a member
+            // Expected behavior:
+            // 1. The warning is recorded in the warnings table with the reason
+            // 2. The bot replies "⚠️ User <name> has been warned (1 of 5)." followed by the reason
+
+            // Additional test cases:
+            // - Warn the same user a third time (with the default WARN_MUTE_THRESHOLD of 3,
+            //   they should be muted for WARN_MUTE_HOURS)
+            // - Warn the same user a fifth time (with the default WARN_BAN_THRESHOLD of 5,
+            //   they should be banned)
+            // - Warn the same user in another group (counts are kept per chat)
+            // - Send "/warn @username" or "/warn 123456789" to warn without replying
+            // - Send "/warn" targeting an admin user (should be refused)
+        }
+        Command::Warnings(args) => {
+            log::info!("Received warnings command");
+            list_user_warnings(bot, msg, pool, config.timezone(), args).await?
+
+            // Test case: Send "/warnings @username" in a group
+            // Expected behavior:
+            // 1. The bot lists the user's warnings in this chat with their dates and reasons,
+            //    or says they have none
+
+            // Additional test cases:
+            // - Send "/warnings" in a private chat (should be refused)
+        }
+        Command::ClearWarns(args) => {
+            log::info!("Received clearwarns command");
+            clear_user_warnings(
+                bot,
+                msg,
+                me,
+                pool,
+                config.audit_log(repositories.modlog, queue),
+                args,
+            )
+            .await?
+
+            // Test case: Send "/clearwarns" as a reply to a message of a warned user
+            // Expected behavior:
+            // 1. All of the user's warnings in this chat are deleted
+            // 2. The bot replies with the number of warnings cleared
+            // 3. The next "/warn" starts counting from 1 again
+        }
+    };
+
+    Ok(())
+}
+
+/// Handles staff commands and responds accordingly.
+///
+/// # Arguments
+///
+/// * `bot` - The Telegram Bot instance used to send messages.
+/// * `msg` - The received message containing the command.
+/// * `cmd` - The parsed staff command.
+/// * `pool` - The database connection pool.
+/// * `repositories` - The repositories of the bot.
+/// * `config` - The bot configuration.
+/// * `queue` - The outbound message queue.
+/// * `jobs` - The registry of scheduled jobs.
+/// * `role` - The role of the caller, already checked against the command.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the command handling.
+#[allow(clippy::too_many_arguments)]
+async fn answer_staff(
+    bot: Bot,
+    msg: Message,
+    cmd: StaffCommand,
+    pool: PgPool,
+    repositories: Repositories,
+    config: Arc<Config>,
+    queue: MessageQueue,
+    jobs: JobRegistry,
+    role: Role,
+) -> Result<(), Error> {
+    match cmd {
+        StaffCommand::Jobs => {
+            log::info!("Received jobs command");
+            list_jobs(bot, msg, jobs).await?
+
+            // Test case: Send "/jobs" in the pharmacy group chat
+            // Expected behavior:
+            // 1. Every registered job is listed with its schedule, last run and next run
+            // 2. Sending "/jobs" as anyone below manager is refused
+        }
+        StaffCommand::RunJob(name) => {
+            log::info!("Received runjob command: {}", name);
+            run_job(bot, msg, jobs, name).await?
+
+            // Test case: Send "/runjob low_stock_check" in the pharmacy group chat
+            // Expected behavior:
+            // 1. The job runs immediately and its result is sent back
+            // 2. The run is recorded with the "manual" trigger in job_runs
+            // 3. An unknown job name lists the available jobs instead
+        }
+        StaffCommand::Tickets => {
+            log::info!("Received tickets command");
+            list_tickets(bot, msg, pool, config).await?
+
+            // Test case: Send "/tickets" in the pharmacy group chat and in a pharmacist's chat
+            // Expected behavior:
+            // 1. In the group chat every ticket that isn't closed is listed
+            // 2. In a private chat only the tickets assigned to that pharmacist are listed
+            // 3. Tickets past the SLA are flagged
+        }
+        StaffCommand::Close(id) => {
+            log::info!("Received close command for ticket {}", id);
+            let result =
+                close_ticket(bot.clone(), msg.clone(), pool, config, queue, role, id).await;
+            error::reply_on_error(&bot, &msg, result).await?
+
+            // Test case: Send "/close <id>" as the assigned pharmacist
+            // Expected behavior:
+            // 1. The ticket is closed and the patient is told the consultation ended
+            // 2. A pharmacist closing someone else's ticket from a private chat is refused
+        }
+        StaffCommand::Rota => {
+            log::info!("Received rota command");
+            list_rota(bot, msg, pool, config).await?
+
+            // Test case: Send "/rota" in the pharmacy group chat
+            // Expected behavior:
+            // 1. Every shift is listed by weekday with its pharmacist and hours
+            // 2. The pharmacist currently on duty is shown
+        }
+        StaffCommand::AddShift(weekday, starts_at, ends_at) => {
+            log::info!("Received addshift command");
+            add_shift(bot, msg, pool, weekday, starts_at, ends_at).await?
+
+            // Test case: Reply "/addshift mon 09:00 17:00" to a pharmacist's message in the group
+            // Expected behavior:
+            // 1. The shift is added to the rota for that pharmacist
+            // 2. Invalid days, times or a shift ending before it starts are explained
+        }
+        StaffCommand::RemoveShift(id) => {
+            log::info!("Received removeshift command for shift {}", id);
+            let result = remove_shift(bot.clone(), msg.clone(), pool, id).await;
+            error::reply_on_error(&bot, &msg, result).await?
+
+            // Test case: Send "/removeshift <id>" in the pharmacy group chat
+            // Expected behavior:
+            // 1. The shift is removed and no longer listed by /rota
+        }
+        StaffCommand::Staff => {
+            log::info!("Received staff command");
+            list_staff(bot, msg, pool).await?
+
+            // Test case: Send "/staff" as a manager
+            // Expected behavior:
+            // 1. Every admin, manager and pharmacist is listed with their role
+        }
+        StaffCommand::SetRole { user_id, role } => {
+            log::info!("Received setrole command: {} -> {}", user_id, role);
+            set_user_role(bot, msg, pool, UserId(user_id), role).await?
+
+            // Test case: Send "/setrole 123456 pharmacist" as an admin
+            // Expected behavior:
+            // 1. The user can use pharmacist commands from now on
+            // 2. "/setrole 123456 customer" takes the role away again
+            // 3. Admins can't change their own role
+        }
+        StaffCommand::ModLog(args) => {
+            log::info!("Received modlog command");
+            show_modlog(bot, msg, pool, repositories.modlog, config.timezone(), args).await?
+
+            // Test case: Send "/modlog @username" after banning and unbanning that user
+            // Expected behavior:
+            // 1. The bot lists the ban and the unban, newest first, with their times,
+            //    moderators, chats, durations and reasons
+
+            // Additional test cases:
+            // - Send "/modlog 123456789" in a private chat (works without the user being a member)
+            // - Send "/modlog" for a user without any actions (should say there are none)
+        }
+    };
+
+    Ok(())
+}
+
+/// Handles incoming messages from users.
+///
+/// This function processes text messages and executes corresponding actions based on the content.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to send responses.
+/// * `msg` - The incoming Message to be processed.
+/// * `repositories` - The repositories of the bot.
+/// * `me` - Information about the bot itself, used to parse commands addressed to it.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+///
+/// # Function flow
+///
+/// 1. Check if the message contains text.
+/// 2. If text is present, match it against known commands:
+///    - "📋 Check Inventory": List the current inventory.
+///    - "🛒 Place Order": Initiate the order placement process.
+///    - "❓ Help": Display help information.
+///    - A command with malformed arguments, e.g. "/ban 3x": Explain what is wrong.
+///    - Any other text in a private chat: Send a message indicating an unknown
+///      command. In groups it is left alone.
+/// 3. If no text is present, do nothing (implicit in the if let structure).
+///
+/// # Error handling
+///
+/// - Any errors during the process are propagated up the call stack.
+async fn handle_message(
+    bot: Bot,
+    msg: Message,
+    repositories: Repositories,
+    config: Arc<Config>,
+    queue: MessageQueue,
+    me: Me,
+) -> Result<(), Error> {
+    if let Some(text) = msg.text() {
+        match text {
+            keyboards::CHECK_INVENTORY => {
+                let result = list_inventory(bot.clone(), msg.clone(), repositories).await;
+                error::reply_on_error(&bot, &msg, result).await?;
+            }
+            keyboards::PLACE_ORDER => {
+                let result =
+                    place_order(bot.clone(), msg.clone(), repositories, config, queue).await;
+                error::reply_on_error(&bot, &msg, result).await?;
+            }
+            keyboards::HELP => {
+                bot.send_message(msg.chat.id, Command::descriptions().to_string())
+                    .await?;
+            }
+            _ => {
+                // Explain malformed arguments rather than pretending not to
+                // know the command. Other group messages are just conversation,
+                // which the bot only reads for the spam filter.
+                let reply = match usage_error(text, me.username()) {
+                    Some(usage) => usage,
+                    None if msg.chat.is_private() => "I don't understand that command. Please use the menu or type /help for available commands.".to_string(),
+                    None => return Ok(()),
+                };
+                bot.send_message(msg.chat.id, reply).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Explains why a known command couldn't be parsed, e.g. a ban with an
+/// unknown unit of time.
+///
+/// # Returns
+///
+/// Returns `None` if `text` isn't one of the user commands, or parses fine.
+fn usage_error(text: &str, bot_name: &str) -> Option<String> {
+    match Command::parse(text, bot_name) {
+        Err(ParseError::IncorrectFormat(e)) | Err(ParseError::Custom(e)) => Some(e.to_string()),
+        Err(ParseError::TooFewArguments { message, .. })
+        | Err(ParseError::TooManyArguments { message, .. }) => Some(message),
+        _ => None,
+    }
+}
@@ -0,0 +1,193 @@
+use std::sync::Arc;
+use teloxide::prelude::*;
+
+use crate::{
+    config::Config,
+    error::AppError,
+    metrics,
+    queue::{MessageQueue, OutboundMessage},
+    repositories::Repositories,
+    rota, services,
+    topics::StaffTopic,
+};
+
+/// Lists the inventory of medicines to the user.
+///
+/// This function retrieves all medicines from the database and sends a formatted
+/// message to the user with the inventory details.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to send messages.
+/// * `msg` - The original message that triggered this function.
+/// * `repositories` - The repositories, used to look up the medicines.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if the operation succeeds, or an [`AppError`] to reply
+/// with if something goes wrong.
+///
+/// # Function flow
+///
+/// 1. Log the inventory listing action.
+/// 2. Fetch all medicines from the medicine repository.
+/// 3. If no medicines are found, inform the user and return.
+/// 4. If medicines are found, format each medicine's details.
+/// 5. Combine all formatted medicine details into a single message.
+/// 6. Send the formatted message to the user.
+///
+/// # Error handling
+///
+/// - Database and message sending errors are returned, so the user is told
+///   something went wrong rather than that the inventory is empty.
+///
+/// # Formatting
+///
+/// The function formats each medicine with:
+/// - An emoji (🏥)
+/// - The medicine name in bold
+/// - The current stock
+/// - The expiry date (formatted as "DD Mon YYYY")
+///
+/// Medicines are separated by two newlines for readability.
+pub async fn list_inventory(
+    bot: Bot,
+    msg: Message,
+    repositories: Repositories,
+) -> Result<(), AppError> {
+    log::info!("Listing inventory");
+    let medicines = repositories.medicines.all().await?;
+
+    if medicines.is_empty() {
+        bot.send_message(msg.chat.id, "No medicines found in the inventory")
+            .await?;
+        return Ok(());
+    }
+
+    let message = medicines
+        .iter()
+        .map(|medicine| {
+            format!(
+                "🏥 *{}*\n   Stock: {} units\n   Expires: {}",
+                medicine.name,
+                medicine.stock,
+                medicine.expiry_date.format("%d %b %Y")
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n\n");
+
+    let formatted_message = format!("*Available medicines:*\n\n{}", message);
+
+    bot.send_message(msg.chat.id, formatted_message).await?;
+
+    Ok(())
+}
+
+/// Handles the process of placing an order for medicine.
+///
+/// This function performs the following steps:
+/// 1. Extracts the user ID from the incoming message; messages without a
+///    sender, e.g. from anonymous group admins, can't place orders.
+/// 2. Sets up hardcoded values for medicine ID and quantity (for simplification).
+/// 3. Places the order with [`services::place_order`], which checks that the
+///    medicine exists and has sufficient stock.
+/// 4. If the medicine is available:
+///    a. Takes the ordered units out of stock.
+///    b. Creates a new pending order.
+///    c. Sends the order for approval to the pharmacist on duty, or to the staff
+///    group outside working hours.
+///    d. Sends a confirmation message to the user, saying when the pharmacy
+///    reopens if it is closed.
+/// 5. If the medicine is not available or there's insufficient stock, returns
+///    the matching [`AppError`].
+///
+/// # Arguments
+///
+/// * `bot` - The Telegram Bot instance used to send messages.
+/// * `msg` - The incoming message from the user.
+/// * `repositories` - The medicine, order and rota repositories.
+/// * `config` - The bot configuration, used to find the staff chat and time zone.
+/// * `queue` - The outbound message queue, used to send the order for approval.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if the order was placed, or an [`AppError`] to reply with
+/// if it wasn't.
+///
+/// # Error Handling
+///
+/// - Errors are returned rather than replied to here; the caller passes them to
+///   [`crate::error::reply_on_error`], which tells the user in their language.
+/// - If updating stock or creating an order fails, the operation is aborted.
+///
+/// # Notes
+///
+/// - This implementation uses hardcoded values for medicine ID and quantity.
+/// - In a real-world scenario, these would typically be provided by the user through interaction.
+/// - Stock is checked and taken in a single step, so concurrent orders can't oversell a medicine.
+pub async fn place_order(
+    bot: Bot,
+    msg: Message,
+    repositories: Repositories,
+    config: Arc<Config>,
+    queue: MessageQueue,
+) -> Result<(), AppError> {
+    let Some(user) = msg.from.as_ref().filter(|user| !user.is_anonymous()) else {
+        return Err(AppError::Validation(
+            "Orders can only be placed from a personal account.".to_string(),
+        ));
+    };
+    let user_id = user.id.to_string();
+
+    // Simplified: Assume we're always ordering medicine with ID 1
+    let medicine_id = 1;
+    let quantity = 2;
+
+    let (order, medicine) = services::place_order(
+        repositories.medicines.as_ref(),
+        repositories.orders.as_ref(),
+        user_id,
+        medicine_id,
+        quantity,
+    )
+    .await?;
+    metrics::global().order_placed();
+
+    // Send the order for approval to whoever is on duty
+    let now = chrono::Utc::now();
+    let staff_chat = ChatId(config.pharmacy_group_chat_id);
+    let duty = repositories
+        .rota
+        .on_duty(config.timezone(), now)
+        .await
+        .unwrap_or_else(|e| {
+            log::error!("Failed to look up the rota: {}", e);
+            rota::Duty::NoRota
+        });
+    let (approver, reply) = match duty {
+        rota::Duty::OnDuty(pharmacist) => (pharmacist, "Order placed successfully".to_string()),
+        rota::Duty::Closed { reopens_at } => (
+            staff_chat,
+            format!(
+                "Order placed successfully. We're closed right now, we'll process it {}.",
+                rota::describe_reopening(reopens_at, now)
+            ),
+        ),
+        rota::Duty::NoRota => (staff_chat, "Order placed successfully".to_string()),
+    };
+    let approval = OutboundMessage::new(
+        approver,
+        format!(
+            "🛒 New order #{} awaiting approval: {} × {}",
+            order.id, quantity, medicine.name
+        ),
+    )
+    .thread_id(config.staff_thread(approver, StaffTopic::Orders));
+    if let Err(e) = queue.push(approval).await {
+        log::error!("Failed to queue the order for approval: {}", e);
+    }
+
+    bot.send_message(msg.chat.id, reply).await?;
+    Ok(())
+}
@@ -0,0 +1,736 @@
+use chrono::{Duration, Utc};
+use chrono_tz::Tz;
+use sqlx::PgPool;
+use teloxide::{
+    prelude::*,
+    types::{ChatPermissions, Me},
+    RequestError,
+};
+
+use crate::{
+    commands::RestrictTime,
+    error,
+    moderation::{self, ModerationArgs, Refusal, Target, TargetSpec},
+    modlog::{self, AuditLog, ModAction, NewAction},
+    repositories::ModLog,
+    warnings::{self, Escalation, WarnPolicy},
+    Error,
+};
+
+/// Kicks a user from a chat.
+///
+/// This function handles the process of kicking a user in response to a command.
+/// It works out who to kick from the command arguments or the replied-to message,
+/// applies the kick, and sends appropriate feedback messages.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to interact with the Telegram API.
+/// * `msg` - The Message object that triggered this command.
+/// * `me` - Information about the bot itself, used to check its own rights.
+/// * `pool` - The database connection pool, used to look up usernames.
+/// * `audit` - The audit log the action is recorded in.
+/// * `args` - The target and reason given with the command.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+///
+/// # Function flow
+///
+/// 1. Resolve the target: `@username`, user ID, text mention or the author of
+///    the replied-to message.
+/// 2. If no target can be found, explain how to name one.
+/// 3. Check that the caller and the bot may restrict members and that the
+///    target isn't an administrator; refuse politely otherwise.
+/// 4. Ban the user and lift the ban right away.
+/// 5. Send a confirmation message, with the reason if one was given.
+///
+/// # Note
+///
+/// Telegram has no kick as such: `unban_chat_member` on its own leaves a member
+/// who is still in the chat untouched. Banning removes the user, and lifting
+/// the ban straight after lets them rejoin through an invite link.
+pub async fn kick_user(
+    bot: Bot,
+    msg: Message,
+    me: Me,
+    pool: PgPool,
+    audit: AuditLog,
+    args: ModerationArgs,
+) -> Result<(), Error> {
+    // Work out who to kick and make sure the caller, the target and the bot all
+    // have the right permissions before touching the chat
+    let Some((target, reason)) = resolve_sanction_target(&bot, &msg, &me, &pool, args).await?
+    else {
+        return Ok(());
+    };
+
+    // Remove the user with a ban, then lift it so they can rejoin
+    bot.ban_chat_member(msg.chat.id, target.id).await?;
+    bot.unban_chat_member(msg.chat.id, target.id)
+        .only_if_banned(true)
+        .await?;
+
+    log_action(
+        &audit,
+        &msg,
+        &target,
+        ModAction::Kick,
+        None,
+        reason.as_deref(),
+    )
+    .await;
+
+    // Send confirmation message
+    bot.send_message(
+        msg.chat.id,
+        moderation::with_reason(
+            format!("User {} has been kicked.", target.name),
+            reason.as_deref(),
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Bans a user from a chat for a specified duration.
+///
+/// This function handles the process of banning a user in response to a command.
+/// It works out who to ban from the command arguments or the replied-to message,
+/// applies the ban, and sends appropriate feedback messages.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to interact with the Telegram API.
+/// * `msg` - The Message object that triggered this command.
+/// * `me` - Information about the bot itself, used to check its own rights.
+/// * `pool` - The database connection pool, used to look up usernames.
+/// * `audit` - The audit log the action is recorded in.
+/// * `time` - How long the user should be banned, possibly permanently.
+/// * `args` - The target and reason given with the command.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+///
+/// # Errors
+///
+/// This function will return an error if:
+/// * Looking up the target fails.
+/// * The bot fails to ban the chat member.
+/// * The bot fails to send a message.
+///
+/// # Function flow
+///
+/// 1. Resolve the target: `@username`, user ID, text mention or the author of
+///    the replied-to message.
+/// 2. If no target can be found, explain how to name one.
+/// 3. Check that the caller and the bot may restrict members and that the
+///    target isn't an administrator; refuse politely otherwise.
+/// 4. Ban the user for the specified duration, or without an end date.
+/// 5. Send a confirmation message, with the reason if one was given.
+///
+/// # Note
+///
+/// This function uses `ban_chat_member` with an `until_date` parameter to implement a temporary ban.
+/// After the specified duration, the user will be able to join the chat again. A permanent ban
+/// leaves `until_date` out and lasts until `/unban`.
+pub async fn ban_user(
+    bot: Bot,
+    msg: Message,
+    me: Me,
+    pool: PgPool,
+    audit: AuditLog,
+    time: RestrictTime,
+    args: ModerationArgs,
+) -> Result<(), Error> {
+    // 1-3. Work out who to ban and check everyone's rights, explaining to the
+    // caller if the ban can't go ahead
+    let Some((target, reason)) = resolve_sanction_target(&bot, &msg, &me, &pool, args).await?
+    else {
+        return Ok(());
+    };
+
+    // 4. Ban the user; 'until_date' sets the duration of the ban
+    apply_ban(&bot, &msg, target.id, time).await?;
+
+    log_action(
+        &audit,
+        &msg,
+        &target,
+        ModAction::Ban,
+        time.duration(),
+        reason.as_deref(),
+    )
+    .await;
+
+    // 5. If the ban is successful, send a confirmation message
+    bot.send_message(
+        msg.chat.id,
+        moderation::with_reason(
+            format!("User {} has been banned {}.", target.name, time),
+            reason.as_deref(),
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Mutes a user in a chat for a specified duration.
+///
+/// This function handles the process of muting a user in response to a command.
+/// It works out who to mute from the command arguments or the replied-to message,
+/// checks that the caller and the bot are allowed to restrict them,
+/// applies the mute restriction, and sends appropriate feedback messages.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to interact with the Telegram API.
+/// * `msg` - The Message object that triggered this command.
+/// * `me` - Information about the bot itself, used to check its own rights.
+/// * `pool` - The database connection pool, used to look up usernames.
+/// * `audit` - The audit log the action is recorded in.
+/// * `time` - How long the user should be muted, possibly permanently.
+/// * `args` - The target and reason given with the command.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+///
+/// # Errors
+///
+/// This function will return an error if:
+/// * Looking up the target fails.
+/// * The bot fails to restrict the chat member.
+/// * The bot fails to send a message.
+///
+pub async fn mute_user(
+    bot: Bot,
+    msg: Message,
+    me: Me,
+    pool: PgPool,
+    audit: AuditLog,
+    time: RestrictTime,
+    args: ModerationArgs,
+) -> Result<(), Error> {
+    // Work out who to mute and make sure the caller, the target and the bot all
+    // have the right permissions before touching the chat
+    let Some((target, reason)) = resolve_sanction_target(&bot, &msg, &me, &pool, args).await?
+    else {
+        return Ok(());
+    };
+
+    // Restrict the user's chat permissions
+    apply_mute(&bot, &msg, target.id, time).await?;
+
+    log_action(
+        &audit,
+        &msg,
+        &target,
+        ModAction::Mute,
+        time.duration(),
+        reason.as_deref(),
+    )
+    .await;
+
+    // Send a confirmation message
+    bot.send_message(
+        msg.chat.id,
+        moderation::with_reason(
+            format!("User {} has been muted {}.", target.name, time),
+            reason.as_deref(),
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Lifts the ban of a user so they can rejoin the chat.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to interact with the Telegram API.
+/// * `msg` - The Message object that triggered this command.
+/// * `me` - Information about the bot itself, used to check its own rights.
+/// * `pool` - The database connection pool, used to look up usernames.
+/// * `audit` - The audit log the action is recorded in.
+/// * `args` - The target and reason given with the command.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+///
+/// # Note
+///
+/// `only_if_banned` keeps Telegram from removing the user when they are
+/// actually still in the chat.
+pub async fn unban_user(
+    bot: Bot,
+    msg: Message,
+    me: Me,
+    pool: PgPool,
+    audit: AuditLog,
+    args: ModerationArgs,
+) -> Result<(), Error> {
+    let Some((target, reason)) = resolve_sanction_target(&bot, &msg, &me, &pool, args).await?
+    else {
+        return Ok(());
+    };
+
+    bot.unban_chat_member(msg.chat.id, target.id)
+        .only_if_banned(true)
+        .await?;
+
+    log_action(
+        &audit,
+        &msg,
+        &target,
+        ModAction::Unban,
+        None,
+        reason.as_deref(),
+    )
+    .await;
+    bot.send_message(
+        msg.chat.id,
+        moderation::with_reason(
+            format!("User {} has been unbanned.", target.name),
+            reason.as_deref(),
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Lets a muted user talk again.
+///
+/// The user gets the chat's default permissions back, rather than every
+/// permission, so unmuting never grants more than other members have.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to interact with the Telegram API.
+/// * `msg` - The Message object that triggered this command.
+/// * `me` - Information about the bot itself, used to check its own rights.
+/// * `pool` - The database connection pool, used to look up usernames.
+/// * `audit` - The audit log the action is recorded in.
+/// * `args` - The target and reason given with the command.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+pub async fn unmute_user(
+    bot: Bot,
+    msg: Message,
+    me: Me,
+    pool: PgPool,
+    audit: AuditLog,
+    args: ModerationArgs,
+) -> Result<(), Error> {
+    let Some((target, reason)) = resolve_sanction_target(&bot, &msg, &me, &pool, args).await?
+    else {
+        return Ok(());
+    };
+
+    // The default member permissions of the chat; Telegram only omits them for
+    // chats that don't have any, where everything is allowed
+    let permissions = bot
+        .get_chat(msg.chat.id)
+        .await?
+        .permissions()
+        .unwrap_or_else(ChatPermissions::all);
+    bot.restrict_chat_member(msg.chat.id, target.id, permissions)
+        .await?;
+
+    log_action(
+        &audit,
+        &msg,
+        &target,
+        ModAction::Unmute,
+        None,
+        reason.as_deref(),
+    )
+    .await;
+    bot.send_message(
+        msg.chat.id,
+        moderation::with_reason(
+            format!("User {} can talk again.", target.name),
+            reason.as_deref(),
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Bans a user from the chat a command was sent in, starting now.
+///
+/// The restriction is timed from when it is applied rather than from when
+/// the message was sent, so updates handled late don't end up permanent.
+///
+/// Callers are expected to have checked the rights involved first, see
+/// `moderation::check_rights`.
+pub async fn apply_ban(
+    bot: &Bot,
+    msg: &Message,
+    user: UserId,
+    time: RestrictTime,
+) -> Result<(), RequestError> {
+    let mut ban = bot.ban_chat_member(msg.chat.id, user);
+    if let Some(until) = time.until(Utc::now()) {
+        ban = ban.until_date(until);
+    }
+    ban.await?;
+    Ok(())
+}
+
+/// Mutes a user in the chat a command was sent in, starting now.
+///
+/// The restriction is timed from when it is applied rather than from when
+/// the message was sent, so updates handled late don't end up permanent.
+///
+/// Callers are expected to have checked the rights involved first, see
+/// `moderation::check_rights`.
+pub async fn apply_mute(
+    bot: &Bot,
+    msg: &Message,
+    user: UserId,
+    time: RestrictTime,
+) -> Result<(), RequestError> {
+    let mut mute = bot.restrict_chat_member(msg.chat.id, user, ChatPermissions::empty());
+    if let Some(until) = time.until(Utc::now()) {
+        mute = mute.until_date(until);
+    }
+    mute.await?;
+    Ok(())
+}
+
+/// Warns a user, muting or banning them once they have enough warnings.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to interact with the Telegram API.
+/// * `msg` - The Message object that triggered this command.
+/// * `me` - Information about the bot itself, used to check its own rights.
+/// * `pool` - The database connection pool.
+/// * `audit` - The audit log the action is recorded in.
+/// * `policy` - The thresholds at which warnings escalate.
+/// * `args` - The target and reason given with the command.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+///
+/// # Function flow
+///
+/// 1. Resolve the target and check the rights involved, as for `/mute`, since
+///    the warning may end in a mute or a ban.
+/// 2. Record the warning and count the user's warnings in this chat.
+/// 3. Mute or ban the user if a threshold has been reached.
+/// 4. Announce the warning, the count and any escalation.
+pub async fn warn_user(
+    bot: Bot,
+    msg: Message,
+    me: Me,
+    pool: PgPool,
+    audit: AuditLog,
+    policy: WarnPolicy,
+    args: ModerationArgs,
+) -> Result<(), Error> {
+    let Some((target, reason)) = resolve_sanction_target(&bot, &msg, &me, &pool, args).await?
+    else {
+        return Ok(());
+    };
+
+    let issued_by = msg.from.as_ref().map(|user| user.id);
+    let count =
+        warnings::add_warning(&pool, msg.chat.id, target.id, reason.as_deref(), issued_by).await?;
+    log_action(
+        &audit,
+        &msg,
+        &target,
+        ModAction::Warn,
+        None,
+        reason.as_deref(),
+    )
+    .await;
+
+    let mut announcement = match policy.ban_after {
+        Some(limit) => format!(
+            "⚠️ User {} has been warned ({} of {}).",
+            target.name, count, limit
+        ),
+        None => format!(
+            "⚠️ User {} has been warned ({} so far).",
+            target.name, count
+        ),
+    };
+    let escalation_reason = format!("Reached {} warnings", count);
+    match policy.escalation(count) {
+        Escalation::None => {}
+        Escalation::Mute(duration) => {
+            let time = RestrictTime::For(duration);
+            apply_mute(&bot, &msg, target.id, time).await?;
+            log_action(
+                &audit,
+                &msg,
+                &target,
+                ModAction::Mute,
+                time.duration(),
+                Some(&escalation_reason),
+            )
+            .await;
+            announcement.push_str(&format!(" They have been muted {}.", time));
+        }
+        Escalation::Ban => {
+            apply_ban(&bot, &msg, target.id, RestrictTime::Permanent).await?;
+            log_action(
+                &audit,
+                &msg,
+                &target,
+                ModAction::Ban,
+                None,
+                Some(&escalation_reason),
+            )
+            .await;
+            announcement.push_str(" They have been banned.");
+        }
+    }
+
+    bot.send_message(
+        msg.chat.id,
+        moderation::with_reason(announcement, reason.as_deref()),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Lists the warnings a user has in the chat the command was sent in.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to interact with the Telegram API.
+/// * `msg` - The Message object that triggered this command.
+/// * `pool` - The database connection pool.
+/// * `timezone` - The pharmacy's time zone, used to show when warnings were given.
+/// * `args` - The target given with the command; a reason is ignored.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+pub async fn list_user_warnings(
+    bot: Bot,
+    msg: Message,
+    pool: PgPool,
+    timezone: Tz,
+    args: ModerationArgs,
+) -> Result<(), Error> {
+    if msg.chat.is_private() {
+        bot.send_message(msg.chat.id, Refusal::PrivateChat.to_string())
+            .await?;
+        return Ok(());
+    }
+
+    let (target, _) = match moderation::resolve_target(&bot, &pool, &msg, args).await {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            error::reply_on_error(&bot, &msg, Err(e)).await?;
+            return Ok(());
+        }
+    };
+
+    let warnings = warnings::list_warnings(&pool, msg.chat.id, target.id).await?;
+    let text = if warnings.is_empty() {
+        format!("User {} has no warnings in this chat.", target.name)
+    } else {
+        let lines = warnings
+            .iter()
+            .enumerate()
+            .map(|(i, warning)| {
+                format!(
+                    "{}. {} - {}",
+                    i + 1,
+                    warning
+                        .created_at
+                        .with_timezone(&timezone)
+                        .format("%Y-%m-%d %H:%M"),
+                    warning.reason.as_deref().unwrap_or("no reason given")
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        format!(
+            "User {} has {} warning(s) in this chat:\n{}",
+            target.name,
+            warnings.len(),
+            lines
+        )
+    };
+    bot.send_message(msg.chat.id, text).await?;
+    Ok(())
+}
+
+/// Removes all of a user's warnings in the chat the command was sent in.
+///
+/// Only chat administrators who could mute or ban the user may do this, so
+/// the same rights are checked as for `/warn`.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to interact with the Telegram API.
+/// * `msg` - The Message object that triggered this command.
+/// * `me` - Information about the bot itself, used to check its own rights.
+/// * `pool` - The database connection pool.
+/// * `audit` - The audit log the action is recorded in.
+/// * `args` - The target and reason given with the command.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+pub async fn clear_user_warnings(
+    bot: Bot,
+    msg: Message,
+    me: Me,
+    pool: PgPool,
+    audit: AuditLog,
+    args: ModerationArgs,
+) -> Result<(), Error> {
+    let Some((target, reason)) = resolve_sanction_target(&bot, &msg, &me, &pool, args).await?
+    else {
+        return Ok(());
+    };
+
+    let cleared = warnings::clear_warnings(&pool, msg.chat.id, target.id).await?;
+
+    log_action(
+        &audit,
+        &msg,
+        &target,
+        ModAction::ClearWarns,
+        None,
+        reason.as_deref(),
+    )
+    .await;
+    let announcement = if cleared == 0 {
+        format!("User {} had no warnings in this chat.", target.name)
+    } else {
+        format!("Cleared {} warning(s) of user {}.", cleared, target.name)
+    };
+    bot.send_message(
+        msg.chat.id,
+        moderation::with_reason(announcement, reason.as_deref()),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Works out who a sanction acts on and checks that it may go ahead.
+///
+/// When no target can be found, or the caller, the target or the bot lack the
+/// rights involved, the caller is told why and `None` is returned.
+async fn resolve_sanction_target(
+    bot: &Bot,
+    msg: &Message,
+    me: &Me,
+    pool: &PgPool,
+    args: ModerationArgs,
+) -> Result<Option<(Target, Option<String>)>, Error> {
+    let (target, reason) = match moderation::resolve_target(bot, pool, msg, args).await {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            error::reply_on_error(bot, msg, Err(e)).await?;
+            return Ok(None);
+        }
+    };
+
+    if let Err(refusal) = moderation::check_rights(bot, msg, target.id, me.id).await? {
+        bot.send_message(msg.chat.id, refusal.to_string()).await?;
+        return Ok(None);
+    }
+
+    Ok(Some((target, reason)))
+}
+
+/// Records a moderation action taken in response to `msg` in the audit log.
+///
+/// The caller of the command is recorded as the moderator who took it. The
+/// action has already been applied by then, so a failure to record it is
+/// logged rather than returned, and the command is still confirmed.
+async fn log_action(
+    audit: &AuditLog,
+    msg: &Message,
+    target: &Target,
+    action: ModAction,
+    duration: Option<Duration>,
+    reason: Option<&str>,
+) {
+    let recorded = audit
+        .record(NewAction {
+            chat: &msg.chat,
+            actor: msg.from.as_ref(),
+            target: target.id,
+            target_name: &target.name,
+            action,
+            duration,
+            reason,
+        })
+        .await;
+    if let Err(e) = recorded {
+        log::error!(
+            "Failed to record {:?} of user {} in chat {}: {}",
+            action,
+            target.id,
+            msg.chat.id,
+            e
+        );
+    }
+}
+
+/// Shows the recent moderation actions against a user, across all chats.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to interact with the Telegram API.
+/// * `msg` - The Message object that triggered this command.
+/// * `pool` - The database connection pool, used to look up usernames.
+/// * `modlog` - The moderation log the actions are read from.
+/// * `timezone` - The pharmacy's time zone, used to show when actions were taken.
+/// * `args` - The target given with the command; a reason is ignored.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+///
+/// # Note
+///
+/// A user ID is taken as is rather than looked up in the current chat, so
+/// `/modlog` also works in private chats and for users who have left.
+pub async fn show_modlog(
+    bot: Bot,
+    msg: Message,
+    pool: PgPool,
+    modlog: ModLog,
+    timezone: Tz,
+    args: ModerationArgs,
+) -> Result<(), Error> {
+    let target = match args.target {
+        Some(TargetSpec::Id(id)) => Target {
+            id,
+            name: id.to_string(),
+        },
+        _ => match moderation::resolve_target(&bot, &pool, &msg, args).await {
+            Ok((target, _)) => target,
+            Err(e) => {
+                error::reply_on_error(&bot, &msg, Err(e)).await?;
+                return Ok(());
+            }
+        },
+    };
+
+    let records = modlog.recent_actions(target.id, 20).await?;
+    bot.send_message(
+        msg.chat.id,
+        modlog::format_history(&target.name, &records, timezone),
+    )
+    .await?;
+    Ok(())
+}
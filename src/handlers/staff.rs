@@ -0,0 +1,461 @@
+use chrono::Duration;
+use sqlx::PgPool;
+use std::sync::Arc;
+use teloxide::prelude::*;
+
+use crate::{
+    config::Config,
+    error::{AppError, Entity},
+    jobs::{JobKind, JobRegistry, Trigger},
+    queue::MessageQueue,
+    relay,
+    roles::{self, Role},
+    rota, tickets, Error,
+};
+
+/// Lists consultation tickets that aren't closed.
+///
+/// In the pharmacy staff group every ticket is listed; in a private chat only
+/// the tickets assigned to the caller are.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to send messages.
+/// * `msg` - The message that triggered this command.
+/// * `pool` - The database connection pool.
+/// * `config` - The bot configuration, used to identify the staff chat and the SLA.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+pub async fn list_tickets(
+    bot: Bot,
+    msg: Message,
+    pool: PgPool,
+    config: Arc<Config>,
+) -> Result<(), Error> {
+    let assignee = if msg.chat.id == ChatId(config.pharmacy_group_chat_id) {
+        None
+    } else {
+        Some(msg.chat.id)
+    };
+
+    let active = tickets::list_active(&pool, assignee).await?;
+    if active.is_empty() {
+        bot.send_message(msg.chat.id, "No open consultations.")
+            .await?;
+        return Ok(());
+    }
+
+    let now = chrono::Utc::now();
+    let sla = Duration::minutes(config.ticket_sla_minutes);
+    let entries = active
+        .iter()
+        .map(|ticket| {
+            let flag = if ticket.is_overdue(sla, now) {
+                "🚨"
+            } else {
+                "🎫"
+            };
+            format!(
+                "{} #{} — {}\n   Opened {} ago, waiting {}",
+                flag,
+                ticket.id,
+                ticket.status,
+                tickets::format_age(now - ticket.created_at),
+                tickets::format_age(now - ticket.waiting_since)
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n\n");
+
+    bot.send_message(
+        msg.chat.id,
+        format!(
+            "Open consultations:\n\n{}\n\nUse /close <id> to close one.",
+            entries
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Closes a consultation ticket and lets the patient know.
+///
+/// The assigned pharmacist can close their own tickets; managers can close
+/// any ticket, and so can anyone in the pharmacy staff group.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to send messages.
+/// * `msg` - The message that triggered this command.
+/// * `pool` - The database connection pool.
+/// * `config` - The bot configuration, used to identify the staff chat.
+/// * `queue` - The outbound message queue, used to notify the patient.
+/// * `role` - The role of the caller.
+/// * `id` - The ID of the ticket to close.
+///
+/// # Returns
+///
+/// Returns `Ok(())` once the ticket is closed or the refusal explained, or an
+/// [`AppError`] to reply with, e.g. if there is no such ticket.
+pub async fn close_ticket(
+    bot: Bot,
+    msg: Message,
+    pool: PgPool,
+    config: Arc<Config>,
+    queue: MessageQueue,
+    role: Role,
+    id: i64,
+) -> Result<(), AppError> {
+    let ticket = tickets::find(&pool, id)
+        .await?
+        .ok_or(AppError::NotFound(Entity::Ticket))?;
+
+    let is_staff_chat = msg.chat.id == ChatId(config.pharmacy_group_chat_id);
+    if role < Role::Manager && !is_staff_chat && ticket.assignee() != Some(msg.chat.id) {
+        bot.send_message(msg.chat.id, "You can only close tickets assigned to you.")
+            .await?;
+        return Ok(());
+    }
+
+    let Some(ticket) = tickets::close(&pool, id).await? else {
+        bot.send_message(msg.chat.id, "This ticket is already closed.")
+            .await?;
+        return Ok(());
+    };
+
+    if let Some(conversation) = relay::find(&pool, ticket.conversation_id).await? {
+        queue
+            .send(
+                conversation.sender(),
+                format!(
+                    "Your consultation #{} has been closed. Reply to any of the pharmacist's messages to reopen it.",
+                    ticket.id
+                ),
+            )
+            .await?;
+    }
+
+    bot.send_message(msg.chat.id, format!("Ticket #{} closed.", ticket.id))
+        .await?;
+    Ok(())
+}
+
+/// Shows the pharmacist rota and who is on duty right now.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to send messages.
+/// * `msg` - The message that triggered this command.
+/// * `pool` - The database connection pool.
+/// * `config` - The bot configuration, used for the time zone.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+pub async fn list_rota(
+    bot: Bot,
+    msg: Message,
+    pool: PgPool,
+    config: Arc<Config>,
+) -> Result<(), Error> {
+    let shifts = rota::list_shifts(&pool).await?;
+    if shifts.is_empty() {
+        bot.send_message(
+            msg.chat.id,
+            "The rota is empty, so everything goes to this group. Reply /addshift <weekday> <HH:MM> <HH:MM> to a pharmacist's message to add a shift.",
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let now = chrono::Utc::now();
+    let status = match rota::on_duty(&pool, config.timezone(), now).await? {
+        rota::Duty::OnDuty(pharmacist) => {
+            let name = shifts
+                .iter()
+                .find(|shift| shift.pharmacist() == pharmacist)
+                .map(|shift| shift.pharmacist_name.as_str())
+                .unwrap_or("unknown");
+            format!("On duty now: {}", name)
+        }
+        rota::Duty::Closed { reopens_at } => format!(
+            "Closed now, reopening {}",
+            rota::describe_reopening(reopens_at, now)
+        ),
+        rota::Duty::NoRota => "Nobody is on the rota".to_string(),
+    };
+
+    let entries = shifts
+        .iter()
+        .map(|shift| {
+            format!(
+                "#{} {} {}–{} {}",
+                shift.id,
+                shift.weekday(),
+                shift.starts_at.format("%H:%M"),
+                shift.ends_at.format("%H:%M"),
+                shift.pharmacist_name
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    bot.send_message(
+        msg.chat.id,
+        format!(
+            "🗓 Pharmacist rota ({})\n\n{}\n\n{}",
+            config.timezone(),
+            entries,
+            status
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Adds a shift to the pharmacist rota.
+///
+/// The shift is added for
+/// the author of the replied-to message, or for the sender if the command
+/// isn't a reply.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to send messages.
+/// * `msg` - The message that triggered this command.
+/// * `pool` - The database connection pool.
+/// * `weekday` - The day of the week of the shift.
+/// * `starts_at` - The local start time, as `HH:MM`.
+/// * `ends_at` - The local end time, as `HH:MM`.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+pub async fn add_shift(
+    bot: Bot,
+    msg: Message,
+    pool: PgPool,
+    weekday: String,
+    starts_at: String,
+    ends_at: String,
+) -> Result<(), Error> {
+    let (weekday, starts_at, ends_at) = match rota::parse_shift(&weekday, &starts_at, &ends_at) {
+        Ok(shift) => shift,
+        Err(reason) => {
+            bot.send_message(msg.chat.id, reason).await?;
+            return Ok(());
+        }
+    };
+
+    // The pharmacist's private chat has the same ID as their user
+    let Some(pharmacist) = msg
+        .reply_to_message()
+        .and_then(|replied| replied.from.as_ref())
+        .or(msg.from.as_ref())
+    else {
+        bot.send_message(
+            msg.chat.id,
+            "Reply to the pharmacist's message to add their shift.",
+        )
+        .await?;
+        return Ok(());
+    };
+
+    let shift = rota::add_shift(
+        &pool,
+        ChatId::from(pharmacist.id),
+        &pharmacist.full_name(),
+        weekday,
+        starts_at,
+        ends_at,
+    )
+    .await?;
+
+    bot.send_message(
+        msg.chat.id,
+        format!(
+            "Added shift #{} for {}: {} {}–{}",
+            shift.id,
+            shift.pharmacist_name,
+            shift.weekday(),
+            shift.starts_at.format("%H:%M"),
+            shift.ends_at.format("%H:%M")
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Removes a shift from the pharmacist rota.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to send messages.
+/// * `msg` - The message that triggered this command.
+/// * `pool` - The database connection pool.
+/// * `id` - The ID of the shift to remove.
+///
+/// # Returns
+///
+/// Returns `Ok(())` once the shift is removed, or an [`AppError`] to reply
+/// with if there is no such shift.
+pub async fn remove_shift(bot: Bot, msg: Message, pool: PgPool, id: i64) -> Result<(), AppError> {
+    if !rota::remove_shift(&pool, id).await? {
+        return Err(AppError::NotFound(Entity::Shift));
+    }
+    bot.send_message(msg.chat.id, format!("Shift #{} removed.", id))
+        .await?;
+    Ok(())
+}
+
+/// Tells the sender their user ID and role.
+///
+/// The user ID is what an admin needs to give someone a role with `/setrole`.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to send messages.
+/// * `msg` - The message that triggered this command.
+/// * `pool` - The database connection pool.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+pub async fn who_am_i(bot: Bot, msg: Message, pool: PgPool) -> Result<(), Error> {
+    let Some(user) = msg.from.as_ref() else {
+        bot.send_message(msg.chat.id, "I can't tell who sent this message.")
+            .await?;
+        return Ok(());
+    };
+
+    let role = roles::role_of(&pool, user.id).await?;
+    bot.send_message(
+        msg.chat.id,
+        format!("Your user ID is {} and your role is {}.", user.id, role),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Lists everyone with a staff role.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to send messages.
+/// * `msg` - The message that triggered this command.
+/// * `pool` - The database connection pool.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+pub async fn list_staff(bot: Bot, msg: Message, pool: PgPool) -> Result<(), Error> {
+    let assignments = roles::list_assignments(&pool).await?;
+    if assignments.is_empty() {
+        bot.send_message(msg.chat.id, "Nobody has a staff role yet.")
+            .await?;
+        return Ok(());
+    }
+
+    let entries = assignments
+        .iter()
+        .map(|assignment| {
+            format!(
+                "• {} — {} since {}",
+                assignment.user_id,
+                assignment.role,
+                assignment.granted_at.format("%d %b %Y")
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    bot.send_message(msg.chat.id, format!("👥 Pharmacy staff:\n\n{}", entries))
+        .await?;
+    Ok(())
+}
+
+/// Changes the role of a user.
+///
+/// Admins can't change their own role, so the last admin can't lock
+/// everyone out by accident.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to send messages.
+/// * `msg` - The message that triggered this command.
+/// * `pool` - The database connection pool.
+/// * `user` - The user whose role changes.
+/// * `role` - The new role.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+pub async fn set_user_role(
+    bot: Bot,
+    msg: Message,
+    pool: PgPool,
+    user: UserId,
+    role: Role,
+) -> Result<(), Error> {
+    let Some(admin) = msg.from.as_ref() else {
+        return Ok(());
+    };
+    if admin.id == user {
+        bot.send_message(msg.chat.id, "You can't change your own role.")
+            .await?;
+        return Ok(());
+    }
+
+    roles::set_role(&pool, user, role, admin.id).await?;
+    bot.send_message(msg.chat.id, format!("User {} is now a {}.", user, role))
+        .await?;
+    Ok(())
+}
+
+/// Lists all scheduled jobs with their last and next runs.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to send messages.
+/// * `msg` - The message that triggered this command.
+/// * `jobs` - The registry of scheduled jobs.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+pub async fn list_jobs(bot: Bot, msg: Message, jobs: JobRegistry) -> Result<(), Error> {
+    let overview = jobs.overview().await?;
+    bot.send_message(msg.chat.id, overview).await?;
+    Ok(())
+}
+
+/// Runs a scheduled job immediately and reports its result.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to send messages.
+/// * `msg` - The message that triggered this command.
+/// * `jobs` - The registry of scheduled jobs.
+/// * `name` - The name of the job to run.
+///
+/// # Returns
+///
+/// Returns a Result indicating success or failure of the operation.
+pub async fn run_job(bot: Bot, msg: Message, jobs: JobRegistry, name: String) -> Result<(), Error> {
+    let kind = match name.parse::<JobKind>() {
+        Ok(kind) => kind,
+        Err(e) => {
+            bot.send_message(msg.chat.id, e).await?;
+            return Ok(());
+        }
+    };
+
+    let reply = match jobs.run(kind, Trigger::Manual).await {
+        Ok(result) => format!("Job {} finished: {}", kind, result),
+        Err(e) => format!("Job {} could not be run: {}", kind, e),
+    };
+    bot.send_message(msg.chat.id, reply).await?;
+    Ok(())
+}
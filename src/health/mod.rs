@@ -0,0 +1,100 @@
+use axum::{extract::State, http::header, http::StatusCode, routing::get, Router};
+use sqlx::PgPool;
+use std::{net::SocketAddr, time::Duration};
+use tokio::net::TcpListener;
+
+use crate::{jobs::JobRegistry, metrics, Error};
+
+/// How long the readiness check waits for the database.
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What the readiness check looks at.
+#[derive(Clone)]
+pub struct HealthState {
+    pub pool: PgPool,
+    pub jobs: JobRegistry,
+}
+
+/// Builds the router of the health endpoints:
+///
+/// - `/healthz` answers as long as the process is alive.
+/// - `/readyz` answers `200` when the database is reachable and the job
+///   scheduler is ticking, and `503` otherwise, listing each check.
+/// - `/metrics` renders the bot's metrics for Prometheus.
+pub fn router(state: HealthState) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(render_metrics))
+        .with_state(state)
+}
+
+/// Starts the HTTP server of the health endpoints in the background.
+///
+/// # Arguments
+///
+/// * `address` - The local address to listen on.
+/// * `state` - What the readiness check looks at.
+///
+/// # Returns
+///
+/// Returns an error if the address couldn't be bound.
+pub async fn serve(address: SocketAddr, state: HealthState) -> Result<(), Error> {
+    let listener = TcpListener::bind(address).await?;
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, router(state)).await {
+            log::error!("Health server failed: {}", e);
+        }
+    });
+    log::info!("Serving health checks and metrics on {}", address);
+    Ok(())
+}
+
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+async fn readyz(State(state): State<HealthState>) -> (StatusCode, String) {
+    let ping = metrics::global().time_query(
+        "ping",
+        tokio::time::timeout(
+            DATABASE_TIMEOUT,
+            sqlx::query("SELECT 1").execute(&state.pool),
+        ),
+    );
+    let database = match ping.await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("timed out".to_string()),
+    };
+    let scheduler = if state.jobs.is_running().await {
+        Ok(())
+    } else {
+        Err("not ticking".to_string())
+    };
+
+    let checks = [("database", database), ("scheduler", scheduler)];
+    let ready = checks.iter().all(|(_, result)| result.is_ok());
+    let body = checks
+        .iter()
+        .map(|(name, result)| match result {
+            Ok(()) => format!("{}: ok", name),
+            Err(e) => format!("{}: {}", name, e),
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, body)
+}
+
+async fn render_metrics() -> ([(header::HeaderName, &'static str); 1], String) {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::global().render(),
+    )
+}
@@ -0,0 +1,674 @@
+use crate::{
+    dialogues,
+    queue::MessageQueue,
+    repositories::Repositories,
+    services,
+    topics::{StaffTopic, StaffTopics},
+    Error,
+};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use croner::Cron;
+use sqlx::PgPool;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    str::FromStr,
+    sync::Arc,
+};
+use teloxide::prelude::*;
+use thiserror::Error;
+use tokio::sync::Mutex;
+use tokio_cron_scheduler::{Job, JobScheduler};
+
+/// Every job known to the bot.
+///
+/// Each kind has a stable name, used as its key in the `scheduled_jobs`
+/// table and as the argument of the `/runjob` command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum JobKind {
+    ExpiryCheck,
+    LowStockCheck,
+    DailyReport,
+    PendingOrderReminder,
+    TicketSlaCheck,
+    DialogueCleanup,
+}
+
+impl JobKind {
+    pub const ALL: [JobKind; 6] = [
+        JobKind::ExpiryCheck,
+        JobKind::LowStockCheck,
+        JobKind::DailyReport,
+        JobKind::PendingOrderReminder,
+        JobKind::TicketSlaCheck,
+        JobKind::DialogueCleanup,
+    ];
+
+    /// The stable name of the job.
+    pub fn name(self) -> &'static str {
+        match self {
+            JobKind::ExpiryCheck => "expiry_check",
+            JobKind::LowStockCheck => "low_stock_check",
+            JobKind::DailyReport => "daily_report",
+            JobKind::PendingOrderReminder => "pending_order_reminder",
+            JobKind::TicketSlaCheck => "ticket_sla_check",
+            JobKind::DialogueCleanup => "dialogue_cleanup",
+        }
+    }
+
+    /// A short human readable description of the job.
+    pub fn description(self) -> &'static str {
+        match self {
+            JobKind::ExpiryCheck => "Alert about medicines expiring within 6 months",
+            JobKind::LowStockCheck => "Alert about medicines running low on stock",
+            JobKind::DailyReport => "Summary of today's orders",
+            JobKind::PendingOrderReminder => "Reminder about orders still pending",
+            JobKind::TicketSlaCheck => "Alert about consultations waiting past the SLA",
+            JobKind::DialogueCleanup => "Remove conversations left idle past the dialogue TTL",
+        }
+    }
+
+    /// The default cron schedule of the job.
+    ///
+    /// Schedules use the six-field format `sec min hour day-of-month month
+    /// day-of-week` and are interpreted in the pharmacy's local time zone.
+    pub fn default_schedule(self) -> &'static str {
+        match self {
+            JobKind::ExpiryCheck => "0 0 8 * * *",
+            JobKind::LowStockCheck => "0 0 9 * * *",
+            JobKind::DailyReport => "0 0 20 * * *",
+            JobKind::PendingOrderReminder => "0 0 */4 * * *",
+            JobKind::TicketSlaCheck => "0 */5 * * * *",
+            JobKind::DialogueCleanup => "0 0 * * * *",
+        }
+    }
+}
+
+impl fmt::Display for JobKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for JobKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        JobKind::ALL
+            .into_iter()
+            .find(|kind| kind.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = JobKind::ALL.iter().map(|kind| kind.name()).collect();
+                format!("Unknown job. Available jobs: {}", names.join(", "))
+            })
+    }
+}
+
+/// What caused a job to run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    /// The job's schedule fired while the bot was running.
+    Schedule,
+    /// The job's scheduled run was missed while the bot was down.
+    CatchUp,
+    /// A staff member started the job with `/runjob`.
+    Manual,
+}
+
+impl Trigger {
+    fn as_str(self) -> &'static str {
+        match self {
+            Trigger::Schedule => "schedule",
+            Trigger::CatchUp => "catch_up",
+            Trigger::Manual => "manual",
+        }
+    }
+}
+
+/// The persisted state of a job, as stored in the `scheduled_jobs` table.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct JobStatus {
+    pub name: String,
+    pub last_run_at: Option<DateTime<Utc>>,
+    pub last_success: Option<bool>,
+    pub last_result: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Everything a job needs to do its work.
+#[derive(Clone)]
+pub struct JobContext {
+    pub pool: PgPool,
+    pub repositories: Repositories,
+    pub queue: MessageQueue,
+    pub staff_chat_id: ChatId,
+    pub topics: StaffTopics,
+    pub low_stock_threshold: i32,
+    pub ticket_sla: chrono::Duration,
+    /// How long an idle dialogue is kept; `None` keeps them until they end.
+    pub dialogue_ttl: Option<chrono::Duration>,
+}
+
+/// An invalid schedule configuration, reported at startup.
+#[derive(Debug, Error)]
+pub enum ScheduleError {
+    #[error("malformed schedule override {0:?}; expected <job>=<cron expression>")]
+    MalformedOverride(String),
+    #[error("unknown job {0:?} in schedule overrides")]
+    UnknownJob(String),
+    #[error("invalid cron expression {expression:?} for {job}: {reason}")]
+    InvalidCron {
+        job: JobKind,
+        expression: String,
+        reason: String,
+    },
+}
+
+/// The validated schedules of all jobs in the pharmacy's time zone.
+#[derive(Clone, Debug)]
+pub struct JobSchedules {
+    timezone: Tz,
+    schedules: HashMap<JobKind, (String, Cron)>,
+}
+
+impl JobSchedules {
+    /// Parses and validates the schedule configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `timezone` - The pharmacy's time zone.
+    /// * `overrides` - Semicolon separated `<job>=<cron>` pairs replacing the
+    ///   default schedule of individual jobs, e.g. `daily_report=0 30 21 * * *`.
+    ///   May be empty.
+    ///
+    /// # Returns
+    ///
+    /// Returns the schedules, or a [`ScheduleError`] describing the first
+    /// problem found.
+    pub fn from_config(timezone: Tz, overrides: &str) -> Result<Self, ScheduleError> {
+        let mut expressions: HashMap<JobKind, String> = JobKind::ALL
+            .into_iter()
+            .map(|kind| (kind, kind.default_schedule().to_string()))
+            .collect();
+
+        for entry in overrides
+            .split(';')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+        {
+            let (name, expression) = entry
+                .split_once('=')
+                .ok_or_else(|| ScheduleError::MalformedOverride(entry.to_string()))?;
+            let kind = name
+                .trim()
+                .parse::<JobKind>()
+                .map_err(|_| ScheduleError::UnknownJob(name.trim().to_string()))?;
+            expressions.insert(kind, expression.trim().to_string());
+        }
+
+        let mut schedules = HashMap::new();
+        for (kind, expression) in expressions {
+            let cron = Cron::new(&expression)
+                .with_seconds_required()
+                .with_dom_and_dow()
+                .parse()
+                .map_err(|e| ScheduleError::InvalidCron {
+                    job: kind,
+                    expression: expression.clone(),
+                    reason: e.to_string(),
+                })?;
+            schedules.insert(kind, (expression, cron));
+        }
+
+        Ok(Self {
+            timezone,
+            schedules,
+        })
+    }
+
+    /// The time zone the schedules are interpreted in.
+    pub fn timezone(&self) -> Tz {
+        self.timezone
+    }
+
+    /// The cron expression of a job.
+    pub fn expression(&self, kind: JobKind) -> &str {
+        &self.schedules[&kind].0
+    }
+
+    /// Returns the first scheduled run of a job strictly after `after`.
+    pub fn next_run(
+        &self,
+        kind: JobKind,
+        after: DateTime<Utc>,
+    ) -> Result<DateTime<Utc>, croner::errors::CronError> {
+        let (_, cron) = &self.schedules[&kind];
+        cron.find_next_occurrence(&after.with_timezone(&self.timezone), false)
+            .map(|next| next.with_timezone(&Utc))
+    }
+}
+
+/// Registry of all scheduled jobs.
+///
+/// The registry keeps the run history of every job in Postgres. Instead of
+/// registering one scheduler job per task, a single tick runs every minute
+/// and starts each job whose next scheduled time (computed from its last
+/// run) has passed. The same check on startup catches up runs that were
+/// missed while the bot was down. Each due run is claimed in the database
+/// first, so the catch-up and the tick never start the same run twice.
+#[derive(Clone)]
+pub struct JobRegistry {
+    schedules: Arc<JobSchedules>,
+    ctx: JobContext,
+    running: Arc<Mutex<HashSet<JobKind>>>,
+    /// When the scheduler last ticked, or started; `None` before it starts.
+    heartbeat: Arc<Mutex<Option<DateTime<Utc>>>>,
+}
+
+impl JobRegistry {
+    /// Creates the registry; nothing runs until it is started.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - The shared context handed to every job.
+    /// * `schedules` - The validated job schedules.
+    pub fn new(ctx: JobContext, schedules: JobSchedules) -> Self {
+        Self {
+            schedules: Arc::new(schedules),
+            ctx,
+            running: Arc::new(Mutex::new(HashSet::new())),
+            heartbeat: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Starts the scheduler that drives the registry.
+    ///
+    /// Every job is first given a row in the database. Missed runs are then
+    /// caught up right away in the background, after which the registry is
+    /// checked at the start of every minute.
+    ///
+    /// # Returns
+    ///
+    /// Returns the running scheduler, or an error if the database could not
+    /// be reached or the scheduler could not be started.
+    pub async fn start(&self) -> Result<JobScheduler, Error> {
+        for kind in JobKind::ALL {
+            sqlx::query("INSERT INTO scheduled_jobs (name) VALUES ($1) ON CONFLICT DO NOTHING")
+                .bind(kind.name())
+                .execute(&self.ctx.pool)
+                .await?;
+        }
+
+        let sched = JobScheduler::new().await?;
+
+        let registry = self.clone();
+        sched
+            .add(Job::new_async("0 * * * * *", move |_uuid, _l| {
+                let registry = registry.clone();
+                Box::pin(async move {
+                    *registry.heartbeat.lock().await = Some(Utc::now());
+                    registry.run_due(Trigger::Schedule).await;
+                })
+            })?)
+            .await?;
+
+        sched.start().await?;
+        *self.heartbeat.lock().await = Some(Utc::now());
+
+        let registry = self.clone();
+        tokio::spawn(async move {
+            registry.run_due(Trigger::CatchUp).await;
+        });
+
+        log::info!("Job scheduler started successfully");
+        Ok(sched)
+    }
+
+    /// Whether the scheduler is ticking, i.e. it started and ticked within the
+    /// last two minutes.
+    pub async fn is_running(&self) -> bool {
+        self.heartbeat
+            .lock()
+            .await
+            .is_some_and(|at| Utc::now() - at < chrono::Duration::minutes(2))
+    }
+
+    /// Runs every job whose next scheduled time is not in the future.
+    async fn run_due(&self, trigger: Trigger) {
+        let statuses = match self.statuses().await {
+            Ok(statuses) => statuses,
+            Err(e) => {
+                log::error!("Failed to load job statuses: {}", e);
+                return;
+            }
+        };
+
+        let now = Utc::now();
+        for kind in JobKind::ALL {
+            let Some(status) = statuses.iter().find(|s| s.name == kind.name()) else {
+                continue;
+            };
+
+            // A job that never ran is scheduled from the moment it was registered
+            let anchor = status.last_run_at.unwrap_or(status.created_at);
+            match self.schedules.next_run(kind, anchor) {
+                Ok(next) if next <= now => {
+                    // The catch-up and the minute tick can both find a job due
+                    // around startup; only the one that claims the run starts it
+                    match self.claim(kind, status.last_run_at, now).await {
+                        Ok(true) => {}
+                        Ok(false) => continue,
+                        Err(e) => {
+                            log::error!("Failed to claim the run of {}: {}", kind, e);
+                            continue;
+                        }
+                    }
+                    if trigger == Trigger::CatchUp {
+                        log::info!("Catching up missed run of {} (due {})", kind, next);
+                    }
+                    if let Err(e) = self.run(kind, trigger).await {
+                        log::error!("Failed to run job {}: {}", kind, e);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => log::error!("Failed to compute next run of {}: {}", kind, e),
+            }
+        }
+    }
+
+    /// Claims a due run of a job by moving its last run to `now`.
+    ///
+    /// # Returns
+    ///
+    /// Returns `false` if the job's last run is no longer `last_run_at`, i.e.
+    /// another trigger has claimed or recorded a run since it was loaded.
+    async fn claim(
+        &self,
+        kind: JobKind,
+        last_run_at: Option<DateTime<Utc>>,
+        now: DateTime<Utc>,
+    ) -> Result<bool, sqlx::Error> {
+        let claimed: Option<(String,)> = sqlx::query_as(
+            "UPDATE scheduled_jobs SET last_run_at = $1
+             WHERE name = $2 AND last_run_at IS NOT DISTINCT FROM $3
+             RETURNING name",
+        )
+        .bind(now)
+        .bind(kind.name())
+        .bind(last_run_at)
+        .fetch_optional(&self.ctx.pool)
+        .await?;
+        Ok(claimed.is_some())
+    }
+
+    /// Runs a single job now and records the outcome.
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - The job to run.
+    /// * `trigger` - What caused the run; stored in the run history.
+    ///
+    /// # Returns
+    ///
+    /// Returns the job's result message, or an error if the job is already
+    /// running or its outcome could not be recorded. A job that fails still
+    /// returns `Ok` with the failure described in the message.
+    pub async fn run(&self, kind: JobKind, trigger: Trigger) -> Result<String, Error> {
+        if !self.running.lock().await.insert(kind) {
+            return Err(format!("Job {} is already running", kind).into());
+        }
+
+        let started_at = Utc::now();
+        let outcome = self.execute(kind).await;
+        let finished_at = Utc::now();
+        self.running.lock().await.remove(&kind);
+
+        let (success, result) = match outcome {
+            Ok(result) => {
+                log::info!("Job {} completed: {}", kind, result);
+                (true, result)
+            }
+            Err(e) => {
+                log::error!("Job {} failed: {}", kind, e);
+                (false, format!("Failed: {}", e))
+            }
+        };
+
+        let mut tx = self.ctx.pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO job_runs (job_name, trigger, started_at, finished_at, success, result)
+             VALUES ($1, $2, $3, $4, $5, $6)",
+        )
+        .bind(kind.name())
+        .bind(trigger.as_str())
+        .bind(started_at)
+        .bind(finished_at)
+        .bind(success)
+        .bind(&result)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            "UPDATE scheduled_jobs SET last_run_at = $1, last_success = $2, last_result = $3
+             WHERE name = $4",
+        )
+        .bind(started_at)
+        .bind(success)
+        .bind(&result)
+        .bind(kind.name())
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(result)
+    }
+
+    /// Does the actual work of a job and describes the outcome.
+    async fn execute(&self, kind: JobKind) -> Result<String, Error> {
+        let JobContext {
+            pool,
+            repositories,
+            queue,
+            staff_chat_id,
+            topics,
+            low_stock_threshold,
+            ticket_sla,
+            dialogue_ttl,
+        } = &self.ctx;
+
+        match kind {
+            JobKind::ExpiryCheck => {
+                let count = services::check_and_notify_expiring_medicines(
+                    repositories.medicines.as_ref(),
+                    queue,
+                    *staff_chat_id,
+                    topics.thread(StaffTopic::Expiry),
+                )
+                .await?;
+                Ok(format!("{} expiring medicine(s) reported", count))
+            }
+            JobKind::LowStockCheck => {
+                let count = services::check_low_stock(
+                    repositories.medicines.as_ref(),
+                    queue,
+                    *staff_chat_id,
+                    topics.thread(StaffTopic::Inventory),
+                    *low_stock_threshold,
+                )
+                .await?;
+                Ok(format!("{} low-stock medicine(s) reported", count))
+            }
+            JobKind::DailyReport => {
+                let count = services::send_daily_report(
+                    repositories.orders.as_ref(),
+                    queue,
+                    *staff_chat_id,
+                    topics.thread(StaffTopic::Orders),
+                )
+                .await?;
+                Ok(format!("Report sent for {} order(s)", count))
+            }
+            JobKind::PendingOrderReminder => {
+                let count = services::remind_pending_orders(
+                    repositories.orders.as_ref(),
+                    queue,
+                    *staff_chat_id,
+                    topics.thread(StaffTopic::Orders),
+                )
+                .await?;
+                Ok(format!("{} pending order(s)", count))
+            }
+            JobKind::TicketSlaCheck => {
+                let count = services::alert_overdue_tickets(
+                    pool,
+                    queue,
+                    *staff_chat_id,
+                    topics.thread(StaffTopic::Consultations),
+                    *ticket_sla,
+                )
+                .await?;
+                Ok(format!("{} overdue ticket(s) reported", count))
+            }
+            JobKind::DialogueCleanup => match dialogue_ttl {
+                Some(ttl) => {
+                    let count = dialogues::remove_stale(pool, *ttl).await?;
+                    Ok(format!("{} idle dialogue(s) removed", count))
+                }
+                None => Ok("Dialogues never expire".to_string()),
+            },
+        }
+    }
+
+    /// Loads the persisted state of every job.
+    pub async fn statuses(&self) -> Result<Vec<JobStatus>, sqlx::Error> {
+        sqlx::query_as::<_, JobStatus>("SELECT * FROM scheduled_jobs ORDER BY name")
+            .fetch_all(&self.ctx.pool)
+            .await
+    }
+
+    /// Formats an overview of all jobs for the `/jobs` command.
+    pub async fn overview(&self) -> Result<String, Error> {
+        let statuses = self.statuses().await?;
+        let now = Utc::now();
+        let timezone = self.schedules.timezone();
+        let local = |at: DateTime<Utc>| at.with_timezone(&timezone).format("%d-%m-%Y %H:%M");
+
+        let entries = JobKind::ALL
+            .into_iter()
+            .map(|kind| {
+                let status = statuses.iter().find(|s| s.name == kind.name());
+                let last_run = match status.and_then(|s| s.last_run_at) {
+                    Some(at) => local(at).to_string(),
+                    None => "never".to_string(),
+                };
+                let outcome = match status.and_then(|s| s.last_success) {
+                    Some(true) => "✅",
+                    Some(false) => "❌",
+                    None => "➖",
+                };
+                let result = status
+                    .and_then(|s| s.last_result.clone())
+                    .unwrap_or_default();
+                let next_run = self
+                    .schedules
+                    .next_run(kind, now)
+                    .map(|at| local(at).to_string())
+                    .unwrap_or_else(|_| "unknown".to_string());
+
+                format!(
+                    "{} {} ({})\n   {}\n   Last run: {} {}\n   Next run: {}",
+                    outcome,
+                    kind.name(),
+                    self.schedules.expression(kind),
+                    kind.description(),
+                    last_run,
+                    result,
+                    next_run
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n\n");
+
+        Ok(format!(
+            "Scheduled jobs (times in {}):\n\n{}\n\nUse /runjob <name> to run a job now.",
+            timezone, entries
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    #[test]
+    fn expiry_check_fires_at_8am_cairo_time() {
+        let schedules = JobSchedules::from_config(Tz::Africa__Cairo, "").unwrap();
+
+        // Winter: Cairo is UTC+2
+        let next = schedules
+            .next_run(JobKind::ExpiryCheck, utc(2024, 1, 15, 0, 0))
+            .unwrap();
+        assert_eq!(next, utc(2024, 1, 15, 6, 0));
+
+        // Summer: Cairo observes daylight saving time, UTC+3
+        let next = schedules
+            .next_run(JobKind::ExpiryCheck, utc(2024, 7, 15, 0, 0))
+            .unwrap();
+        assert_eq!(next, utc(2024, 7, 15, 5, 0));
+
+        // Once today's run has passed, the next one is tomorrow
+        let next = schedules
+            .next_run(JobKind::ExpiryCheck, utc(2024, 1, 15, 6, 0))
+            .unwrap();
+        assert_eq!(next, utc(2024, 1, 16, 6, 0));
+    }
+
+    #[test]
+    fn overrides_replace_default_schedules() {
+        let schedules = JobSchedules::from_config(
+            Tz::UTC,
+            "daily_report=0 30 21 * * *; low_stock_check = 0 0 7 * * Mon",
+        )
+        .unwrap();
+
+        assert_eq!(schedules.expression(JobKind::DailyReport), "0 30 21 * * *");
+        assert_eq!(
+            schedules
+                .next_run(JobKind::DailyReport, utc(2024, 3, 1, 12, 0))
+                .unwrap(),
+            utc(2024, 3, 1, 21, 30)
+        );
+        // 2024-03-01 is a Friday; the next Monday is 2024-03-04
+        assert_eq!(
+            schedules
+                .next_run(JobKind::LowStockCheck, utc(2024, 3, 1, 12, 0))
+                .unwrap(),
+            utc(2024, 3, 4, 7, 0)
+        );
+        assert_eq!(
+            schedules.expression(JobKind::ExpiryCheck),
+            JobKind::ExpiryCheck.default_schedule()
+        );
+    }
+
+    #[test]
+    fn invalid_configuration_is_rejected() {
+        assert!(matches!(
+            JobSchedules::from_config(Tz::Africa__Cairo, "daily_report"),
+            Err(ScheduleError::MalformedOverride(_))
+        ));
+        assert!(matches!(
+            JobSchedules::from_config(Tz::Africa__Cairo, "weekly_report=0 0 8 * * *"),
+            Err(ScheduleError::UnknownJob(_))
+        ));
+        // Five-field expressions are rejected: seconds are required
+        assert!(matches!(
+            JobSchedules::from_config(Tz::Africa__Cairo, "daily_report=0 8 * * *"),
+            Err(ScheduleError::InvalidCron { .. })
+        ));
+    }
+}
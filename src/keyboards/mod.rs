@@ -0,0 +1,25 @@
+use teloxide::types::{KeyboardButton, KeyboardMarkup};
+
+/// The menu button that lists the inventory.
+pub const CHECK_INVENTORY: &str = "📋 Check Inventory";
+
+/// The menu button that places an order.
+pub const PLACE_ORDER: &str = "🛒 Place Order";
+
+/// The menu button that shows the available commands.
+pub const HELP: &str = "❓ Help";
+
+/// Builds the menu shown by `/menu`, one button per row.
+///
+/// The keyboard is resizable and disappears after one use. Taps arrive as
+/// plain text messages carrying the button's label, which `handle_message`
+/// matches against the constants above.
+pub fn main_menu() -> KeyboardMarkup {
+    KeyboardMarkup::new(vec![
+        vec![KeyboardButton::new(CHECK_INVENTORY)],
+        vec![KeyboardButton::new(PLACE_ORDER)],
+        vec![KeyboardButton::new(HELP)],
+    ])
+    .resize_keyboard() // Allow the keyboard to be resized
+    .one_time_keyboard() // Make the keyboard disappear after one use
+}
@@ -0,0 +1,29 @@
+pub mod captcha;
+pub mod commands;
+pub mod config;
+pub mod dialogues;
+pub mod error;
+pub mod filters;
+pub mod handlers;
+pub mod health;
+pub mod jobs;
+pub mod keyboards;
+pub mod links;
+pub mod metrics;
+pub mod models;
+pub mod moderation;
+pub mod modlog;
+pub mod queue;
+pub mod relay;
+pub mod repositories;
+pub mod roles;
+pub mod rota;
+pub mod services;
+pub mod tickets;
+pub mod topics;
+pub mod users;
+pub mod utils;
+pub mod warnings;
+pub mod webhook;
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
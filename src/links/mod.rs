@@ -0,0 +1,190 @@
+use chrono::{DateTime, Duration, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use sqlx::PgPool;
+use teloxide::types::ChatId;
+
+/// Length of generated tokens. Telegram allows up to 64 characters in a
+/// `start` parameter; 24 alphanumeric characters are far beyond guessing.
+const TOKEN_LENGTH: usize = 24;
+
+/// An opaque deep-link token that lets people message a recipient anonymously.
+///
+/// Links built from a token (`t.me/<bot>?start=<token>`) never reveal the
+/// recipient's chat. Tokens can expire, be limited to a number of uses and be
+/// revoked by their owner at any time.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct DeepLinkToken {
+    pub token: String,
+    pub recipient_chat_id: i64,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_uses: Option<i32>,
+    pub use_count: i32,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl DeepLinkToken {
+    /// Whether the token can still start a conversation at `now`: it isn't
+    /// revoked, expired or used up.
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.revoked_at.is_none()
+            && self.expires_at.is_none_or(|expires_at| expires_at > now)
+            && self
+                .max_uses
+                .is_none_or(|max_uses| self.use_count < max_uses)
+    }
+}
+
+/// Default limits applied to newly created tokens.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenPolicy {
+    /// How long a token stays valid; `None` means it never expires.
+    pub ttl: Option<Duration>,
+    /// How many conversations a token can start; `None` means unlimited.
+    pub max_uses: Option<i32>,
+}
+
+/// Generates a random token that is safe to use in a `start` parameter.
+pub fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Creates a new token for the given recipient.
+///
+/// # Arguments
+///
+/// * `pool` - The database connection pool.
+/// * `recipient` - The chat that will receive messages sent through the link.
+/// * `policy` - Expiry and usage limits for the token.
+///
+/// # Returns
+///
+/// Returns the stored token.
+pub async fn create_token(
+    pool: &PgPool,
+    recipient: ChatId,
+    policy: TokenPolicy,
+) -> Result<DeepLinkToken, sqlx::Error> {
+    let expires_at = policy.ttl.map(|ttl| Utc::now() + ttl);
+
+    sqlx::query_as::<_, DeepLinkToken>(
+        "INSERT INTO deep_link_tokens (token, recipient_chat_id, expires_at, max_uses)
+         VALUES ($1, $2, $3, $4) RETURNING *",
+    )
+    .bind(generate_token())
+    .bind(recipient.0)
+    .bind(expires_at)
+    .bind(policy.max_uses)
+    .fetch_one(pool)
+    .await
+}
+
+/// Redeems a token, counting one use.
+///
+/// The check and the increment happen in a single statement, so concurrent
+/// redemptions can't exceed the usage limit.
+///
+/// # Arguments
+///
+/// * `pool` - The database connection pool.
+/// * `token` - The token from the deep link.
+///
+/// # Returns
+///
+/// Returns the recipient's chat, or `None` if the token is unknown, expired,
+/// revoked or used up.
+pub async fn redeem(pool: &PgPool, token: &str) -> Result<Option<ChatId>, sqlx::Error> {
+    let recipient: Option<(i64,)> = sqlx::query_as(
+        "UPDATE deep_link_tokens SET use_count = use_count + 1
+         WHERE token = $1
+           AND revoked_at IS NULL
+           AND (expires_at IS NULL OR expires_at > NOW())
+           AND (max_uses IS NULL OR use_count < max_uses)
+         RETURNING recipient_chat_id",
+    )
+    .bind(token)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(recipient.map(|(id,)| ChatId(id)))
+}
+
+/// Looks up the recipient of a token that can still be used, without
+/// counting a use.
+///
+/// # Returns
+///
+/// Returns the recipient's chat, or `None` if the token is unknown, expired,
+/// revoked or used up.
+pub async fn recipient(pool: &PgPool, token: &str) -> Result<Option<ChatId>, sqlx::Error> {
+    let recipient: Option<(i64,)> = sqlx::query_as(
+        "SELECT recipient_chat_id FROM deep_link_tokens
+         WHERE token = $1
+           AND revoked_at IS NULL
+           AND (expires_at IS NULL OR expires_at > NOW())
+           AND (max_uses IS NULL OR use_count < max_uses)",
+    )
+    .bind(token)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(recipient.map(|(id,)| ChatId(id)))
+}
+
+/// Lists the tokens of a recipient that can still be used.
+pub async fn list_active(
+    pool: &PgPool,
+    recipient: ChatId,
+) -> Result<Vec<DeepLinkToken>, sqlx::Error> {
+    sqlx::query_as::<_, DeepLinkToken>(
+        "SELECT * FROM deep_link_tokens
+         WHERE recipient_chat_id = $1
+           AND revoked_at IS NULL
+           AND (expires_at IS NULL OR expires_at > NOW())
+           AND (max_uses IS NULL OR use_count < max_uses)
+         ORDER BY created_at",
+    )
+    .bind(recipient.0)
+    .fetch_all(pool)
+    .await
+}
+
+/// Revokes one of the recipient's tokens.
+///
+/// # Returns
+///
+/// Returns `true` if a token was revoked, `false` if the recipient has no
+/// such active token.
+pub async fn revoke(pool: &PgPool, recipient: ChatId, token: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE deep_link_tokens SET revoked_at = NOW()
+         WHERE token = $1 AND recipient_chat_id = $2 AND revoked_at IS NULL",
+    )
+    .bind(token)
+    .bind(recipient.0)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Revokes every token of the recipient.
+///
+/// # Returns
+///
+/// Returns the number of tokens revoked.
+pub async fn revoke_all(pool: &PgPool, recipient: ChatId) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE deep_link_tokens SET revoked_at = NOW()
+         WHERE recipient_chat_id = $1 AND revoked_at IS NULL",
+    )
+    .bind(recipient.0)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
@@ -0,0 +1,167 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::{future::Future, sync::LazyLock, time::Instant};
+use teloxide::RequestError;
+
+use crate::{error::AppError, Error};
+
+/// The metrics of this process, exposed at `/metrics`.
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Returns the metrics of this process.
+pub fn global() -> &'static Metrics {
+    &METRICS
+}
+
+/// Counters and histograms describing what the bot is doing, in the
+/// Prometheus text format.
+///
+/// Metrics are process-wide rather than handed around as a dependency, since
+/// they are recorded from every corner of the bot: handlers, the outbound
+/// queue and the dialogue storage.
+pub struct Metrics {
+    registry: Registry,
+    commands: IntCounterVec,
+    orders: IntCounter,
+    notifications_sent: IntCounter,
+    notifications_failed: IntCounter,
+    api_errors: IntCounterVec,
+    db_queries: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("telepharma".to_string()), None)
+            .expect("the metrics prefix is valid");
+
+        let commands = IntCounterVec::new(
+            Opts::new("commands_handled_total", "Commands handled, by command"),
+            &["command"],
+        )
+        .expect("the commands metric is valid");
+        let orders = IntCounter::new("orders_placed_total", "Orders placed by customers")
+            .expect("the orders metric is valid");
+        let notifications_sent = IntCounter::new(
+            "notifications_sent_total",
+            "Messages delivered by the outbound queue",
+        )
+        .expect("the notifications metric is valid");
+        let notifications_failed = IntCounter::new(
+            "notifications_failed_total",
+            "Messages the outbound queue gave up on",
+        )
+        .expect("the notifications metric is valid");
+        let api_errors = IntCounterVec::new(
+            Opts::new(
+                "telegram_api_errors_total",
+                "Failed Telegram Bot API requests, by kind of error",
+            ),
+            &["kind"],
+        )
+        .expect("the API errors metric is valid");
+        let db_queries = HistogramVec::new(
+            HistogramOpts::new(
+                "db_query_duration_seconds",
+                "Duration of database queries made by the medicine and order repositories \
+                 and the dialogue storage, and of queuing messages, remembering users and \
+                 the readiness check, by query",
+            )
+            .buckets(vec![
+                0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+            ]),
+            &["query"],
+        )
+        .expect("the query duration metric is valid");
+
+        for collector in [
+            Box::new(commands.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(orders.clone()),
+            Box::new(notifications_sent.clone()),
+            Box::new(notifications_failed.clone()),
+            Box::new(api_errors.clone()),
+            Box::new(db_queries.clone()),
+        ] {
+            registry
+                .register(collector)
+                .expect("metric names are unique");
+        }
+
+        Self {
+            registry,
+            commands,
+            orders,
+            notifications_sent,
+            notifications_failed,
+            api_errors,
+            db_queries,
+        }
+    }
+
+    /// Counts a command that passed the role check, e.g. `start`.
+    pub fn command_handled(&self, command: &str) {
+        self.commands.with_label_values(&[command]).inc();
+    }
+
+    /// Counts a placed order.
+    pub fn order_placed(&self) {
+        self.orders.inc();
+    }
+
+    /// Counts a message delivered by the outbound queue.
+    pub fn notification_sent(&self) {
+        self.notifications_sent.inc();
+    }
+
+    /// Counts a message the outbound queue gave up on.
+    pub fn notification_failed(&self) {
+        self.notifications_failed.inc();
+    }
+
+    /// Counts a failed Telegram Bot API request.
+    pub fn api_error(&self, error: &RequestError) {
+        let kind = match error {
+            RequestError::Api(_) => "api",
+            RequestError::MigrateToChatId(_) => "migrate_to_chat_id",
+            RequestError::RetryAfter(_) => "retry_after",
+            RequestError::Network(_) => "network",
+            RequestError::InvalidJson { .. } => "invalid_json",
+            RequestError::Io(_) => "io",
+        };
+        self.api_errors.with_label_values(&[kind]).inc();
+    }
+
+    /// Counts an error returned by an update handler if it came from the Bot API.
+    pub fn handler_error(&self, error: &Error) {
+        if let Some(error) = error.downcast_ref::<RequestError>() {
+            self.api_error(error);
+        } else if let Some(AppError::Telegram(error)) = error.downcast_ref::<AppError>() {
+            self.api_error(error);
+        }
+    }
+
+    /// Runs a database query and records how long it took, whether or not it
+    /// succeeded.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A short, fixed name for the query, used as its label.
+    /// * `future` - The query to run.
+    pub async fn time_query<F: Future>(&self, query: &str, future: F) -> F::Output {
+        let started = Instant::now();
+        let output = future.await;
+        self.db_queries
+            .with_label_values(&[query])
+            .observe(started.elapsed().as_secs_f64());
+        output
+    }
+
+    /// Renders every metric in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            log::error!("Failed to encode metrics: {}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
@@ -0,0 +1,17 @@
+#[derive(sqlx::FromRow, serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Medicine {
+    pub id: i32,
+    pub name: String,
+    pub stock: i32,
+    pub expiry_date: chrono::NaiveDate,
+}
+
+#[derive(sqlx::FromRow, serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Order {
+    pub id: i32,
+    pub user_id: String,
+    pub medicine_id: i32,
+    pub quantity: i32,
+    pub status: String,
+    pub created_at: chrono::NaiveDate,
+}
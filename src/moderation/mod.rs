@@ -0,0 +1,253 @@
+use sqlx::PgPool;
+use std::{convert::Infallible, fmt, str::FromStr};
+use teloxide::{prelude::*, types::MessageEntityKind, RequestError};
+
+use crate::{error::AppError, users};
+
+/// A user named in the arguments of a moderation command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetSpec {
+    /// `@username`, resolved through the users the bot has seen.
+    Username(String),
+    /// A numeric Telegram user ID.
+    Id(UserId),
+}
+
+/// The arguments of a moderation command: an optional target followed by an
+/// optional free-text reason, e.g. `@someone posting ads`.
+///
+/// Without an explicit target the command acts on the user mentioned in the
+/// message or, failing that, on the author of the replied-to message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModerationArgs {
+    pub target: Option<TargetSpec>,
+    pub reason: Option<String>,
+}
+
+impl FromStr for ModerationArgs {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (first, rest) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+
+        let target = if first.len() > 1 && first.starts_with('@') {
+            Some(TargetSpec::Username(first[1..].to_string()))
+        } else if !first.is_empty() && first.bytes().all(|b| b.is_ascii_digit()) {
+            first.parse().ok().map(|id| TargetSpec::Id(UserId(id)))
+        } else {
+            None
+        };
+
+        let reason = if target.is_some() { rest } else { s }.trim();
+        Ok(Self {
+            target,
+            reason: (!reason.is_empty()).then(|| reason.to_string()),
+        })
+    }
+}
+
+/// The user a moderation command acts on.
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub id: UserId,
+    /// A name to show in replies.
+    pub name: String,
+}
+
+/// Works out who a moderation command acts on, and why.
+///
+/// In order of preference the target is the user named in the arguments, a
+/// text mention in the message (for users without a username), or the author
+/// of the replied-to message.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance, used to look up users given by ID.
+/// * `pool` - The database connection pool, used to look up usernames.
+/// * `msg` - The message containing the command.
+/// * `args` - The parsed command arguments.
+///
+/// # Returns
+///
+/// Returns the target and the reason, [`AppError::Validation`] explaining how
+/// to name one if no target could be found, or the error of a failed lookup.
+pub async fn resolve_target(
+    bot: &Bot,
+    pool: &PgPool,
+    msg: &Message,
+    args: ModerationArgs,
+) -> Result<(Target, Option<String>), AppError> {
+    let ModerationArgs { target, reason } = args;
+
+    match target {
+        Some(TargetSpec::Username(username)) => {
+            let Some(user) = users::find_by_username(pool, &username).await? else {
+                return Err(AppError::Validation(format!(
+                    "I haven't seen @{} yet. Reply to one of their messages or use their user ID instead.",
+                    username
+                )));
+            };
+            let target = Target {
+                id: user.id(),
+                name: format!("@{}", username),
+            };
+            return Ok((target, reason));
+        }
+        Some(TargetSpec::Id(id)) => {
+            // Telegram knows members, and people who left or were banned
+            return match bot.get_chat_member(msg.chat.id, id).await {
+                Ok(member) => {
+                    let target = Target {
+                        id,
+                        name: member.user.first_name,
+                    };
+                    Ok((target, reason))
+                }
+                Err(RequestError::Api(_)) => Err(AppError::Validation(format!(
+                    "There is no user with ID {} in this chat.",
+                    id
+                ))),
+                Err(e) => Err(e.into()),
+            };
+        }
+        None => {}
+    }
+
+    // A text mention links to a user without a username; its text is the
+    // user's name, which also starts the reason
+    let mention = msg.parse_entities().and_then(|entities| {
+        entities.into_iter().find_map(|entity| match entity.kind() {
+            MessageEntityKind::TextMention { user } => Some((user.clone(), entity.text())),
+            _ => None,
+        })
+    });
+    if let Some((user, text)) = mention {
+        let reason = reason
+            .as_deref()
+            .map(|reason| reason.strip_prefix(text).unwrap_or(reason).trim())
+            .filter(|reason| !reason.is_empty())
+            .map(str::to_string);
+        let target = Target {
+            id: user.id,
+            name: user.first_name,
+        };
+        return Ok((target, reason));
+    }
+
+    match msg.reply_to_message().and_then(|replied| replied.from.as_ref()) {
+        Some(user) => {
+            let target = Target {
+                id: user.id,
+                name: user.first_name.clone(),
+            };
+            Ok((target, reason))
+        }
+        None => Err(AppError::Validation(
+            "Reply to a message of the user, or name them: @username, user ID or a mention, optionally followed by a reason.".to_string(),
+        )),
+    }
+}
+
+/// Appends the reason for a moderation action to its announcement.
+pub fn with_reason(announcement: String, reason: Option<&str>) -> String {
+    match reason {
+        Some(reason) => format!("{}\nReason: {}", announcement, reason),
+        None => announcement,
+    }
+}
+
+/// Why a moderation command was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    /// Moderation only makes sense in groups.
+    PrivateChat,
+    /// The command wasn't sent by an identifiable user, e.g. an anonymous admin.
+    UnknownCaller,
+    /// The caller isn't a chat administrator allowed to restrict members.
+    CallerLacksRights,
+    /// The target is the chat owner or an administrator.
+    TargetIsAdmin,
+    /// The caller tried to moderate themselves.
+    TargetIsCaller,
+    /// The caller tried to moderate the bot.
+    TargetIsBot,
+    /// The bot isn't an administrator allowed to restrict members.
+    BotLacksRights,
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Refusal::PrivateChat => "Moderation commands only work in groups.",
+            Refusal::UnknownCaller => {
+                "I can't tell who sent this command. If you're an anonymous admin, please turn off \"Remain anonymous\" first."
+            }
+            Refusal::CallerLacksRights => {
+                "Sorry, only chat administrators who can ban users may do that."
+            }
+            Refusal::TargetIsAdmin => "Sorry, I can't do that to a chat administrator.",
+            Refusal::TargetIsCaller => "You can't use this command on yourself.",
+            Refusal::TargetIsBot => "I'm not going to do that to myself.",
+            Refusal::BotLacksRights => {
+                "I need to be an administrator with the \"Ban users\" right to do that."
+            }
+        })
+    }
+}
+
+/// Checks that a moderation command may act on `target` in the chat it was
+/// sent in.
+///
+/// Telegram itself rejects most of these cases, but with errors that mean
+/// nothing to the people in the chat; checking first lets the bot refuse
+/// politely. Both parties and the bot are looked up with `get_chat_member`.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to query the chat.
+/// * `msg` - The message containing the command.
+/// * `target` - The user the command acts on.
+/// * `bot_id` - The bot's own user ID.
+///
+/// # Returns
+///
+/// Returns `Ok(Err(refusal))` when the command must not go ahead, or an
+/// error if Telegram couldn't be queried.
+pub async fn check_rights(
+    bot: &Bot,
+    msg: &Message,
+    target: UserId,
+    bot_id: UserId,
+) -> Result<Result<(), Refusal>, RequestError> {
+    if msg.chat.is_private() {
+        return Ok(Err(Refusal::PrivateChat));
+    }
+
+    let Some(caller) = msg.from.as_ref().filter(|user| !user.is_anonymous()) else {
+        return Ok(Err(Refusal::UnknownCaller));
+    };
+    if target == bot_id {
+        return Ok(Err(Refusal::TargetIsBot));
+    }
+    if target == caller.id {
+        return Ok(Err(Refusal::TargetIsCaller));
+    }
+
+    let caller_member = bot.get_chat_member(msg.chat.id, caller.id).await?;
+    if !caller_member.kind.can_restrict_members() {
+        return Ok(Err(Refusal::CallerLacksRights));
+    }
+
+    let target_member = bot.get_chat_member(msg.chat.id, target).await?;
+    if target_member.kind.is_privileged() {
+        return Ok(Err(Refusal::TargetIsAdmin));
+    }
+
+    let bot_member = bot.get_chat_member(msg.chat.id, bot_id).await?;
+    if !bot_member.kind.can_restrict_members() {
+        return Ok(Err(Refusal::BotLacksRights));
+    }
+
+    Ok(Ok(()))
+}
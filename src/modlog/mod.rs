@@ -0,0 +1,218 @@
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use sqlx::PgPool;
+use std::fmt;
+use teloxide::types::{Chat, ChatId, ThreadId, User, UserId};
+
+use crate::{
+    queue::{MessageQueue, OutboundMessage},
+    repositories::ModLog,
+    utils::format_duration,
+};
+
+/// A kind of moderation action.
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Eq)]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+pub enum ModAction {
+    Kick,
+    Ban,
+    Mute,
+    Unban,
+    Unmute,
+    Warn,
+    ClearWarns,
+    /// A message removed by the spam filter.
+    Delete,
+}
+
+impl ModAction {
+    /// Whether the action lasts for a while, so a missing duration means it
+    /// is permanent.
+    fn is_timed(&self) -> bool {
+        matches!(self, ModAction::Ban | ModAction::Mute)
+    }
+}
+
+impl fmt::Display for ModAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ModAction::Kick => "kicked",
+            ModAction::Ban => "banned",
+            ModAction::Mute => "muted",
+            ModAction::Unban => "unbanned",
+            ModAction::Unmute => "unmuted",
+            ModAction::Warn => "warned",
+            ModAction::ClearWarns => "cleared the warnings of",
+            ModAction::Delete => "deleted a message from",
+        })
+    }
+}
+
+/// A moderation action as stored in the audit log.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct ModerationRecord {
+    pub id: i64,
+    pub chat_id: i64,
+    pub chat_title: Option<String>,
+    /// The moderator; `None` for actions the bot took on its own.
+    pub actor_id: Option<i64>,
+    pub actor_name: Option<String>,
+    pub target_id: i64,
+    pub target_name: String,
+    pub action: ModAction,
+    /// How long a ban or mute lasts; `None` for a permanent one.
+    pub duration_seconds: Option<i64>,
+    pub reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ModerationRecord {
+    /// Describes the action in one line, e.g. `Alice banned Bob (123) in
+    /// Pharmacy chat for 2h`, followed by the reason on the next line.
+    pub fn describe(&self) -> String {
+        let actor = self.actor_name.as_deref().unwrap_or("The bot");
+        let mut text = format!(
+            "{} {} {} ({})",
+            actor, self.action, self.target_name, self.target_id
+        );
+        if let Some(title) = &self.chat_title {
+            text.push_str(&format!(" in {}", title));
+        }
+        if self.action.is_timed() {
+            match self.duration_seconds {
+                Some(seconds) => text.push_str(&format!(
+                    " for {}",
+                    format_duration(Duration::seconds(seconds))
+                )),
+                None => text.push_str(" permanently"),
+            }
+        }
+        if let Some(reason) = &self.reason {
+            text.push_str(&format!("\nReason: {}", reason));
+        }
+        text
+    }
+}
+
+/// A moderation action about to be logged.
+#[derive(Debug, Clone, Copy)]
+pub struct NewAction<'a> {
+    pub chat: &'a Chat,
+    pub actor: Option<&'a User>,
+    pub target: UserId,
+    pub target_name: &'a str,
+    pub action: ModAction,
+    /// How long a ban or mute lasts; `None` for a permanent one.
+    pub duration: Option<Duration>,
+    pub reason: Option<&'a str>,
+}
+
+/// Where moderation actions are recorded and announced.
+///
+/// Every action is stored through the moderation log repository and mirrored
+/// to a log chat through the outbound queue, so it survives the chat it
+/// happened in.
+#[derive(Clone)]
+pub struct AuditLog {
+    modlog: ModLog,
+    queue: MessageQueue,
+    chat: ChatId,
+    thread: Option<ThreadId>,
+}
+
+impl AuditLog {
+    /// Creates an audit log that posts to `chat`, in the forum topic `thread`
+    /// if there is one.
+    pub fn new(
+        modlog: ModLog,
+        queue: MessageQueue,
+        chat: ChatId,
+        thread: Option<ThreadId>,
+    ) -> Self {
+        Self {
+            modlog,
+            queue,
+            chat,
+            thread,
+        }
+    }
+
+    /// Stores an action and queues its announcement in the log chat.
+    pub async fn record(&self, action: NewAction<'_>) -> Result<ModerationRecord, sqlx::Error> {
+        let record = self.modlog.record(action).await?;
+
+        self.queue
+            .push(
+                OutboundMessage::new(self.chat, format!("🛡 {}", record.describe()))
+                    .thread_id(self.thread),
+            )
+            .await?;
+        Ok(record)
+    }
+}
+
+/// Stores an action in the `moderation_actions` table.
+pub async fn record(pool: &PgPool, action: NewAction<'_>) -> Result<ModerationRecord, sqlx::Error> {
+    sqlx::query_as::<_, ModerationRecord>(
+        "INSERT INTO moderation_actions
+             (chat_id, chat_title, actor_id, actor_name, target_id, target_name, action,
+              duration_seconds, reason)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+         RETURNING *",
+    )
+    .bind(action.chat.id.0)
+    .bind(action.chat.title())
+    .bind(action.actor.map(|user| user.id.0 as i64))
+    .bind(action.actor.map(|user| user.full_name()))
+    .bind(action.target.0 as i64)
+    .bind(action.target_name)
+    .bind(action.action)
+    .bind(action.duration.map(|duration| duration.num_seconds()))
+    .bind(action.reason)
+    .fetch_one(pool)
+    .await
+}
+
+/// Lists the most recent actions taken against a user, in any chat, newest
+/// first.
+pub async fn recent_actions(
+    pool: &PgPool,
+    target: UserId,
+    limit: i64,
+) -> Result<Vec<ModerationRecord>, sqlx::Error> {
+    sqlx::query_as::<_, ModerationRecord>(
+        "SELECT * FROM moderation_actions WHERE target_id = $1
+         ORDER BY created_at DESC, id DESC LIMIT $2",
+    )
+    .bind(target.0 as i64)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+/// Formats a user's recent actions for `/modlog`, with times in the
+/// pharmacy's time zone.
+pub fn format_history(target_name: &str, records: &[ModerationRecord], timezone: Tz) -> String {
+    if records.is_empty() {
+        return format!("No moderation actions against {} on record.", target_name);
+    }
+
+    let lines = records
+        .iter()
+        .map(|record| {
+            format!(
+                "{} - {}",
+                record
+                    .created_at
+                    .with_timezone(&timezone)
+                    .format("%Y-%m-%d %H:%M"),
+                record.describe()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!(
+        "Recent moderation actions against {}:\n{}",
+        target_name, lines
+    )
+}
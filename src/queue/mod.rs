@@ -0,0 +1,576 @@
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use teloxide::{
+    prelude::*,
+    types::{MessageId, ParseMode, ThreadId},
+    ApiError, RequestError,
+};
+use tokio::{
+    sync::{mpsc, watch, Mutex},
+    task::JoinHandle,
+    time::{sleep_until, Instant},
+};
+
+use crate::metrics;
+
+/// A message waiting to be delivered by the outbound queue.
+///
+/// Messages are built with [`OutboundMessage::new`] and optionally decorated
+/// with a parse mode or a forum topic before being handed to
+/// [`MessageQueue::push`].
+#[derive(Debug, Clone)]
+pub struct OutboundMessage {
+    pub chat_id: ChatId,
+    pub text: String,
+    pub parse_mode: Option<ParseMode>,
+    pub thread_id: Option<ThreadId>,
+}
+
+impl OutboundMessage {
+    /// Creates a plain text message for the given chat.
+    pub fn new(chat_id: ChatId, text: impl Into<String>) -> Self {
+        Self {
+            chat_id,
+            text: text.into(),
+            parse_mode: None,
+            thread_id: None,
+        }
+    }
+
+    /// Sets the parse mode used when the message is sent.
+    pub fn parse_mode(mut self, parse_mode: ParseMode) -> Self {
+        self.parse_mode = Some(parse_mode);
+        self
+    }
+
+    /// Sets the forum topic the message is posted to; `None` posts to the
+    /// general thread.
+    pub fn thread_id(mut self, thread_id: Option<ThreadId>) -> Self {
+        self.thread_id = thread_id;
+        self
+    }
+}
+
+/// Throttling and retry settings for the outbound queue.
+///
+/// The defaults follow Telegram's documented limits: roughly 30 messages per
+/// second overall, one message per second to a private chat and 20 messages
+/// per minute to a group.
+#[derive(Debug, Clone)]
+pub struct QueueSettings {
+    /// Minimum delay between any two sends.
+    pub global_interval: Duration,
+    /// Minimum delay between two sends to the same private chat.
+    pub private_chat_interval: Duration,
+    /// Minimum delay between two sends to the same group or channel.
+    pub group_chat_interval: Duration,
+    /// Number of failed attempts after which a message is given up on.
+    pub max_attempts: i32,
+    /// How long the worker keeps sending after shutdown has been requested.
+    pub drain_timeout: Duration,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        Self {
+            global_interval: Duration::from_millis(35),
+            private_chat_interval: Duration::from_secs(1),
+            group_chat_interval: Duration::from_secs(3),
+            max_attempts: 5,
+            drain_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A persisted message as stored in the `outbound_messages` table.
+#[derive(sqlx::FromRow, Debug, Clone)]
+struct QueuedMessage {
+    id: i64,
+    chat_id: i64,
+    text: String,
+    parse_mode: Option<String>,
+    message_thread_id: Option<i32>,
+    attempts: i32,
+}
+
+impl QueuedMessage {
+    fn chat_id(&self) -> ChatId {
+        ChatId(self.chat_id)
+    }
+
+    fn thread_id(&self) -> Option<ThreadId> {
+        self.message_thread_id.map(|id| ThreadId(MessageId(id)))
+    }
+}
+
+/// Central queue for all outbound bot messages.
+///
+/// Every message is first written to the `outbound_messages` table and then
+/// handed to a single background worker, which sends messages one by one while
+/// respecting per-chat and global rate limits. Telegram's `retry_after` hints
+/// are honoured, transient failures are retried with backoff and anything
+/// still unsent when the bot stops is picked up again on the next start.
+///
+/// The queue is cheap to clone; all clones share the same worker.
+#[derive(Clone)]
+pub struct MessageQueue {
+    /// Where messages are persisted; `None` keeps them in memory only.
+    pool: Option<PgPool>,
+    /// The ID of the last message kept in memory only.
+    last_id: Arc<AtomicI64>,
+    sender: mpsc::UnboundedSender<QueuedMessage>,
+    shutdown: watch::Sender<bool>,
+    worker: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl MessageQueue {
+    /// Starts the queue worker.
+    ///
+    /// Messages left unsent by a previous run are loaded from the database
+    /// and scheduled before any new messages.
+    ///
+    /// # Arguments
+    ///
+    /// * `bot` - The Bot instance used to deliver messages.
+    /// * `pool` - The database connection pool used to persist messages.
+    /// * `settings` - Throttling and retry settings.
+    ///
+    /// # Returns
+    ///
+    /// Returns the queue handle, or an error if pending messages could not be loaded.
+    pub async fn start(
+        bot: Bot,
+        pool: PgPool,
+        settings: QueueSettings,
+    ) -> Result<Self, sqlx::Error> {
+        let pending = sqlx::query_as::<_, QueuedMessage>(
+            "SELECT id, chat_id, text, parse_mode, message_thread_id, attempts FROM outbound_messages
+             WHERE sent_at IS NULL AND failed_at IS NULL ORDER BY id",
+        )
+        .fetch_all(&pool)
+        .await?;
+
+        if !pending.is_empty() {
+            log::info!("Resuming {} unsent outbound messages", pending.len());
+        }
+
+        Ok(Self::spawn(bot, Some(pool), settings, pending.into()))
+    }
+
+    /// Starts a queue worker that keeps messages in memory only, for tests.
+    ///
+    /// Messages are delivered as usual, but anything unsent when the queue
+    /// stops is lost.
+    pub fn start_in_memory(bot: Bot, settings: QueueSettings) -> Self {
+        Self::spawn(bot, None, settings, VecDeque::new())
+    }
+
+    fn spawn(
+        bot: Bot,
+        pool: Option<PgPool>,
+        settings: QueueSettings,
+        pending: VecDeque<QueuedMessage>,
+    ) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (shutdown, shutdown_rx) = watch::channel(false);
+
+        let worker = Worker {
+            bot,
+            pool: pool.clone(),
+            throttle: Throttle::new(settings.clone()),
+            settings,
+            pending,
+        };
+        let handle = tokio::spawn(worker.run(receiver, shutdown_rx));
+
+        Self {
+            pool,
+            last_id: Arc::new(AtomicI64::new(0)),
+            sender,
+            shutdown,
+            worker: Arc::new(Mutex::new(Some(handle))),
+        }
+    }
+
+    /// Persists a message and schedules it for delivery.
+    ///
+    /// Returns once the message is stored; delivery happens in the background.
+    pub async fn push(&self, message: OutboundMessage) -> Result<(), sqlx::Error> {
+        let parse_mode = message
+            .parse_mode
+            .and_then(|mode| serde_json::to_value(mode).ok())
+            .and_then(|value| value.as_str().map(str::to_owned));
+
+        let queued = match &self.pool {
+            Some(pool) => {
+                let insert = sqlx::query_as::<_, QueuedMessage>(
+                    "INSERT INTO outbound_messages (chat_id, text, parse_mode, message_thread_id)
+                     VALUES ($1, $2, $3, $4)
+                     RETURNING id, chat_id, text, parse_mode, message_thread_id, attempts",
+                )
+                .bind(message.chat_id.0)
+                .bind(&message.text)
+                .bind(parse_mode)
+                .bind(message.thread_id.map(|thread| thread.0 .0))
+                .fetch_one(pool);
+                metrics::global().time_query("queue_push", insert).await?
+            }
+            None => QueuedMessage {
+                id: self.last_id.fetch_add(1, Ordering::Relaxed) + 1,
+                chat_id: message.chat_id.0,
+                text: message.text,
+                parse_mode,
+                message_thread_id: message.thread_id.map(|thread| thread.0 .0),
+                attempts: 0,
+            },
+        };
+
+        // If the worker is already shutting down the message stays in the
+        // database and is delivered after the next start.
+        if self.sender.send(queued).is_err() {
+            log::warn!("Outbound queue is closed; message kept for the next start");
+        }
+
+        Ok(())
+    }
+
+    /// Convenience wrapper around [`MessageQueue::push`] for plain text.
+    pub async fn send(&self, chat_id: ChatId, text: impl Into<String>) -> Result<(), sqlx::Error> {
+        self.push(OutboundMessage::new(chat_id, text)).await
+    }
+
+    /// Stops accepting new messages and waits for the worker to drain.
+    ///
+    /// The worker keeps sending for at most `drain_timeout`; messages that
+    /// could not be sent in time remain in the database.
+    pub async fn shutdown(&self) {
+        let _ = self.shutdown.send(true);
+        if let Some(handle) = self.worker.lock().await.take() {
+            if let Err(e) = handle.await {
+                log::error!("Outbound queue worker panicked: {}", e);
+            }
+        }
+    }
+}
+
+/// Tracks when the next message may be sent, globally and per chat.
+struct Throttle {
+    settings: QueueSettings,
+    global_ready: Instant,
+    chat_ready: HashMap<ChatId, Instant>,
+}
+
+impl Throttle {
+    fn new(settings: QueueSettings) -> Self {
+        Self {
+            settings,
+            global_ready: Instant::now(),
+            chat_ready: HashMap::new(),
+        }
+    }
+
+    /// Returns the earliest instant a message to `chat_id` may be sent.
+    fn ready_at(&self, chat_id: ChatId) -> Instant {
+        match self.chat_ready.get(&chat_id) {
+            Some(chat_ready) => (*chat_ready).max(self.global_ready),
+            None => self.global_ready,
+        }
+    }
+
+    /// Records a send to `chat_id` at `now`.
+    fn record_send(&mut self, chat_id: ChatId, now: Instant) {
+        let chat_interval = if chat_id.is_user() {
+            self.settings.private_chat_interval
+        } else {
+            self.settings.group_chat_interval
+        };
+        self.global_ready = now + self.settings.global_interval;
+        self.chat_ready.insert(chat_id, now + chat_interval);
+    }
+
+    /// Holds back messages to `chat_id` until `until`.
+    fn back_off(&mut self, chat_id: ChatId, until: Instant) {
+        let ready = self.chat_ready.entry(chat_id).or_insert(until);
+        *ready = (*ready).max(until);
+    }
+
+    /// Holds back all messages until `until`, as requested by a flood-wait error.
+    fn pause(&mut self, until: Instant) {
+        self.global_ready = self.global_ready.max(until);
+    }
+
+    /// Forgets chats whose interval has already elapsed.
+    fn prune(&mut self, now: Instant) {
+        self.chat_ready.retain(|_, ready| *ready > now);
+    }
+}
+
+/// The background task that owns the pending messages.
+struct Worker {
+    bot: Bot,
+    /// Where delivery outcomes are recorded; `None` for an in-memory queue.
+    pool: Option<PgPool>,
+    settings: QueueSettings,
+    throttle: Throttle,
+    pending: VecDeque<QueuedMessage>,
+}
+
+impl Worker {
+    async fn run(
+        mut self,
+        mut receiver: mpsc::UnboundedReceiver<QueuedMessage>,
+        mut shutdown: watch::Receiver<bool>,
+    ) {
+        let mut drain_deadline: Option<Instant> = None;
+
+        loop {
+            let now = Instant::now();
+
+            if let Some(deadline) = drain_deadline {
+                // Pick up whatever was pushed before the channel was closed.
+                while let Ok(message) = receiver.try_recv() {
+                    self.pending.push_back(message);
+                }
+                if self.pending.is_empty() {
+                    break;
+                }
+                if now >= deadline {
+                    log::warn!(
+                        "Outbound queue drain timed out; {} messages kept for the next start",
+                        self.pending.len()
+                    );
+                    break;
+                }
+            }
+
+            // Find the oldest message whose chat is ready to receive it.
+            // Scanning from the front keeps delivery in order within a chat.
+            let next_ready = self
+                .pending
+                .iter()
+                .enumerate()
+                .map(|(index, message)| (index, self.throttle.ready_at(message.chat_id())))
+                .min_by_key(|(_, ready)| *ready);
+
+            match next_ready {
+                Some((index, ready)) if ready <= now => {
+                    if let Some(message) = self.pending.remove(index) {
+                        self.deliver(message).await;
+                    }
+                    self.throttle.prune(now);
+                    continue;
+                }
+                _ => {}
+            }
+
+            let wake_at = match (next_ready, drain_deadline) {
+                (Some((_, ready)), Some(deadline)) => ready.min(deadline),
+                (Some((_, ready)), None) => ready,
+                (None, Some(deadline)) => deadline,
+                (None, None) => now + Duration::from_secs(3600),
+            };
+
+            tokio::select! {
+                message = receiver.recv(), if drain_deadline.is_none() => {
+                    match message {
+                        Some(message) => self.pending.push_back(message),
+                        // Every queue handle is gone; nothing new can arrive.
+                        None => drain_deadline = Some(Instant::now() + self.settings.drain_timeout),
+                    }
+                }
+                _ = shutdown.changed(), if drain_deadline.is_none() => {
+                    log::info!("Draining outbound queue ({} pending)", self.pending.len());
+                    receiver.close();
+                    drain_deadline = Some(Instant::now() + self.settings.drain_timeout);
+                }
+                _ = sleep_until(wake_at) => {}
+            }
+        }
+
+        log::info!("Outbound queue stopped");
+    }
+
+    /// Sends a single message and records the outcome.
+    async fn deliver(&mut self, mut message: QueuedMessage) {
+        let now = Instant::now();
+        self.throttle.record_send(message.chat_id(), now);
+
+        let mut request = self.bot.send_message(message.chat_id(), &message.text);
+        if let Some(mode) = message.parse_mode.as_deref().and_then(|m| m.parse().ok()) {
+            request = request.parse_mode(mode);
+        }
+        if let Some(thread_id) = message.thread_id() {
+            request = request.message_thread_id(thread_id);
+        }
+
+        let result = request.await;
+        if let Err(e) = &result {
+            metrics::global().api_error(e);
+        }
+
+        match result {
+            Ok(_) => {
+                metrics::global().notification_sent();
+                self.mark_sent(message.id).await;
+            }
+            Err(RequestError::RetryAfter(wait)) => {
+                // Flood control: wait as instructed and try again. This does
+                // not count as a failed attempt.
+                log::warn!("Telegram asked to retry after {}", wait);
+                self.throttle.pause(now + wait.duration());
+                self.pending.push_front(message);
+            }
+            Err(RequestError::MigrateToChatId(new_chat_id)) => {
+                log::info!("Chat {} migrated to {}", message.chat_id, new_chat_id);
+                message.chat_id = new_chat_id.0;
+                self.update_chat_id(&message).await;
+                self.pending.push_front(message);
+            }
+            Err(RequestError::Api(ApiError::Unknown(ref error)))
+                if message.message_thread_id.is_some()
+                    && error.contains("message thread not found") =>
+            {
+                // The forum topic was deleted or the group is no longer a
+                // forum; post to the general thread rather than losing the message.
+                log::warn!(
+                    "Topic {:?} of chat {} not found; sending to the general thread",
+                    message.message_thread_id,
+                    message.chat_id
+                );
+                message.message_thread_id = None;
+                self.clear_thread(&message).await;
+                self.pending.push_front(message);
+            }
+            Err(e @ RequestError::Api(_)) => {
+                // The request itself was rejected; retrying won't help.
+                log::error!("Outbound message {} rejected: {}", message.id, e);
+                metrics::global().notification_failed();
+                self.mark_failed(message.id, message.attempts + 1, &e.to_string())
+                    .await;
+            }
+            Err(e) => {
+                message.attempts += 1;
+                if message.attempts >= self.settings.max_attempts {
+                    log::error!(
+                        "Giving up on outbound message {} after {} attempts: {}",
+                        message.id,
+                        message.attempts,
+                        e
+                    );
+                    metrics::global().notification_failed();
+                    self.mark_failed(message.id, message.attempts, &e.to_string())
+                        .await;
+                } else {
+                    log::warn!(
+                        "Outbound message {} failed (attempt {}): {}",
+                        message.id,
+                        message.attempts,
+                        e
+                    );
+                    self.record_attempt(message.id, message.attempts, &e.to_string())
+                        .await;
+                    let backoff = Duration::from_secs(2u64.pow(message.attempts as u32));
+                    self.throttle.back_off(message.chat_id(), now + backoff);
+                    self.pending.push_front(message);
+                }
+            }
+        }
+    }
+
+    async fn mark_sent(&self, id: i64) {
+        let Some(pool) = &self.pool else {
+            return;
+        };
+        let sent_at: DateTime<Utc> = Utc::now();
+        if let Err(e) = sqlx::query("UPDATE outbound_messages SET sent_at = $1 WHERE id = $2")
+            .bind(sent_at)
+            .bind(id)
+            .execute(pool)
+            .await
+        {
+            log::error!("Failed to mark outbound message {} as sent: {}", id, e);
+        }
+    }
+
+    async fn mark_failed(&self, id: i64, attempts: i32, error: &str) {
+        let Some(pool) = &self.pool else {
+            return;
+        };
+        if let Err(e) = sqlx::query(
+            "UPDATE outbound_messages SET attempts = $1, last_error = $2, failed_at = $3 WHERE id = $4",
+        )
+        .bind(attempts)
+        .bind(error)
+        .bind(Utc::now())
+        .bind(id)
+        .execute(pool)
+        .await
+        {
+            log::error!("Failed to mark outbound message {} as failed: {}", id, e);
+        }
+    }
+
+    async fn record_attempt(&self, id: i64, attempts: i32, error: &str) {
+        let Some(pool) = &self.pool else {
+            return;
+        };
+        if let Err(e) =
+            sqlx::query("UPDATE outbound_messages SET attempts = $1, last_error = $2 WHERE id = $3")
+                .bind(attempts)
+                .bind(error)
+                .bind(id)
+                .execute(pool)
+                .await
+        {
+            log::error!(
+                "Failed to record attempt for outbound message {}: {}",
+                id,
+                e
+            );
+        }
+    }
+
+    async fn update_chat_id(&self, message: &QueuedMessage) {
+        let Some(pool) = &self.pool else {
+            return;
+        };
+        if let Err(e) = sqlx::query("UPDATE outbound_messages SET chat_id = $1 WHERE id = $2")
+            .bind(message.chat_id)
+            .bind(message.id)
+            .execute(pool)
+            .await
+        {
+            log::error!(
+                "Failed to update chat of outbound message {}: {}",
+                message.id,
+                e
+            );
+        }
+    }
+
+    async fn clear_thread(&self, message: &QueuedMessage) {
+        let Some(pool) = &self.pool else {
+            return;
+        };
+        if let Err(e) =
+            sqlx::query("UPDATE outbound_messages SET message_thread_id = NULL WHERE id = $1")
+                .bind(message.id)
+                .execute(pool)
+                .await
+        {
+            log::error!(
+                "Failed to clear topic of outbound message {}: {}",
+                message.id,
+                e
+            );
+        }
+    }
+}
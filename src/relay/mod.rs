@@ -0,0 +1,319 @@
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use teloxide::{
+    prelude::*,
+    types::{MessageId, ThreadId},
+};
+
+/// An anonymous conversation between a patient and a pharmacist.
+///
+/// Neither side ever learns the other's chat: every message is re-sent by
+/// the bot, and replies are matched to the conversation through the
+/// `relayed_messages` table.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct Conversation {
+    pub id: i64,
+    pub sender_chat_id: i64,
+    pub recipient_chat_id: i64,
+    pub created_at: DateTime<Utc>,
+    pub last_message_at: DateTime<Utc>,
+}
+
+impl Conversation {
+    /// The chat of the person who started the conversation.
+    pub fn sender(&self) -> ChatId {
+        ChatId(self.sender_chat_id)
+    }
+
+    /// The chat of the pharmacist the conversation was addressed to.
+    pub fn recipient(&self) -> ChatId {
+        ChatId(self.recipient_chat_id)
+    }
+
+    /// Returns the chat on the other side of the conversation from `chat_id`,
+    /// or `None` if `chat_id` doesn't take part in it.
+    pub fn counterpart(&self, chat_id: ChatId) -> Option<ChatId> {
+        if chat_id == self.recipient() {
+            Some(self.sender())
+        } else if chat_id == self.sender() {
+            Some(self.recipient())
+        } else {
+            None
+        }
+    }
+}
+
+/// Starts a new anonymous conversation with the message that opened it.
+///
+/// The conversation is only stored once its first message was delivered, and
+/// together with that message, so a failed delivery leaves nothing behind.
+///
+/// # Arguments
+///
+/// * `pool` - The database connection pool.
+/// * `sender` - The chat of the person writing anonymously.
+/// * `recipient` - The chat of the pharmacist receiving the message.
+/// * `message_id` - The ID of the delivered message in the recipient's chat.
+///
+/// # Returns
+///
+/// Returns the newly created conversation.
+pub async fn start_conversation(
+    pool: &PgPool,
+    sender: ChatId,
+    recipient: ChatId,
+    message_id: MessageId,
+) -> Result<Conversation, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let conversation = sqlx::query_as::<_, Conversation>(
+        "INSERT INTO anonymous_conversations (sender_chat_id, recipient_chat_id)
+         VALUES ($1, $2) RETURNING *",
+    )
+    .bind(sender.0)
+    .bind(recipient.0)
+    .fetch_one(&mut *tx)
+    .await?;
+    sqlx::query(
+        "INSERT INTO relayed_messages (chat_id, message_id, conversation_id) VALUES ($1, $2, $3)",
+    )
+    .bind(recipient.0)
+    .bind(message_id.0)
+    .bind(conversation.id)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(conversation)
+}
+
+/// Records a message the bot delivered as part of a conversation.
+///
+/// Replies to this message will be routed back through the conversation.
+///
+/// # Arguments
+///
+/// * `pool` - The database connection pool.
+/// * `conversation_id` - The conversation the message belongs to.
+/// * `chat_id` - The chat the message was delivered to.
+/// * `message_id` - The ID of the delivered message in that chat.
+pub async fn record_delivery(
+    pool: &PgPool,
+    conversation_id: i64,
+    chat_id: ChatId,
+    message_id: MessageId,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        "INSERT INTO relayed_messages (chat_id, message_id, conversation_id) VALUES ($1, $2, $3)",
+    )
+    .bind(chat_id.0)
+    .bind(message_id.0)
+    .bind(conversation_id)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("UPDATE anonymous_conversations SET last_message_at = NOW() WHERE id = $1")
+        .bind(conversation_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await
+}
+
+/// Looks up the conversation a relayed message belongs to.
+///
+/// # Arguments
+///
+/// * `pool` - The database connection pool.
+/// * `chat_id` - The chat the relayed message was delivered to.
+/// * `message_id` - The ID of the relayed message.
+///
+/// # Returns
+///
+/// Returns the conversation, or `None` if the message wasn't relayed by the bot.
+pub async fn find_by_message(
+    pool: &PgPool,
+    chat_id: ChatId,
+    message_id: MessageId,
+) -> Result<Option<Conversation>, sqlx::Error> {
+    sqlx::query_as::<_, Conversation>(
+        "SELECT c.* FROM anonymous_conversations c
+         JOIN relayed_messages r ON r.conversation_id = c.id
+         WHERE r.chat_id = $1 AND r.message_id = $2",
+    )
+    .bind(chat_id.0)
+    .bind(message_id.0)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Limits on the media that can be relayed anonymously.
+#[derive(Debug, Clone)]
+pub struct MediaPolicy {
+    /// Largest file that is relayed, in bytes.
+    pub max_file_size: u32,
+    /// MIME types accepted for documents, e.g. `application/pdf`.
+    pub allowed_document_types: Vec<String>,
+    /// Longest voice message that is relayed, in seconds.
+    pub max_voice_duration: u32,
+}
+
+/// Content of a message that passed the [`MediaPolicy`] checks.
+#[derive(Debug, Clone, Copy)]
+pub enum RelayContent<'a> {
+    /// A plain text message.
+    Text(&'a str),
+    /// A photo, document or voice message with its optional caption.
+    Media { caption: Option<&'a str> },
+}
+
+/// Maximum length of a media caption accepted by Telegram.
+const MAX_CAPTION_LENGTH: usize = 1024;
+
+/// Checks whether a message can be relayed and extracts its content.
+///
+/// Text, photos, documents of an allowed type and voice messages are
+/// accepted as long as they stay within the size and duration limits.
+///
+/// # Arguments
+///
+/// * `msg` - The message to relay.
+/// * `policy` - The media limits to enforce.
+///
+/// # Returns
+///
+/// Returns the content to relay, or a message explaining to the user why it
+/// was rejected.
+pub fn check_content<'a>(
+    msg: &'a Message,
+    policy: &MediaPolicy,
+) -> Result<RelayContent<'a>, String> {
+    let too_large = || {
+        format!(
+            "This file is too large. Please send files up to {} MB.",
+            policy.max_file_size / (1024 * 1024)
+        )
+    };
+
+    if let Some(text) = msg.text() {
+        return Ok(RelayContent::Text(text));
+    }
+
+    if let Some(sizes) = msg.photo() {
+        // Telegram sends several sizes of a photo; the largest one counts
+        let largest = sizes.iter().map(|size| size.file.size).max().unwrap_or(0);
+        if largest > policy.max_file_size {
+            return Err(too_large());
+        }
+    } else if let Some(document) = msg.document() {
+        let mime_type = document
+            .mime_type
+            .as_ref()
+            .map(|mime| mime.essence_str().to_string())
+            .unwrap_or_default();
+        if !policy
+            .allowed_document_types
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(&mime_type))
+        {
+            return Err(format!(
+                "This type of document can't be sent. Allowed types: {}.",
+                policy.allowed_document_types.join(", ")
+            ));
+        }
+        if document.file.size > policy.max_file_size {
+            return Err(too_large());
+        }
+    } else if let Some(voice) = msg.voice() {
+        if voice.duration.seconds() > policy.max_voice_duration {
+            return Err(format!(
+                "This voice message is too long. Please keep it under {} seconds.",
+                policy.max_voice_duration
+            ));
+        }
+        if voice.file.size > policy.max_file_size {
+            return Err(too_large());
+        }
+    } else {
+        return Err(
+            "Please send a text message, a photo, a document or a voice message.".to_string(),
+        );
+    }
+
+    Ok(RelayContent::Media {
+        caption: msg.caption(),
+    })
+}
+
+/// Delivers checked content to the other side of a conversation.
+///
+/// Text is re-sent by the bot. Media is copied with `copy_message`, which
+/// unlike forwarding doesn't reveal the original sender; its caption is
+/// replaced with the header, the original caption and the footer.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to deliver the message.
+/// * `msg` - The original message.
+/// * `content` - The content returned by [`check_content`].
+/// * `target` - The chat to deliver to.
+/// * `thread_id` - The forum topic to deliver to, or `None` for the general thread.
+/// * `header` - Text shown above the content.
+/// * `footer` - Text shown below the content.
+///
+/// # Returns
+///
+/// Returns the ID of the delivered message in the target chat.
+pub async fn deliver(
+    bot: &Bot,
+    msg: &Message,
+    content: RelayContent<'_>,
+    target: ChatId,
+    thread_id: Option<ThreadId>,
+    header: &str,
+    footer: &str,
+) -> ResponseResult<MessageId> {
+    match content {
+        RelayContent::Text(text) => {
+            let mut request =
+                bot.send_message(target, format!("{}\n\n{}\n\n{}", header, text, footer));
+            if let Some(thread_id) = thread_id {
+                request = request.message_thread_id(thread_id);
+            }
+            Ok(request.await?.id)
+        }
+        RelayContent::Media { caption } => {
+            let mut request = bot
+                .copy_message(target, msg.chat.id, msg.id)
+                .caption(compose_caption(header, caption, footer));
+            if let Some(thread_id) = thread_id {
+                request = request.message_thread_id(thread_id);
+            }
+            request.await
+        }
+    }
+}
+
+/// Builds a media caption that fits Telegram's caption limit, shortening the
+/// user's own caption if necessary.
+fn compose_caption(header: &str, caption: Option<&str>, footer: &str) -> String {
+    let Some(caption) = caption.filter(|c| !c.is_empty()) else {
+        return format!("{}\n\n{}", header, footer);
+    };
+
+    let overhead = header.chars().count() + footer.chars().count() + 4;
+    let available = MAX_CAPTION_LENGTH.saturating_sub(overhead);
+    let caption = if caption.chars().count() > available {
+        let shortened: String = caption.chars().take(available.saturating_sub(1)).collect();
+        format!("{}…", shortened)
+    } else {
+        caption.to_string()
+    };
+
+    format!("{}\n\n{}\n\n{}", header, caption, footer)
+}
+
+/// Finds a conversation by its ID.
+pub async fn find(pool: &PgPool, id: i64) -> Result<Option<Conversation>, sqlx::Error> {
+    sqlx::query_as::<_, Conversation>("SELECT * FROM anonymous_conversations WHERE id = $1")
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+}
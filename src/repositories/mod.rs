@@ -0,0 +1,1497 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use futures::future::BoxFuture;
+use sqlx::PgPool;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use teloxide::types::{ChatId, MessageId, User, UserId};
+
+use crate::{
+    links::{self, DeepLinkToken, TokenPolicy},
+    metrics,
+    models::{Medicine, Order},
+    modlog::{self, ModerationRecord, NewAction},
+    relay::{self, Conversation},
+    roles::{self, Role},
+    rota::{self, Duty, Shift},
+    tickets::{self, Ticket, TicketStatus},
+    users::{self, KnownUser},
+};
+
+/// The medicine repository handed to handlers and jobs.
+pub type Medicines = Arc<dyn MedicineRepo>;
+
+/// The order repository handed to handlers and jobs.
+pub type Orders = Arc<dyn OrderRepo>;
+
+/// The rota repository handed to handlers.
+pub type Rota = Arc<dyn RotaRepo>;
+
+/// The anonymous conversation repository handed to handlers.
+pub type Relay = Arc<dyn RelayRepo>;
+
+/// The consultation ticket repository handed to handlers.
+pub type Tickets = Arc<dyn TicketRepo>;
+
+/// The role repository handed to handlers.
+pub type Roles = Arc<dyn RoleRepo>;
+
+/// The known user repository handed to handlers.
+pub type Users = Arc<dyn UserRepo>;
+
+/// The deep-link token repository handed to handlers.
+pub type Links = Arc<dyn LinkRepo>;
+
+/// The moderation action repository behind the audit log.
+pub type ModLog = Arc<dyn ModLogRepo>;
+
+/// The repositories of the bot, handed to handlers and jobs as one
+/// dependency.
+#[derive(Clone)]
+pub struct Repositories {
+    pub medicines: Medicines,
+    pub orders: Orders,
+    pub rota: Rota,
+    pub relay: Relay,
+    pub tickets: Tickets,
+    pub roles: Roles,
+    pub users: Users,
+    pub links: Links,
+    pub modlog: ModLog,
+}
+
+impl Repositories {
+    /// The repositories backed by Postgres, used by the bot.
+    pub fn postgres(pool: PgPool) -> Self {
+        let store = Arc::new(PgStore::new(pool));
+        Self {
+            medicines: store.clone(),
+            orders: store.clone(),
+            rota: store.clone(),
+            relay: store.clone(),
+            tickets: store.clone(),
+            roles: store.clone(),
+            users: store.clone(),
+            links: store.clone(),
+            modlog: store,
+        }
+    }
+
+    /// The repositories kept in `store`, used by tests.
+    pub fn memory(store: MemoryStore) -> Self {
+        let store = Arc::new(store);
+        Self {
+            medicines: store.clone(),
+            orders: store.clone(),
+            rota: store.clone(),
+            relay: store.clone(),
+            tickets: store.clone(),
+            roles: store.clone(),
+            users: store.clone(),
+            links: store.clone(),
+            modlog: store,
+        }
+    }
+}
+
+/// Access to the medicines the pharmacy stocks.
+///
+/// Handlers and jobs only see this trait, so they can be run against
+/// [`MemoryStore`] in tests; the bot itself uses [`PgStore`].
+pub trait MedicineRepo: Send + Sync {
+    /// Every medicine, by name.
+    fn all(&self) -> BoxFuture<'_, Result<Vec<Medicine>, sqlx::Error>>;
+
+    /// The medicine with the given ID, if there is one.
+    fn find(&self, id: i32) -> BoxFuture<'_, Result<Option<Medicine>, sqlx::Error>>;
+
+    /// Medicines expiring on or before `date`.
+    fn expiring_by(&self, date: NaiveDate) -> BoxFuture<'_, Result<Vec<Medicine>, sqlx::Error>>;
+
+    /// Medicines with fewer than `threshold` units left, lowest stock first.
+    fn low_stock(&self, threshold: i32) -> BoxFuture<'_, Result<Vec<Medicine>, sqlx::Error>>;
+
+    /// Takes `quantity` units of a medicine out of stock.
+    ///
+    /// # Returns
+    ///
+    /// Returns `false`, leaving the stock untouched, if there is no such
+    /// medicine or not enough of it.
+    fn take_stock(&self, id: i32, quantity: i32) -> BoxFuture<'_, Result<bool, sqlx::Error>>;
+
+    /// Stores a medicine as given, e.g. from seed data.
+    fn insert(&self, medicine: Medicine) -> BoxFuture<'_, Result<(), sqlx::Error>>;
+}
+
+/// An order about to be placed; its ID is given when it is stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewOrder {
+    pub user_id: String,
+    pub medicine_id: i32,
+    pub quantity: i32,
+    pub created_at: NaiveDate,
+}
+
+/// The orders of a single day, for the daily report.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DailySummary {
+    pub order_count: i64,
+    pub total_quantity: i64,
+    /// The names of the most ordered medicines with the units ordered, most
+    /// ordered first.
+    pub top_medicines: Vec<(String, i64)>,
+}
+
+/// Access to customers' orders.
+pub trait OrderRepo: Send + Sync {
+    /// Takes the ordered units out of stock and stores a new pending order,
+    /// both or neither: `None` means there wasn't enough stock, or no such
+    /// medicine, and nothing was changed.
+    fn place(&self, order: NewOrder) -> BoxFuture<'_, Result<Option<Order>, sqlx::Error>>;
+
+    /// Summarises the orders placed on `day`, listing at most `top` medicines.
+    fn daily_summary(
+        &self,
+        day: NaiveDate,
+        top: i64,
+    ) -> BoxFuture<'_, Result<DailySummary, sqlx::Error>>;
+
+    /// The number of orders still pending.
+    fn count_pending(&self) -> BoxFuture<'_, Result<i64, sqlx::Error>>;
+
+    /// Stores an order as given, whatever its status, e.g. from seed data.
+    /// Customers' orders are placed with [`OrderRepo::place`].
+    fn insert(&self, order: Order) -> BoxFuture<'_, Result<(), sqlx::Error>>;
+}
+
+/// Access to the pharmacist rota, for routing orders and messages.
+pub trait RotaRepo: Send + Sync {
+    /// Who is on duty at `now`, reading the shifts in `timezone`.
+    fn on_duty(&self, timezone: Tz, now: DateTime<Utc>)
+        -> BoxFuture<'_, Result<Duty, sqlx::Error>>;
+}
+
+/// Access to anonymous conversations and the messages relayed in them.
+pub trait RelayRepo: Send + Sync {
+    /// Starts a conversation with the delivered message that opened it, both
+    /// or neither.
+    fn start_conversation(
+        &self,
+        sender: ChatId,
+        recipient: ChatId,
+        message_id: MessageId,
+    ) -> BoxFuture<'_, Result<Conversation, sqlx::Error>>;
+
+    /// Records a message delivered as part of a conversation, so replies to
+    /// it are routed back through the conversation.
+    fn record_delivery(
+        &self,
+        conversation_id: i64,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> BoxFuture<'_, Result<(), sqlx::Error>>;
+
+    /// The conversation a message delivered to `chat_id` belongs to, if the
+    /// bot relayed it.
+    fn find_by_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> BoxFuture<'_, Result<Option<Conversation>, sqlx::Error>>;
+}
+
+/// Access to the consultation tickets of anonymous conversations.
+pub trait TicketRepo: Send + Sync {
+    /// Opens a ticket for a new conversation, assigned to `assignee` or left
+    /// open for anyone to pick up.
+    fn open(
+        &self,
+        conversation_id: i64,
+        assignee: Option<ChatId>,
+    ) -> BoxFuture<'_, Result<Ticket, sqlx::Error>>;
+
+    /// The ticket of a conversation, if it has one.
+    fn find_by_conversation(
+        &self,
+        conversation_id: i64,
+    ) -> BoxFuture<'_, Result<Option<Ticket>, sqlx::Error>>;
+
+    /// Records that the pharmacist answered; the ticket now waits for the
+    /// patient.
+    fn record_pharmacist_reply(&self, id: i64) -> BoxFuture<'_, Result<(), sqlx::Error>>;
+
+    /// Records that the patient wrote again, reopening a closed ticket and
+    /// restarting the SLA timer.
+    fn record_patient_reply(&self, id: i64) -> BoxFuture<'_, Result<(), sqlx::Error>>;
+}
+
+/// Access to the roles of users.
+pub trait RoleRepo: Send + Sync {
+    /// The role of a user; users without an assignment are customers.
+    fn role_of(&self, user: UserId) -> BoxFuture<'_, Result<Role, sqlx::Error>>;
+}
+
+/// Access to the Telegram users the bot has seen.
+pub trait UserRepo: Send + Sync {
+    /// Records or refreshes a user; any other user holding the same username
+    /// loses it.
+    fn remember<'a>(&'a self, user: &'a User) -> BoxFuture<'a, Result<(), sqlx::Error>>;
+}
+
+/// Access to the deep-link tokens of anonymous links.
+pub trait LinkRepo: Send + Sync {
+    /// Creates a new token for `recipient`, limited by `policy`.
+    fn create_token(
+        &self,
+        recipient: ChatId,
+        policy: TokenPolicy,
+    ) -> BoxFuture<'_, Result<DeepLinkToken, sqlx::Error>>;
+
+    /// Redeems a token, counting one use; `None` if it can't be used.
+    fn redeem<'a>(&'a self, token: &'a str) -> BoxFuture<'a, Result<Option<ChatId>, sqlx::Error>>;
+
+    /// The recipient of a token that can still be used, without counting a
+    /// use.
+    fn recipient<'a>(
+        &'a self,
+        token: &'a str,
+    ) -> BoxFuture<'a, Result<Option<ChatId>, sqlx::Error>>;
+
+    /// The tokens of `recipient` that can still be used, oldest first.
+    fn list_active(
+        &self,
+        recipient: ChatId,
+    ) -> BoxFuture<'_, Result<Vec<DeepLinkToken>, sqlx::Error>>;
+
+    /// Revokes one of the recipient's tokens; `false` if it has no such
+    /// active token.
+    fn revoke<'a>(
+        &'a self,
+        recipient: ChatId,
+        token: &'a str,
+    ) -> BoxFuture<'a, Result<bool, sqlx::Error>>;
+
+    /// Revokes every token of the recipient, returning how many there were.
+    fn revoke_all(&self, recipient: ChatId) -> BoxFuture<'_, Result<u64, sqlx::Error>>;
+}
+
+/// Access to the moderation actions recorded in the audit log.
+pub trait ModLogRepo: Send + Sync {
+    /// Stores an action.
+    fn record<'a>(
+        &'a self,
+        action: NewAction<'a>,
+    ) -> BoxFuture<'a, Result<ModerationRecord, sqlx::Error>>;
+
+    /// The most recent actions taken against a user, in any chat, newest
+    /// first.
+    fn recent_actions(
+        &self,
+        target: UserId,
+        limit: i64,
+    ) -> BoxFuture<'_, Result<Vec<ModerationRecord>, sqlx::Error>>;
+}
+
+/// The repositories backed by Postgres.
+#[derive(Clone)]
+pub struct PgStore {
+    pool: PgPool,
+}
+
+impl PgStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+impl MedicineRepo for PgStore {
+    fn all(&self) -> BoxFuture<'_, Result<Vec<Medicine>, sqlx::Error>> {
+        Box::pin(async move {
+            let select = sqlx::query_as::<_, Medicine>("SELECT * FROM medicines ORDER BY name")
+                .fetch_all(&self.pool);
+            metrics::global().time_query("list_medicines", select).await
+        })
+    }
+
+    fn find(&self, id: i32) -> BoxFuture<'_, Result<Option<Medicine>, sqlx::Error>> {
+        Box::pin(async move {
+            let select = sqlx::query_as::<_, Medicine>("SELECT * FROM medicines WHERE id = $1")
+                .bind(id)
+                .fetch_optional(&self.pool);
+            metrics::global().time_query("find_medicine", select).await
+        })
+    }
+
+    fn expiring_by(&self, date: NaiveDate) -> BoxFuture<'_, Result<Vec<Medicine>, sqlx::Error>> {
+        Box::pin(async move {
+            let select = sqlx::query_as::<_, Medicine>(
+                "SELECT * FROM medicines WHERE expiry_date <= $1 ORDER BY expiry_date",
+            )
+            .bind(date)
+            .fetch_all(&self.pool);
+            metrics::global()
+                .time_query("expiring_medicines", select)
+                .await
+        })
+    }
+
+    fn low_stock(&self, threshold: i32) -> BoxFuture<'_, Result<Vec<Medicine>, sqlx::Error>> {
+        Box::pin(async move {
+            let select = sqlx::query_as::<_, Medicine>(
+                "SELECT * FROM medicines WHERE stock < $1 ORDER BY stock ASC",
+            )
+            .bind(threshold)
+            .fetch_all(&self.pool);
+            metrics::global()
+                .time_query("low_stock_medicines", select)
+                .await
+        })
+    }
+
+    fn take_stock(&self, id: i32, quantity: i32) -> BoxFuture<'_, Result<bool, sqlx::Error>> {
+        Box::pin(async move {
+            // Checking and taking in one statement, so two orders can't both
+            // take the last units
+            let update = sqlx::query(
+                "UPDATE medicines SET stock = stock - $1 WHERE id = $2 AND stock >= $1",
+            )
+            .bind(quantity)
+            .bind(id)
+            .execute(&self.pool);
+            let result = metrics::global().time_query("take_stock", update).await?;
+            Ok(result.rows_affected() > 0)
+        })
+    }
+
+    fn insert(&self, medicine: Medicine) -> BoxFuture<'_, Result<(), sqlx::Error>> {
+        Box::pin(async move {
+            let insert = sqlx::query(
+                "INSERT INTO medicines (id, name, stock, expiry_date) VALUES ($1, $2, $3, $4)",
+            )
+            .bind(medicine.id)
+            .bind(&medicine.name)
+            .bind(medicine.stock)
+            .bind(medicine.expiry_date)
+            .execute(&self.pool);
+            metrics::global()
+                .time_query("insert_medicine", insert)
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+impl OrderRepo for PgStore {
+    fn place(&self, order: NewOrder) -> BoxFuture<'_, Result<Option<Order>, sqlx::Error>> {
+        let place = async move {
+            let mut tx = self.pool.begin().await?;
+            // Checking and taking in one statement, so two orders can't both
+            // take the last units; the stock is given back if the insert fails
+            let taken = sqlx::query(
+                "UPDATE medicines SET stock = stock - $1 WHERE id = $2 AND stock >= $1",
+            )
+            .bind(order.quantity)
+            .bind(order.medicine_id)
+            .execute(&mut *tx)
+            .await?;
+            if taken.rows_affected() == 0 {
+                return Ok(None);
+            }
+
+            let order = sqlx::query_as::<_, Order>(
+                "INSERT INTO orders (user_id, medicine_id, quantity, status, created_at)
+                 VALUES ($1, $2, $3, 'pending', $4) RETURNING *",
+            )
+            .bind(&order.user_id)
+            .bind(order.medicine_id)
+            .bind(order.quantity)
+            .bind(order.created_at)
+            .fetch_one(&mut *tx)
+            .await?;
+            tx.commit().await?;
+            Ok(Some(order))
+        };
+        Box::pin(metrics::global().time_query("place_order", place))
+    }
+
+    fn daily_summary(
+        &self,
+        day: NaiveDate,
+        top: i64,
+    ) -> BoxFuture<'_, Result<DailySummary, sqlx::Error>> {
+        let summary = async move {
+            let (order_count, total_quantity): (i64, i64) = sqlx::query_as(
+                "SELECT COUNT(*), COALESCE(SUM(quantity), 0)::BIGINT FROM orders WHERE created_at = $1",
+            )
+            .bind(day)
+            .fetch_one(&self.pool)
+            .await?;
+
+            let top_medicines = sqlx::query_as(
+                "SELECT m.name, SUM(o.quantity)::BIGINT AS total
+                 FROM orders o JOIN medicines m ON m.id = o.medicine_id
+                 WHERE o.created_at = $1
+                 GROUP BY m.name ORDER BY total DESC LIMIT $2",
+            )
+            .bind(day)
+            .bind(top)
+            .fetch_all(&self.pool)
+            .await?;
+
+            Ok(DailySummary {
+                order_count,
+                total_quantity,
+                top_medicines,
+            })
+        };
+        Box::pin(metrics::global().time_query("daily_summary", summary))
+    }
+
+    fn count_pending(&self) -> BoxFuture<'_, Result<i64, sqlx::Error>> {
+        Box::pin(async move {
+            let count =
+                sqlx::query_as("SELECT COUNT(*) FROM orders WHERE LOWER(status) = 'pending'")
+                    .fetch_one(&self.pool);
+            let (pending,): (i64,) = metrics::global()
+                .time_query("count_pending_orders", count)
+                .await?;
+            Ok(pending)
+        })
+    }
+
+    fn insert(&self, order: Order) -> BoxFuture<'_, Result<(), sqlx::Error>> {
+        Box::pin(async move {
+            let insert = sqlx::query(
+                "INSERT INTO orders (id, user_id, medicine_id, quantity, status, created_at)
+                 VALUES ($1, $2, $3, $4, $5, $6)",
+            )
+            .bind(order.id)
+            .bind(&order.user_id)
+            .bind(order.medicine_id)
+            .bind(order.quantity)
+            .bind(&order.status)
+            .bind(order.created_at)
+            .execute(&self.pool);
+            metrics::global().time_query("insert_order", insert).await?;
+
+            // Keep the IDs given to placed orders clear of the ones given here
+            let advance = sqlx::query(
+                "SELECT setval(pg_get_serial_sequence('orders', 'id'), MAX(id)) FROM orders",
+            )
+            .execute(&self.pool);
+            metrics::global()
+                .time_query("advance_order_ids", advance)
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+impl RotaRepo for PgStore {
+    fn on_duty(
+        &self,
+        timezone: Tz,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'_, Result<Duty, sqlx::Error>> {
+        Box::pin(async move {
+            let lookup = rota::on_duty(&self.pool, timezone, now);
+            metrics::global().time_query("on_duty", lookup).await
+        })
+    }
+}
+
+impl RelayRepo for PgStore {
+    fn start_conversation(
+        &self,
+        sender: ChatId,
+        recipient: ChatId,
+        message_id: MessageId,
+    ) -> BoxFuture<'_, Result<Conversation, sqlx::Error>> {
+        Box::pin(async move {
+            let start = relay::start_conversation(&self.pool, sender, recipient, message_id);
+            metrics::global()
+                .time_query("start_conversation", start)
+                .await
+        })
+    }
+
+    fn record_delivery(
+        &self,
+        conversation_id: i64,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> BoxFuture<'_, Result<(), sqlx::Error>> {
+        Box::pin(async move {
+            let record = relay::record_delivery(&self.pool, conversation_id, chat_id, message_id);
+            metrics::global()
+                .time_query("record_delivery", record)
+                .await
+        })
+    }
+
+    fn find_by_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> BoxFuture<'_, Result<Option<Conversation>, sqlx::Error>> {
+        Box::pin(async move {
+            let lookup = relay::find_by_message(&self.pool, chat_id, message_id);
+            metrics::global()
+                .time_query("find_relayed_message", lookup)
+                .await
+        })
+    }
+}
+
+impl TicketRepo for PgStore {
+    fn open(
+        &self,
+        conversation_id: i64,
+        assignee: Option<ChatId>,
+    ) -> BoxFuture<'_, Result<Ticket, sqlx::Error>> {
+        Box::pin(async move {
+            let open = tickets::open_ticket(&self.pool, conversation_id, assignee);
+            metrics::global().time_query("open_ticket", open).await
+        })
+    }
+
+    fn find_by_conversation(
+        &self,
+        conversation_id: i64,
+    ) -> BoxFuture<'_, Result<Option<Ticket>, sqlx::Error>> {
+        Box::pin(async move {
+            let lookup = tickets::find_by_conversation(&self.pool, conversation_id);
+            metrics::global()
+                .time_query("find_conversation_ticket", lookup)
+                .await
+        })
+    }
+
+    fn record_pharmacist_reply(&self, id: i64) -> BoxFuture<'_, Result<(), sqlx::Error>> {
+        Box::pin(async move {
+            let record = tickets::record_pharmacist_reply(&self.pool, id);
+            metrics::global()
+                .time_query("record_pharmacist_reply", record)
+                .await
+        })
+    }
+
+    fn record_patient_reply(&self, id: i64) -> BoxFuture<'_, Result<(), sqlx::Error>> {
+        Box::pin(async move {
+            let record = tickets::record_patient_reply(&self.pool, id);
+            metrics::global()
+                .time_query("record_patient_reply", record)
+                .await
+        })
+    }
+}
+
+impl RoleRepo for PgStore {
+    fn role_of(&self, user: UserId) -> BoxFuture<'_, Result<Role, sqlx::Error>> {
+        Box::pin(async move {
+            let lookup = roles::role_of(&self.pool, user);
+            metrics::global().time_query("role_of", lookup).await
+        })
+    }
+}
+
+impl UserRepo for PgStore {
+    fn remember<'a>(&'a self, user: &'a User) -> BoxFuture<'a, Result<(), sqlx::Error>> {
+        Box::pin(async move {
+            let remember = users::remember(&self.pool, user);
+            metrics::global()
+                .time_query("remember_user", remember)
+                .await
+        })
+    }
+}
+
+impl LinkRepo for PgStore {
+    fn create_token(
+        &self,
+        recipient: ChatId,
+        policy: TokenPolicy,
+    ) -> BoxFuture<'_, Result<DeepLinkToken, sqlx::Error>> {
+        Box::pin(async move {
+            let create = links::create_token(&self.pool, recipient, policy);
+            metrics::global().time_query("create_token", create).await
+        })
+    }
+
+    fn redeem<'a>(&'a self, token: &'a str) -> BoxFuture<'a, Result<Option<ChatId>, sqlx::Error>> {
+        Box::pin(async move {
+            let redeem = links::redeem(&self.pool, token);
+            metrics::global().time_query("redeem_token", redeem).await
+        })
+    }
+
+    fn recipient<'a>(
+        &'a self,
+        token: &'a str,
+    ) -> BoxFuture<'a, Result<Option<ChatId>, sqlx::Error>> {
+        Box::pin(async move {
+            let lookup = links::recipient(&self.pool, token);
+            metrics::global()
+                .time_query("token_recipient", lookup)
+                .await
+        })
+    }
+
+    fn list_active(
+        &self,
+        recipient: ChatId,
+    ) -> BoxFuture<'_, Result<Vec<DeepLinkToken>, sqlx::Error>> {
+        Box::pin(async move {
+            let select = links::list_active(&self.pool, recipient);
+            metrics::global().time_query("list_tokens", select).await
+        })
+    }
+
+    fn revoke<'a>(
+        &'a self,
+        recipient: ChatId,
+        token: &'a str,
+    ) -> BoxFuture<'a, Result<bool, sqlx::Error>> {
+        Box::pin(async move {
+            let revoke = links::revoke(&self.pool, recipient, token);
+            metrics::global().time_query("revoke_token", revoke).await
+        })
+    }
+
+    fn revoke_all(&self, recipient: ChatId) -> BoxFuture<'_, Result<u64, sqlx::Error>> {
+        Box::pin(async move {
+            let revoke = links::revoke_all(&self.pool, recipient);
+            metrics::global()
+                .time_query("revoke_all_tokens", revoke)
+                .await
+        })
+    }
+}
+
+impl ModLogRepo for PgStore {
+    fn record<'a>(
+        &'a self,
+        action: NewAction<'a>,
+    ) -> BoxFuture<'a, Result<ModerationRecord, sqlx::Error>> {
+        Box::pin(async move {
+            let insert = modlog::record(&self.pool, action);
+            metrics::global()
+                .time_query("record_moderation_action", insert)
+                .await
+        })
+    }
+
+    fn recent_actions(
+        &self,
+        target: UserId,
+        limit: i64,
+    ) -> BoxFuture<'_, Result<Vec<ModerationRecord>, sqlx::Error>> {
+        Box::pin(async move {
+            let select = modlog::recent_actions(&self.pool, target, limit);
+            metrics::global()
+                .time_query("recent_moderation_actions", select)
+                .await
+        })
+    }
+}
+
+#[derive(Default)]
+struct Tables {
+    medicines: HashMap<i32, Medicine>,
+    orders: Vec<Order>,
+    shifts: Vec<Shift>,
+    conversations: Vec<Conversation>,
+    /// The conversation of each relayed message, by chat and message ID.
+    relayed_messages: HashMap<(ChatId, MessageId), i64>,
+    tickets: Vec<Ticket>,
+    roles: HashMap<UserId, Role>,
+    known_users: HashMap<UserId, KnownUser>,
+    tokens: Vec<DeepLinkToken>,
+    moderation_actions: Vec<ModerationRecord>,
+}
+
+/// The repositories kept in memory, for tests.
+///
+/// Clones share the same data, so a test can keep one to inspect what a
+/// handler did with another.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    tables: Arc<Mutex<Tables>>,
+}
+
+impl MemoryStore {
+    /// Creates a store holding the given medicines and no orders.
+    pub fn with_medicines(medicines: impl IntoIterator<Item = Medicine>) -> Self {
+        let store = Self::default();
+        store.lock().medicines = medicines
+            .into_iter()
+            .map(|medicine| (medicine.id, medicine))
+            .collect();
+        store
+    }
+
+    /// Adds a shift to the rota.
+    pub fn add_shift(&self, shift: Shift) {
+        self.lock().shifts.push(shift);
+    }
+
+    /// Gives a user a role, replacing any previous one.
+    pub fn set_role(&self, user: UserId, role: Role) {
+        self.lock().roles.insert(user, role);
+    }
+
+    /// Every consultation ticket opened so far, oldest first.
+    pub fn tickets(&self) -> Vec<Ticket> {
+        self.lock().tickets.clone()
+    }
+
+    /// Every moderation action recorded so far, oldest first.
+    pub fn moderation_actions(&self) -> Vec<ModerationRecord> {
+        self.lock().moderation_actions.clone()
+    }
+
+    /// Every order placed so far, oldest first.
+    pub fn orders(&self) -> Vec<Order> {
+        self.lock().orders.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Tables> {
+        // A test that panicked while holding the lock has failed already
+        self.tables.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn medicines_where(&self, keep: impl Fn(&Medicine) -> bool) -> Vec<Medicine> {
+        let mut medicines: Vec<Medicine> = self
+            .lock()
+            .medicines
+            .values()
+            .filter(|medicine| keep(medicine))
+            .cloned()
+            .collect();
+        medicines.sort_by(|a, b| a.name.cmp(&b.name));
+        medicines
+    }
+}
+
+impl MedicineRepo for MemoryStore {
+    fn all(&self) -> BoxFuture<'_, Result<Vec<Medicine>, sqlx::Error>> {
+        Box::pin(async move { Ok(self.medicines_where(|_| true)) })
+    }
+
+    fn find(&self, id: i32) -> BoxFuture<'_, Result<Option<Medicine>, sqlx::Error>> {
+        Box::pin(async move { Ok(self.lock().medicines.get(&id).cloned()) })
+    }
+
+    fn expiring_by(&self, date: NaiveDate) -> BoxFuture<'_, Result<Vec<Medicine>, sqlx::Error>> {
+        Box::pin(async move {
+            let mut medicines = self.medicines_where(|medicine| medicine.expiry_date <= date);
+            medicines.sort_by_key(|medicine| medicine.expiry_date);
+            Ok(medicines)
+        })
+    }
+
+    fn low_stock(&self, threshold: i32) -> BoxFuture<'_, Result<Vec<Medicine>, sqlx::Error>> {
+        Box::pin(async move {
+            let mut medicines = self.medicines_where(|medicine| medicine.stock < threshold);
+            medicines.sort_by_key(|medicine| medicine.stock);
+            Ok(medicines)
+        })
+    }
+
+    fn take_stock(&self, id: i32, quantity: i32) -> BoxFuture<'_, Result<bool, sqlx::Error>> {
+        Box::pin(async move {
+            let mut tables = self.lock();
+            match tables.medicines.get_mut(&id) {
+                Some(medicine) if medicine.stock >= quantity => {
+                    medicine.stock -= quantity;
+                    Ok(true)
+                }
+                _ => Ok(false),
+            }
+        })
+    }
+
+    fn insert(&self, medicine: Medicine) -> BoxFuture<'_, Result<(), sqlx::Error>> {
+        Box::pin(async move {
+            self.lock().medicines.insert(medicine.id, medicine);
+            Ok(())
+        })
+    }
+}
+
+impl OrderRepo for MemoryStore {
+    fn place(&self, order: NewOrder) -> BoxFuture<'_, Result<Option<Order>, sqlx::Error>> {
+        Box::pin(async move {
+            let mut tables = self.lock();
+            match tables.medicines.get_mut(&order.medicine_id) {
+                Some(medicine) if medicine.stock >= order.quantity => {
+                    medicine.stock -= order.quantity;
+                }
+                _ => return Ok(None),
+            }
+
+            let order = Order {
+                id: tables
+                    .orders
+                    .iter()
+                    .map(|order| order.id)
+                    .max()
+                    .unwrap_or(0)
+                    + 1,
+                user_id: order.user_id,
+                medicine_id: order.medicine_id,
+                quantity: order.quantity,
+                status: "pending".to_string(),
+                created_at: order.created_at,
+            };
+            tables.orders.push(order.clone());
+            Ok(Some(order))
+        })
+    }
+
+    fn daily_summary(
+        &self,
+        day: NaiveDate,
+        top: i64,
+    ) -> BoxFuture<'_, Result<DailySummary, sqlx::Error>> {
+        Box::pin(async move {
+            let tables = self.lock();
+            let orders: Vec<&Order> = tables
+                .orders
+                .iter()
+                .filter(|order| order.created_at == day)
+                .collect();
+
+            let mut totals: HashMap<String, i64> = HashMap::new();
+            for order in &orders {
+                if let Some(medicine) = tables.medicines.get(&order.medicine_id) {
+                    *totals.entry(medicine.name.clone()).or_default() += order.quantity as i64;
+                }
+            }
+            let mut top_medicines: Vec<(String, i64)> = totals.into_iter().collect();
+            top_medicines.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            top_medicines.truncate(top.max(0) as usize);
+
+            Ok(DailySummary {
+                order_count: orders.len() as i64,
+                total_quantity: orders.iter().map(|order| order.quantity as i64).sum(),
+                top_medicines,
+            })
+        })
+    }
+
+    fn count_pending(&self) -> BoxFuture<'_, Result<i64, sqlx::Error>> {
+        Box::pin(async move {
+            let pending = self
+                .lock()
+                .orders
+                .iter()
+                .filter(|order| order.status.eq_ignore_ascii_case("pending"))
+                .count();
+            Ok(pending as i64)
+        })
+    }
+
+    fn insert(&self, order: Order) -> BoxFuture<'_, Result<(), sqlx::Error>> {
+        Box::pin(async move {
+            self.lock().orders.push(order);
+            Ok(())
+        })
+    }
+}
+
+impl RotaRepo for MemoryStore {
+    fn on_duty(
+        &self,
+        timezone: Tz,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'_, Result<Duty, sqlx::Error>> {
+        Box::pin(async move {
+            Ok(rota::duty_in(
+                &self.lock().shifts,
+                now.with_timezone(&timezone),
+            ))
+        })
+    }
+}
+
+impl RelayRepo for MemoryStore {
+    fn start_conversation(
+        &self,
+        sender: ChatId,
+        recipient: ChatId,
+        message_id: MessageId,
+    ) -> BoxFuture<'_, Result<Conversation, sqlx::Error>> {
+        Box::pin(async move {
+            let mut tables = self.lock();
+            let now = Utc::now();
+            let conversation = Conversation {
+                id: tables.conversations.len() as i64 + 1,
+                sender_chat_id: sender.0,
+                recipient_chat_id: recipient.0,
+                created_at: now,
+                last_message_at: now,
+            };
+            tables.conversations.push(conversation.clone());
+            tables
+                .relayed_messages
+                .insert((recipient, message_id), conversation.id);
+            Ok(conversation)
+        })
+    }
+
+    fn record_delivery(
+        &self,
+        conversation_id: i64,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> BoxFuture<'_, Result<(), sqlx::Error>> {
+        Box::pin(async move {
+            let mut tables = self.lock();
+            tables
+                .relayed_messages
+                .insert((chat_id, message_id), conversation_id);
+            if let Some(conversation) = tables
+                .conversations
+                .iter_mut()
+                .find(|conversation| conversation.id == conversation_id)
+            {
+                conversation.last_message_at = Utc::now();
+            }
+            Ok(())
+        })
+    }
+
+    fn find_by_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> BoxFuture<'_, Result<Option<Conversation>, sqlx::Error>> {
+        Box::pin(async move {
+            let tables = self.lock();
+            let conversation = tables
+                .relayed_messages
+                .get(&(chat_id, message_id))
+                .and_then(|&id| tables.conversations.iter().find(|c| c.id == id))
+                .cloned();
+            Ok(conversation)
+        })
+    }
+}
+
+impl TicketRepo for MemoryStore {
+    fn open(
+        &self,
+        conversation_id: i64,
+        assignee: Option<ChatId>,
+    ) -> BoxFuture<'_, Result<Ticket, sqlx::Error>> {
+        Box::pin(async move {
+            let mut tables = self.lock();
+            let now = Utc::now();
+            let ticket = Ticket {
+                id: tables.tickets.len() as i64 + 1,
+                conversation_id,
+                status: match assignee {
+                    Some(_) => TicketStatus::Assigned,
+                    None => TicketStatus::Open,
+                },
+                assigned_chat_id: assignee.map(|chat| chat.0),
+                created_at: now,
+                updated_at: now,
+                waiting_since: now,
+                first_response_at: None,
+                closed_at: None,
+                sla_alerted_at: None,
+            };
+            tables.tickets.push(ticket.clone());
+            Ok(ticket)
+        })
+    }
+
+    fn find_by_conversation(
+        &self,
+        conversation_id: i64,
+    ) -> BoxFuture<'_, Result<Option<Ticket>, sqlx::Error>> {
+        Box::pin(async move {
+            let ticket = self
+                .lock()
+                .tickets
+                .iter()
+                .find(|ticket| ticket.conversation_id == conversation_id)
+                .cloned();
+            Ok(ticket)
+        })
+    }
+
+    fn record_pharmacist_reply(&self, id: i64) -> BoxFuture<'_, Result<(), sqlx::Error>> {
+        Box::pin(async move {
+            let mut tables = self.lock();
+            let now = Utc::now();
+            if let Some(ticket) = tables
+                .tickets
+                .iter_mut()
+                .find(|ticket| ticket.id == id && ticket.status != TicketStatus::Closed)
+            {
+                ticket.status = TicketStatus::AwaitingPatient;
+                ticket.updated_at = now;
+                ticket.first_response_at.get_or_insert(now);
+            }
+            Ok(())
+        })
+    }
+
+    fn record_patient_reply(&self, id: i64) -> BoxFuture<'_, Result<(), sqlx::Error>> {
+        Box::pin(async move {
+            let mut tables = self.lock();
+            let now = Utc::now();
+            if let Some(ticket) = tables.tickets.iter_mut().find(|ticket| ticket.id == id) {
+                ticket.status = match ticket.assigned_chat_id {
+                    Some(_) => TicketStatus::Assigned,
+                    None => TicketStatus::Open,
+                };
+                ticket.updated_at = now;
+                ticket.waiting_since = now;
+                ticket.closed_at = None;
+                ticket.sla_alerted_at = None;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl RoleRepo for MemoryStore {
+    fn role_of(&self, user: UserId) -> BoxFuture<'_, Result<Role, sqlx::Error>> {
+        Box::pin(async move {
+            let role = self.lock().roles.get(&user).copied();
+            Ok(role.unwrap_or(Role::Customer))
+        })
+    }
+}
+
+impl UserRepo for MemoryStore {
+    fn remember<'a>(&'a self, user: &'a User) -> BoxFuture<'a, Result<(), sqlx::Error>> {
+        Box::pin(async move {
+            let mut tables = self.lock();
+            if let Some(username) = &user.username {
+                for known in tables.known_users.values_mut() {
+                    let taken = known
+                        .username
+                        .as_ref()
+                        .is_some_and(|name| name.eq_ignore_ascii_case(username));
+                    if taken && known.id() != user.id {
+                        known.username = None;
+                    }
+                }
+            }
+            tables.known_users.insert(
+                user.id,
+                KnownUser {
+                    user_id: user.id.0 as i64,
+                    username: user.username.clone(),
+                    first_name: user.first_name.clone(),
+                    last_name: user.last_name.clone(),
+                    last_seen_at: Utc::now(),
+                },
+            );
+            Ok(())
+        })
+    }
+}
+
+impl LinkRepo for MemoryStore {
+    fn create_token(
+        &self,
+        recipient: ChatId,
+        policy: TokenPolicy,
+    ) -> BoxFuture<'_, Result<DeepLinkToken, sqlx::Error>> {
+        Box::pin(async move {
+            let now = Utc::now();
+            let token = DeepLinkToken {
+                token: links::generate_token(),
+                recipient_chat_id: recipient.0,
+                created_at: now,
+                expires_at: policy.ttl.map(|ttl| now + ttl),
+                max_uses: policy.max_uses,
+                use_count: 0,
+                revoked_at: None,
+            };
+            self.lock().tokens.push(token.clone());
+            Ok(token)
+        })
+    }
+
+    fn redeem<'a>(&'a self, token: &'a str) -> BoxFuture<'a, Result<Option<ChatId>, sqlx::Error>> {
+        Box::pin(async move {
+            let now = Utc::now();
+            let mut tables = self.lock();
+            let redeemed = tables
+                .tokens
+                .iter_mut()
+                .find(|link| link.token == token && link.is_active(now))
+                .map(|link| {
+                    link.use_count += 1;
+                    ChatId(link.recipient_chat_id)
+                });
+            Ok(redeemed)
+        })
+    }
+
+    fn recipient<'a>(
+        &'a self,
+        token: &'a str,
+    ) -> BoxFuture<'a, Result<Option<ChatId>, sqlx::Error>> {
+        Box::pin(async move {
+            let now = Utc::now();
+            let recipient = self
+                .lock()
+                .tokens
+                .iter()
+                .find(|link| link.token == token && link.is_active(now))
+                .map(|link| ChatId(link.recipient_chat_id));
+            Ok(recipient)
+        })
+    }
+
+    fn list_active(
+        &self,
+        recipient: ChatId,
+    ) -> BoxFuture<'_, Result<Vec<DeepLinkToken>, sqlx::Error>> {
+        Box::pin(async move {
+            let now = Utc::now();
+            let tokens = self
+                .lock()
+                .tokens
+                .iter()
+                .filter(|link| link.recipient_chat_id == recipient.0 && link.is_active(now))
+                .cloned()
+                .collect();
+            Ok(tokens)
+        })
+    }
+
+    fn revoke<'a>(
+        &'a self,
+        recipient: ChatId,
+        token: &'a str,
+    ) -> BoxFuture<'a, Result<bool, sqlx::Error>> {
+        Box::pin(async move {
+            let mut tables = self.lock();
+            let link = tables.tokens.iter_mut().find(|link| {
+                link.token == token
+                    && link.recipient_chat_id == recipient.0
+                    && link.revoked_at.is_none()
+            });
+            Ok(match link {
+                Some(link) => {
+                    link.revoked_at = Some(Utc::now());
+                    true
+                }
+                None => false,
+            })
+        })
+    }
+
+    fn revoke_all(&self, recipient: ChatId) -> BoxFuture<'_, Result<u64, sqlx::Error>> {
+        Box::pin(async move {
+            let now = Utc::now();
+            let mut revoked = 0;
+            for link in self.lock().tokens.iter_mut() {
+                if link.recipient_chat_id == recipient.0 && link.revoked_at.is_none() {
+                    link.revoked_at = Some(now);
+                    revoked += 1;
+                }
+            }
+            Ok(revoked)
+        })
+    }
+}
+
+impl ModLogRepo for MemoryStore {
+    fn record<'a>(
+        &'a self,
+        action: NewAction<'a>,
+    ) -> BoxFuture<'a, Result<ModerationRecord, sqlx::Error>> {
+        Box::pin(async move {
+            let mut tables = self.lock();
+            let record = ModerationRecord {
+                id: tables.moderation_actions.len() as i64 + 1,
+                chat_id: action.chat.id.0,
+                chat_title: action.chat.title().map(str::to_owned),
+                actor_id: action.actor.map(|user| user.id.0 as i64),
+                actor_name: action.actor.map(|user| user.full_name()),
+                target_id: action.target.0 as i64,
+                target_name: action.target_name.to_owned(),
+                action: action.action,
+                duration_seconds: action.duration.map(|duration| duration.num_seconds()),
+                reason: action.reason.map(str::to_owned),
+                created_at: Utc::now(),
+            };
+            tables.moderation_actions.push(record.clone());
+            Ok(record)
+        })
+    }
+
+    fn recent_actions(
+        &self,
+        target: UserId,
+        limit: i64,
+    ) -> BoxFuture<'_, Result<Vec<ModerationRecord>, sqlx::Error>> {
+        Box::pin(async move {
+            let records = self
+                .lock()
+                .moderation_actions
+                .iter()
+                .rev()
+                .filter(|record| record.target_id == target.0 as i64)
+                .take(limit as usize)
+                .cloned()
+                .collect();
+            Ok(records)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveTime;
+    use teloxide::types::ChatId;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn medicine(id: i32, name: &str, stock: i32, expiry_date: NaiveDate) -> Medicine {
+        Medicine {
+            id,
+            name: name.to_string(),
+            stock,
+            expiry_date,
+        }
+    }
+
+    fn store() -> MemoryStore {
+        MemoryStore::with_medicines([
+            medicine(1, "Paracetamol", 40, date(2025, 3, 1)),
+            medicine(2, "Ibuprofen", 5, date(2026, 1, 1)),
+            medicine(3, "Amoxicillin", 2, date(2025, 1, 1)),
+        ])
+    }
+
+    fn order(medicine_id: i32, quantity: i32, created_at: NaiveDate) -> NewOrder {
+        NewOrder {
+            user_id: "42".to_string(),
+            medicine_id,
+            quantity,
+            created_at,
+        }
+    }
+
+    #[tokio::test]
+    async fn low_stock_lists_the_emptiest_first() {
+        let names: Vec<String> = store()
+            .low_stock(10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|medicine| medicine.name)
+            .collect();
+
+        assert_eq!(names, ["Amoxicillin", "Ibuprofen"]);
+    }
+
+    #[tokio::test]
+    async fn expiring_by_includes_the_day_itself() {
+        let ids: Vec<i32> = store()
+            .expiring_by(date(2025, 3, 1))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|medicine| medicine.id)
+            .collect();
+
+        assert_eq!(ids, [3, 1]);
+    }
+
+    #[tokio::test]
+    async fn take_stock_never_goes_below_zero() {
+        let store = store();
+
+        assert!(store.take_stock(3, 2).await.unwrap());
+        assert!(!store.take_stock(3, 1).await.unwrap());
+        assert!(!store.take_stock(9, 1).await.unwrap());
+        assert_eq!(store.find(3).await.unwrap().unwrap().stock, 0);
+    }
+
+    fn shift(pharmacist: i64, weekday: i16, starts_at: u32, ends_at: u32) -> Shift {
+        Shift {
+            id: pharmacist,
+            pharmacist_chat_id: pharmacist,
+            pharmacist_name: format!("Pharmacist {}", pharmacist),
+            weekday,
+            starts_at: NaiveTime::from_hms_opt(starts_at, 0, 0).unwrap(),
+            ends_at: NaiveTime::from_hms_opt(ends_at, 0, 0).unwrap(),
+            created_at: Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn on_duty_follows_the_shifts() {
+        let store = store();
+        // Saturday 1 June 2024, 10:00 in Cairo (UTC+3)
+        let saturday_morning = date(2024, 6, 1).and_hms_opt(7, 0, 0).unwrap().and_utc();
+        let cairo = chrono_tz::Africa::Cairo;
+
+        assert!(matches!(
+            store.on_duty(cairo, saturday_morning).await.unwrap(),
+            Duty::NoRota
+        ));
+
+        store.add_shift(shift(7, 5, 9, 17));
+        store.add_shift(shift(8, 5, 8, 12));
+        store.add_shift(shift(9, 6, 9, 17));
+        assert!(matches!(
+            store.on_duty(cairo, saturday_morning).await.unwrap(),
+            Duty::OnDuty(ChatId(8))
+        ));
+
+        let saturday_night = saturday_morning + chrono::Duration::hours(12);
+        match store.on_duty(cairo, saturday_night).await.unwrap() {
+            Duty::Closed { reopens_at } => {
+                assert_eq!(
+                    reopens_at.naive_local(),
+                    date(2024, 6, 2).and_hms_opt(9, 0, 0).unwrap()
+                )
+            }
+            duty => panic!("expected the pharmacy to be closed, got {:?}", duty),
+        }
+    }
+
+    #[tokio::test]
+    async fn inserted_orders_keep_their_status() {
+        let store = store();
+        let placed = date(2023, 6, 12);
+        for (id, status) in [(1, "Pending"), (2, "Delivered")] {
+            OrderRepo::insert(
+                &store,
+                Order {
+                    id,
+                    user_id: "42".to_string(),
+                    medicine_id: 1,
+                    quantity: 1,
+                    status: status.to_string(),
+                    created_at: placed,
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(store.count_pending().await.unwrap(), 1);
+        assert_eq!(store.orders()[1].status, "Delivered");
+    }
+
+    #[tokio::test]
+    async fn orders_are_placed_only_with_enough_stock() {
+        let store = store();
+        let today = date(2024, 6, 1);
+
+        let first = store.place(order(3, 2, today)).await.unwrap().unwrap();
+        let second = store.place(order(1, 1, today)).await.unwrap().unwrap();
+        assert_eq!((first.id, second.id), (1, 2));
+        assert_eq!(store.find(3).await.unwrap().unwrap().stock, 0);
+
+        assert!(store.place(order(3, 1, today)).await.unwrap().is_none());
+        assert!(store.place(order(9, 1, today)).await.unwrap().is_none());
+        assert_eq!(store.orders().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn replies_are_traced_back_to_their_conversation() {
+        let store = store();
+        let (patient, pharmacist) = (ChatId(7), ChatId(8));
+        let conversation = store
+            .start_conversation(patient, pharmacist, MessageId(1))
+            .await
+            .unwrap();
+        store
+            .record_delivery(conversation.id, patient, MessageId(2))
+            .await
+            .unwrap();
+
+        for (chat, message) in [(pharmacist, MessageId(1)), (patient, MessageId(2))] {
+            let found = store.find_by_message(chat, message).await.unwrap();
+            assert_eq!(found.map(|c| c.id), Some(conversation.id));
+        }
+        assert!(store
+            .find_by_message(patient, MessageId(1))
+            .await
+            .unwrap()
+            .is_none());
+
+        let ticket = store.open(conversation.id, Some(pharmacist)).await.unwrap();
+        store.record_pharmacist_reply(ticket.id).await.unwrap();
+        assert_eq!(store.tickets()[0].status, TicketStatus::AwaitingPatient);
+        store.record_patient_reply(ticket.id).await.unwrap();
+        assert_eq!(store.tickets()[0].status, TicketStatus::Assigned);
+        assert!(store.tickets()[0].first_response_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn used_up_and_revoked_tokens_stop_working() {
+        let store = store();
+        let owner = ChatId(7);
+        let policy = TokenPolicy {
+            ttl: None,
+            max_uses: Some(1),
+        };
+        let once = store.create_token(owner, policy).await.unwrap();
+        assert_eq!(store.redeem(&once.token).await.unwrap(), Some(owner));
+        assert_eq!(store.redeem(&once.token).await.unwrap(), None);
+
+        let unlimited = TokenPolicy {
+            ttl: None,
+            max_uses: None,
+        };
+        let revoked = store.create_token(owner, unlimited).await.unwrap();
+        let kept = store.create_token(owner, unlimited).await.unwrap();
+        assert!(store.revoke(owner, &revoked.token).await.unwrap());
+        assert!(!store.revoke(ChatId(8), &kept.token).await.unwrap());
+        assert_eq!(store.recipient(&revoked.token).await.unwrap(), None);
+
+        let active = store.list_active(owner).await.unwrap();
+        assert_eq!(
+            active.iter().map(|t| &t.token).collect::<Vec<_>>(),
+            [&kept.token]
+        );
+    }
+
+    #[tokio::test]
+    async fn daily_summary_only_counts_that_day() {
+        let store = store();
+        let today = date(2024, 6, 1);
+        for (medicine_id, quantity, day) in [
+            (1, 2, today),
+            (2, 3, today),
+            (1, 4, today),
+            (1, 9, date(2024, 5, 31)),
+        ] {
+            let placed = store.place(order(medicine_id, quantity, day)).await;
+            assert!(placed.unwrap().is_some());
+        }
+
+        let summary = store.daily_summary(today, 1).await.unwrap();
+
+        assert_eq!(
+            summary,
+            DailySummary {
+                order_count: 3,
+                total_quantity: 9,
+                top_medicines: vec![("Paracetamol".to_string(), 6)],
+            }
+        );
+        assert_eq!(store.count_pending().await.unwrap(), 4);
+    }
+}
@@ -0,0 +1,139 @@
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use std::{fmt, str::FromStr};
+use teloxide::types::UserId;
+
+/// What a user is allowed to do, from least to most privileged.
+///
+/// Every role includes the permissions of the roles before it, so a check is
+/// a simple comparison: `role >= Role::Pharmacist`.
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+pub enum Role {
+    /// Anyone talking to the bot. Customers have no row in `user_roles`.
+    Customer,
+    /// Answers consultations, handles orders and moderates chats.
+    Pharmacist,
+    /// Manages the rota and the scheduled jobs.
+    Manager,
+    /// Grants and revokes roles.
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [Role::Customer, Role::Pharmacist, Role::Manager, Role::Admin];
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::Customer => "customer",
+            Role::Pharmacist => "pharmacist",
+            Role::Manager => "manager",
+            Role::Admin => "admin",
+        })
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Role::ALL
+            .into_iter()
+            .find(|role| role.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                let available = Role::ALL.map(|role| role.to_string()).join(", ");
+                format!("Unknown role \"{}\". Available roles: {}", s, available)
+            })
+    }
+}
+
+/// A command that can only be used by some roles.
+pub trait Restricted {
+    /// The least privileged role allowed to use the command.
+    fn required_role(&self) -> Role;
+}
+
+/// A user with a role above customer.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct RoleAssignment {
+    pub user_id: i64,
+    pub role: Role,
+    pub granted_by: Option<i64>,
+    pub granted_at: DateTime<Utc>,
+}
+
+/// Looks up the role of a user; users without an assignment are customers.
+pub async fn role_of(pool: &PgPool, user: UserId) -> Result<Role, sqlx::Error> {
+    let role: Option<(Role,)> = sqlx::query_as("SELECT role FROM user_roles WHERE user_id = $1")
+        .bind(user.0 as i64)
+        .fetch_optional(pool)
+        .await?;
+    Ok(role.map_or(Role::Customer, |(role,)| role))
+}
+
+/// Gives a user a role, replacing any previous one.
+///
+/// # Arguments
+///
+/// * `pool` - The database connection pool.
+/// * `user` - The user whose role changes.
+/// * `role` - The new role; [`Role::Customer`] removes the assignment.
+/// * `granted_by` - The admin making the change.
+pub async fn set_role(
+    pool: &PgPool,
+    user: UserId,
+    role: Role,
+    granted_by: UserId,
+) -> Result<(), sqlx::Error> {
+    if role == Role::Customer {
+        sqlx::query("DELETE FROM user_roles WHERE user_id = $1")
+            .bind(user.0 as i64)
+            .execute(pool)
+            .await?;
+        return Ok(());
+    }
+
+    sqlx::query(
+        "INSERT INTO user_roles (user_id, role, granted_by) VALUES ($1, $2, $3)
+         ON CONFLICT (user_id) DO UPDATE
+         SET role = EXCLUDED.role, granted_by = EXCLUDED.granted_by, granted_at = NOW()",
+    )
+    .bind(user.0 as i64)
+    .bind(role)
+    .bind(granted_by.0 as i64)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Makes `user` an admin if there is no admin yet.
+///
+/// Once an admin exists, roles are managed with commands only, so the
+/// configured user can be demoted like anyone else.
+///
+/// # Returns
+///
+/// Returns `true` if the user was made an admin.
+pub async fn bootstrap_admin(pool: &PgPool, user: UserId) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "INSERT INTO user_roles (user_id, role)
+         SELECT $1, $2 WHERE NOT EXISTS (SELECT 1 FROM user_roles WHERE role = $2)
+         ON CONFLICT (user_id) DO UPDATE SET role = EXCLUDED.role, granted_at = NOW()",
+    )
+    .bind(user.0 as i64)
+    .bind(Role::Admin)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Lists every user with a role above customer, most privileged first.
+pub async fn list_assignments(pool: &PgPool) -> Result<Vec<RoleAssignment>, sqlx::Error> {
+    let mut assignments = sqlx::query_as::<_, RoleAssignment>("SELECT * FROM user_roles")
+        .fetch_all(pool)
+        .await?;
+    assignments.sort_by(|a, b| b.role.cmp(&a.role).then(a.granted_at.cmp(&b.granted_at)));
+    Ok(assignments)
+}
@@ -0,0 +1,225 @@
+use chrono::{DateTime, Datelike, Duration, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use sqlx::PgPool;
+use teloxide::types::ChatId;
+
+use crate::tickets::TicketStatus;
+
+/// A weekly shift during which a pharmacist is on duty.
+///
+/// Times are local to the pharmacy's time zone. A shift can't cross midnight;
+/// a night shift is entered as two shifts on consecutive days.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct Shift {
+    pub id: i64,
+    pub pharmacist_chat_id: i64,
+    pub pharmacist_name: String,
+    /// Day of the week, counted from Monday (0) to Sunday (6).
+    pub weekday: i16,
+    pub starts_at: NaiveTime,
+    pub ends_at: NaiveTime,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Shift {
+    /// The pharmacist working the shift.
+    pub fn pharmacist(&self) -> ChatId {
+        ChatId(self.pharmacist_chat_id)
+    }
+
+    /// The day of the week the shift is on.
+    pub fn weekday(&self) -> Weekday {
+        Weekday::try_from(self.weekday as u8).unwrap_or(Weekday::Mon)
+    }
+}
+
+/// Who should handle something that comes in right now.
+#[derive(Debug, Clone, Copy)]
+pub enum Duty {
+    /// A pharmacist is on shift.
+    OnDuty(ChatId),
+    /// Nobody is on shift; the pharmacy reopens with the next shift.
+    Closed { reopens_at: DateTime<Tz> },
+    /// No shifts have been entered, so there is nobody to route to.
+    NoRota,
+}
+
+/// Parses the arguments of `/addshift`.
+///
+/// # Arguments
+///
+/// * `weekday` - A day name such as `mon` or `Monday`.
+/// * `starts_at` - The start of the shift as `HH:MM`.
+/// * `ends_at` - The end of the shift as `HH:MM`, later than the start.
+///
+/// # Returns
+///
+/// Returns the parsed shift, or a message explaining what is wrong.
+pub fn parse_shift(
+    weekday: &str,
+    starts_at: &str,
+    ends_at: &str,
+) -> Result<(Weekday, NaiveTime, NaiveTime), String> {
+    let weekday = weekday
+        .parse::<Weekday>()
+        .map_err(|_| format!("\"{}\" isn't a day of the week.", weekday))?;
+    let parse_time = |time: &str| {
+        NaiveTime::parse_from_str(time, "%H:%M")
+            .map_err(|_| format!("\"{}\" isn't a time, use HH:MM.", time))
+    };
+    let (starts_at, ends_at) = (parse_time(starts_at)?, parse_time(ends_at)?);
+    if starts_at >= ends_at {
+        return Err(
+            "A shift must end after it starts. Split night shifts at midnight.".to_string(),
+        );
+    }
+    Ok((weekday, starts_at, ends_at))
+}
+
+/// Adds a shift to the rota.
+///
+/// # Arguments
+///
+/// * `pool` - The database connection pool.
+/// * `pharmacist` - The pharmacist's private chat.
+/// * `name` - The pharmacist's name, shown in the rota.
+/// * `weekday` - The day of the week of the shift.
+/// * `starts_at` - Local start time of the shift.
+/// * `ends_at` - Local end time of the shift.
+///
+/// # Returns
+///
+/// Returns the new shift.
+pub async fn add_shift(
+    pool: &PgPool,
+    pharmacist: ChatId,
+    name: &str,
+    weekday: Weekday,
+    starts_at: NaiveTime,
+    ends_at: NaiveTime,
+) -> Result<Shift, sqlx::Error> {
+    sqlx::query_as::<_, Shift>(
+        "INSERT INTO pharmacist_shifts (pharmacist_chat_id, pharmacist_name, weekday, starts_at, ends_at)
+         VALUES ($1, $2, $3, $4, $5) RETURNING *",
+    )
+    .bind(pharmacist.0)
+    .bind(name)
+    .bind(weekday.num_days_from_monday() as i16)
+    .bind(starts_at)
+    .bind(ends_at)
+    .fetch_one(pool)
+    .await
+}
+
+/// Removes a shift from the rota.
+///
+/// # Returns
+///
+/// Returns `true` if the shift existed.
+pub async fn remove_shift(pool: &PgPool, id: i64) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM pharmacist_shifts WHERE id = $1")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Lists every shift, ordered through the week.
+pub async fn list_shifts(pool: &PgPool) -> Result<Vec<Shift>, sqlx::Error> {
+    sqlx::query_as::<_, Shift>(
+        "SELECT * FROM pharmacist_shifts ORDER BY weekday, starts_at, pharmacist_name",
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Finds who is on duty at a given moment.
+///
+/// When several pharmacists are on shift, the one with the fewest tickets
+/// still open is picked, so new work is spread between them.
+///
+/// # Arguments
+///
+/// * `pool` - The database connection pool.
+/// * `timezone` - The pharmacy's time zone, which the shifts are expressed in.
+/// * `now` - The moment to look up.
+pub async fn on_duty(pool: &PgPool, timezone: Tz, now: DateTime<Utc>) -> Result<Duty, sqlx::Error> {
+    let local = now.with_timezone(&timezone);
+
+    let pharmacist: Option<(i64,)> = sqlx::query_as(
+        "SELECT s.pharmacist_chat_id FROM pharmacist_shifts s
+         WHERE s.weekday = $1 AND s.starts_at <= $2 AND $2 < s.ends_at
+         ORDER BY (
+             SELECT COUNT(*) FROM tickets t
+             WHERE t.assigned_chat_id = s.pharmacist_chat_id AND t.status <> $3
+         ), s.starts_at
+         LIMIT 1",
+    )
+    .bind(local.weekday().num_days_from_monday() as i16)
+    .bind(local.time())
+    .bind(TicketStatus::Closed)
+    .fetch_optional(pool)
+    .await?;
+
+    if let Some((chat_id,)) = pharmacist {
+        return Ok(Duty::OnDuty(ChatId(chat_id)));
+    }
+
+    let shifts = list_shifts(pool).await?;
+    Ok(duty_in(&shifts, local))
+}
+
+/// Finds who is on duty at a given local moment from the shifts alone.
+///
+/// Without the tickets to balance by, the pharmacist whose shift started
+/// first is picked when several are on shift.
+pub fn duty_in(shifts: &[Shift], now: DateTime<Tz>) -> Duty {
+    let on_shift = shifts
+        .iter()
+        .filter(|shift| {
+            shift.weekday() == now.weekday()
+                && shift.starts_at <= now.time()
+                && now.time() < shift.ends_at
+        })
+        .min_by_key(|shift| shift.starts_at);
+    if let Some(shift) = on_shift {
+        return Duty::OnDuty(shift.pharmacist());
+    }
+
+    match next_opening(shifts, now) {
+        Some(reopens_at) => Duty::Closed { reopens_at },
+        None => Duty::NoRota,
+    }
+}
+
+/// Finds the start of the first shift after `now`, looking one week ahead.
+fn next_opening(shifts: &[Shift], now: DateTime<Tz>) -> Option<DateTime<Tz>> {
+    let timezone = now.timezone();
+    (0..=7)
+        .map(|offset| now.date_naive() + Duration::days(offset))
+        .flat_map(|date| {
+            shifts
+                .iter()
+                .filter(move |shift| shift.weekday() == date.weekday())
+                .filter_map(move |shift| {
+                    // Skip starts that fall into a daylight saving gap
+                    timezone
+                        .from_local_datetime(&date.and_time(shift.starts_at))
+                        .earliest()
+                })
+        })
+        .filter(|start| *start > now)
+        .min()
+}
+
+/// Describes when the pharmacy reopens relative to `now`, e.g. `at 09:00`,
+/// `tomorrow at 09:00` or `on Monday at 09:00`.
+pub fn describe_reopening(reopens_at: DateTime<Tz>, now: DateTime<Utc>) -> String {
+    let today = now.with_timezone(&reopens_at.timezone()).date_naive();
+    let time = reopens_at.format("%H:%M");
+    match (reopens_at.date_naive() - today).num_days() {
+        0 => format!("at {}", time),
+        1 => format!("tomorrow at {}", time),
+        _ => format!("on {} at {}", reopens_at.format("%A"), time),
+    }
+}
@@ -1,160 +1,98 @@
 use crate::{
+    error::{AppError, Entity},
+    models::{Medicine, Order},
+    queue::{MessageQueue, OutboundMessage},
+    repositories::{DailySummary, MedicineRepo, NewOrder, OrderRepo},
+    tickets,
     utils::{escape_markdown, format_date},
-    Medicine,
+    Error,
 };
 use chrono::Utc;
-use futures::future;
 use sqlx::PgPool;
-use teloxide::prelude::*;
-use tokio_cron_scheduler::{Job, JobScheduler};
-
-/// Schedules notifications for expiring medicines.
-///
-/// This function sets up a scheduled job to check for expiring medicines and send notifications.
-/// It uses the `tokio_cron_scheduler` crate to create a job that runs daily at 8:00 AM.
-///
-/// Parameters:
-/// - `pool`: A PostgreSQL connection pool for database operations.
-/// - `bot`: A Telegram Bot instance for sending notifications.
-/// - `pharmacy_group_chat_id`: The ChatId of the pharmacy group where notifications will be sent.
-///
-/// The function performs the following steps:
-/// 1. Creates a new JobScheduler instance.
-/// 2. Defines a new asynchronous job that runs daily at 8:00 AM.
-/// 3. The job calls `check_and_notify_expiring_medicines` function.
-/// 4. Adds the job to the scheduler and starts it.
-///
-/// Returns:
-/// - `Ok(())` if the job is successfully scheduled and started.
-/// - `Err(Box<dyn std::error::Error>)` if any step fails.
-pub async fn schedule_notifications(
-    pool: PgPool,
-    bot: Bot,
-    pharmacy_group_chat_id: ChatId,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // Create a new JobScheduler
-    let sched = JobScheduler::new().await?;
-
-    // Define the job to run daily at 8:00 AM
-    let job = Job::new_async("0 8 0 * * *", move |_uuid, _l| {
-        let bot = bot.clone();
-        let pool = pool.clone();
-        let chat_id = pharmacy_group_chat_id;
-        Box::pin(async move {
-            match check_and_notify_expiring_medicines(&pool, &bot, chat_id).await {
-                Ok(_) => log::info!("Expiring medicines check completed successfully"),
-                Err(e) => log::error!("Error checking expiring medicines: {}", e),
-            }
-        })
-    })
-    .map_err(|e| {
-        log::error!("Failed to create job: {}", e);
-        Box::new(e) as Box<dyn std::error::Error>
-    })?;
-
-    // Add the job to the scheduler
-    sched.add(job).await.map_err(|e| {
-        log::error!("Failed to add job to scheduler: {}", e);
-        Box::new(e) as Box<dyn std::error::Error>
-    })?;
-
-    // Start the scheduler in a separate task
-    tokio::spawn(async move {
-        if let Err(e) = sched.start().await {
-            log::error!("Scheduler error: {}", e);
-        }
-    });
-
-    log::info!("Notification scheduler started successfully");
-    Ok(())
-}
+use teloxide::{
+    prelude::*,
+    types::{ParseMode, ThreadId},
+};
 
 /// Checks for expiring medicines and sends notifications.
 ///
 /// This function performs the following steps:
 /// 1. Fetches a list of medicines that are expiring soon from the database.
-/// 2. For each expiring medicine, sends a notification to the specified chat.
+/// 2. For each expiring medicine, queues a notification for the specified chat.
 ///
 /// Parameters:
-/// - `pool`: A reference to the PostgreSQL connection pool.
-/// - `bot`: A reference to the Telegram Bot instance used to send messages.
+/// - `medicines`: The medicine repository.
+/// - `queue`: A reference to the outbound message queue.
 /// - `chat_id`: The ID of the chat where notifications will be sent.
+/// - `thread_id`: The forum topic to post in, or `None` for the general thread.
 ///
 /// Returns:
-/// - `Ok(())` if all operations succeed.
-/// - `Err(Box<dyn std::error::Error>)` if any step fails.
+/// - `Ok(count)` with the number of medicines found if the check succeeds.
+/// - `Err(Error)` if the medicines could not be fetched.
 ///
-/// Note: This function uses `?` operator to propagate errors from both
-/// `fetch_expiring_medicines` and `send_expiry_notification` functions.
-async fn check_and_notify_expiring_medicines(
-    pool: &PgPool,
-    bot: &Bot,
+/// Note: Failing to queue a single notification is logged and does not
+/// abort the remaining notifications.
+pub async fn check_and_notify_expiring_medicines(
+    medicines: &dyn MedicineRepo,
+    queue: &MessageQueue,
     chat_id: ChatId,
-) -> Result<(), Box<dyn std::error::Error>> {
+    thread_id: Option<ThreadId>,
+) -> Result<usize, Error> {
     // Fetch the list of expiring medicines
-    let medicines = fetch_expiring_medicines(pool).await?;
+    let medicines = fetch_expiring_medicines(medicines).await?;
 
-    // Create a vector to store all the notification futures
-    let notification_futures: Vec<_> = medicines
-        .iter()
-        .map(|medicine| send_expiry_notification(bot, chat_id, medicine))
-        .collect();
-
-    // Execute all notification futures concurrently
-    let results = future::join_all(notification_futures).await;
-
-    // Check if any notifications failed
-    for result in results {
-        if let Err(e) = result {
-            log::error!("Failed to send notification: {}", e);
+    // Queue one notification per medicine; the queue takes care of pacing
+    // the actual sends so a long list doesn't trip Telegram's flood limits
+    for medicine in &medicines {
+        if let Err(e) = send_expiry_notification(queue, chat_id, thread_id, medicine).await {
+            log::error!("Failed to queue notification: {}", e);
         }
     }
 
-    // Return Ok if all operations succeeded
-    Ok(())
+    // Return the number of medicines that need attention
+    Ok(medicines.len())
 }
 
-/// Fetches medicines that are expiring within the next 6 months from the database.
+/// Fetches medicines that are expiring within the next 6 months.
 ///
-/// This function queries the database for all medicines whose expiry date is less than or equal to
-/// 6 months from the current date and time. It uses the following parameters:
+/// This function asks the medicine repository for all medicines whose expiry date is less than
+/// or equal to 6 months from today. It uses the following parameters:
 ///
-/// - `pool`: A reference to the PostgreSQL connection pool.
+/// - `medicines`: The medicine repository.
 ///
 /// The function performs the following steps:
 /// 1. Calculates the date 6 months from now.
-/// 2. Constructs an SQL query to select all columns from the 'medicines' table where the expiry_date
-///    is less than or equal to the calculated future date.
-/// 3. Binds the future date to the query parameter.
-/// 4. Executes the query and fetches all matching rows, mapping them to `Medicine` structs.
+/// 2. Fetches the medicines expiring on or before that date, soonest first.
 ///
 /// Returns a `Result` containing either:
 /// - `Ok(Vec<Medicine>)`: A vector of `Medicine` structs representing the medicines expiring within 6 months.
 /// - `Err(sqlx::Error)`: An error if the database query fails.
-async fn fetch_expiring_medicines(pool: &PgPool) -> Result<Vec<Medicine>, sqlx::Error> {
-    let six_months_from_now = Utc::now() + chrono::Duration::days(180);
-    sqlx::query_as::<_, Medicine>("SELECT * FROM medicines WHERE expiry_date <= $1")
-        .bind(six_months_from_now.naive_utc())
-        .fetch_all(pool)
-        .await
+async fn fetch_expiring_medicines(
+    medicines: &dyn MedicineRepo,
+) -> Result<Vec<Medicine>, sqlx::Error> {
+    let six_months_from_now = Utc::now().date_naive() + chrono::Duration::days(180);
+    medicines.expiring_by(six_months_from_now).await
 }
 
-/// Sends a notification about an expiring medicine to the specified chat.
+/// Queues a notification about an expiring medicine for the specified chat.
 ///
 /// This function is responsible for notifying the pharmacy group about medicines
 /// that are about to expire. It takes the following parameters:
 ///
-/// - `bot`: A reference to the Telegram Bot instance used to send messages.
+/// - `queue`: A reference to the outbound message queue.
 /// - `chat_id`: The ID of the chat (likely a group chat) where the notification will be sent.
+/// - `thread_id`: The forum topic to post in, or `None` for the general thread.
 /// - `medicine`: A reference to the Medicine struct containing information about the expiring medicine.
 ///
-/// The function constructs a formatted message with the medicine's name and sends it to the specified chat.
-/// It returns a Result, which will be Ok(()) if the message was sent successfully, or an error if there was a problem.
+/// The function constructs a formatted message with the medicine's name and queues it for the specified chat.
+/// It returns a Result, which will be Ok(()) if the message was queued successfully, or an error if it
+/// could not be persisted.
 async fn send_expiry_notification(
-    bot: &Bot,
+    queue: &MessageQueue,
     chat_id: ChatId,
+    thread_id: Option<ThreadId>,
     medicine: &Medicine,
-) -> Result<(), teloxide::RequestError> {
+) -> Result<(), sqlx::Error> {
     // Calculate days until expiry
     let days_until_expiry = (medicine.expiry_date - Utc::now().date_naive()).num_days();
 
@@ -172,11 +110,326 @@ async fn send_expiry_notification(
         escaped_name, formatted_date, days_until_expiry, medicine.stock,
     );
 
-    // Send the message to the specified chat with Markdown parsing
-    bot.send_message(chat_id, message)
-        .parse_mode(teloxide::types::ParseMode::MarkdownV2)
+    // Queue the message for the specified chat with Markdown parsing
+    queue
+        .push(
+            OutboundMessage::new(chat_id, message)
+                .parse_mode(ParseMode::MarkdownV2)
+                .thread_id(thread_id),
+        )
         .await?;
 
-    // If we've reached this point, the message was sent successfully
+    // If we've reached this point, the message was persisted and will be sent
     Ok(())
 }
+
+/// Places an order for a medicine, taking it out of stock.
+///
+/// Parameters:
+/// - `medicines`: The medicine repository.
+/// - `orders`: The order repository.
+/// - `user_id`: The ID of the customer placing the order.
+/// - `medicine_id`: The ID of the medicine ordered.
+/// - `quantity`: The number of units ordered.
+///
+/// Returns:
+/// - `Ok((order, medicine))` with the new pending order and the medicine it is for.
+/// - `Err(AppError::NotFound)` if there is no such medicine.
+/// - `Err(AppError::InsufficientStock)` if there isn't enough of it left.
+/// - `Err(AppError::Db)` if a query fails.
+///
+/// Note: The stock is taken and the order stored in one step, so two customers
+/// can't both order the last units, and stock is never taken for an order that
+/// wasn't stored.
+pub async fn place_order(
+    medicines: &dyn MedicineRepo,
+    orders: &dyn OrderRepo,
+    user_id: String,
+    medicine_id: i32,
+    quantity: i32,
+) -> Result<(Order, Medicine), AppError> {
+    let medicine = medicines
+        .find(medicine_id)
+        .await?
+        .ok_or(AppError::NotFound(Entity::Medicine))?;
+
+    let placed = orders
+        .place(NewOrder {
+            user_id,
+            medicine_id,
+            quantity,
+            created_at: Utc::now().date_naive(),
+        })
+        .await?;
+    let Some(order) = placed else {
+        // Someone else may have taken stock since, so report what is left now
+        let available = medicines
+            .find(medicine_id)
+            .await?
+            .map_or(0, |medicine| medicine.stock);
+        return Err(AppError::InsufficientStock {
+            medicine: medicine.name,
+            available,
+            requested: quantity,
+        });
+    };
+    Ok((order, medicine))
+}
+
+/// Checks for medicines that are running low and notifies the pharmacy group.
+///
+/// A single summary message listing every medicine whose stock is below
+/// `threshold` is queued for the given chat. Nothing is sent when all
+/// medicines are sufficiently stocked.
+///
+/// Parameters:
+/// - `medicines`: The medicine repository.
+/// - `queue`: A reference to the outbound message queue.
+/// - `chat_id`: The ID of the chat where the alert will be sent.
+/// - `thread_id`: The forum topic to post in, or `None` for the general thread.
+/// - `threshold`: The stock level below which a medicine counts as low.
+///
+/// Returns:
+/// - `Ok(count)` with the number of low-stock medicines.
+/// - `Err(Error)` if the query or queueing the alert fails.
+pub async fn check_low_stock(
+    medicines: &dyn MedicineRepo,
+    queue: &MessageQueue,
+    chat_id: ChatId,
+    thread_id: Option<ThreadId>,
+    threshold: i32,
+) -> Result<usize, Error> {
+    let medicines = medicines.low_stock(threshold).await?;
+
+    if medicines.is_empty() {
+        return Ok(0);
+    }
+
+    let lines = medicines
+        .iter()
+        .map(|medicine| format!("• {}: {} units left", medicine.name, medicine.stock))
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    queue
+        .push(
+            OutboundMessage::new(
+                chat_id,
+                format!(
+                    "📉 Low Stock Alert\n\nThe following medicines are below {} units:\n\n{}",
+                    threshold, lines
+                ),
+            )
+            .thread_id(thread_id),
+        )
+        .await?;
+
+    Ok(medicines.len())
+}
+
+/// Sends a daily summary of the orders placed today to the pharmacy group.
+///
+/// The report contains the number of orders, the total quantity ordered and
+/// the five most ordered medicines of the day.
+///
+/// Parameters:
+/// - `orders`: The order repository.
+/// - `queue`: A reference to the outbound message queue.
+/// - `chat_id`: The ID of the chat where the report will be sent.
+/// - `thread_id`: The forum topic to post in, or `None` for the general thread.
+///
+/// Returns:
+/// - `Ok(count)` with the number of orders included in the report.
+/// - `Err(Error)` if a query or queueing the report fails.
+pub async fn send_daily_report(
+    orders: &dyn OrderRepo,
+    queue: &MessageQueue,
+    chat_id: ChatId,
+    thread_id: Option<ThreadId>,
+) -> Result<usize, Error> {
+    let today = Utc::now().date_naive();
+    let DailySummary {
+        order_count,
+        total_quantity,
+        top_medicines,
+    } = orders.daily_summary(today, 5).await?;
+
+    let mut report = format!(
+        "📊 Daily Report for {}\n\nOrders placed: {}\nUnits ordered: {}",
+        format_date(today),
+        order_count,
+        total_quantity
+    );
+
+    if !top_medicines.is_empty() {
+        let top = top_medicines
+            .iter()
+            .map(|(name, total)| format!("• {}: {} units", name, total))
+            .collect::<Vec<String>>()
+            .join("\n");
+        report.push_str(&format!("\n\nMost ordered:\n{}", top));
+    }
+
+    queue
+        .push(OutboundMessage::new(chat_id, report).thread_id(thread_id))
+        .await?;
+
+    Ok(order_count as usize)
+}
+
+/// Reminds the pharmacy group of orders that are still pending.
+///
+/// Parameters:
+/// - `orders`: The order repository.
+/// - `queue`: A reference to the outbound message queue.
+/// - `chat_id`: The ID of the chat where the reminder will be sent.
+/// - `thread_id`: The forum topic to post in, or `None` for the general thread.
+///
+/// Returns:
+/// - `Ok(count)` with the number of pending orders.
+/// - `Err(Error)` if the query or queueing the reminder fails.
+pub async fn remind_pending_orders(
+    orders: &dyn OrderRepo,
+    queue: &MessageQueue,
+    chat_id: ChatId,
+    thread_id: Option<ThreadId>,
+) -> Result<usize, Error> {
+    let pending = orders.count_pending().await?;
+
+    if pending > 0 {
+        queue
+            .push(
+                OutboundMessage::new(
+                    chat_id,
+                    format!(
+                        "⏰ Reminder: {} order(s) are still pending and waiting to be processed.",
+                        pending
+                    ),
+                )
+                .thread_id(thread_id),
+            )
+            .await?;
+    }
+
+    Ok(pending as usize)
+}
+
+/// Alerts the pharmacy group about consultation tickets that breached the SLA.
+///
+/// Each overdue ticket is reported once; it is reported again only after the
+/// patient writes another message that also goes unanswered.
+///
+/// Parameters:
+/// - `pool`: A reference to the PostgreSQL connection pool.
+/// - `queue`: A reference to the outbound message queue.
+/// - `chat_id`: The ID of the chat where the alert will be sent.
+/// - `thread_id`: The forum topic to post in, or `None` for the general thread.
+/// - `sla`: How long a patient may wait for an answer.
+///
+/// Returns:
+/// - `Ok(count)` with the number of newly overdue tickets.
+/// - `Err(Error)` if the query or queueing the alert fails.
+pub async fn alert_overdue_tickets(
+    pool: &PgPool,
+    queue: &MessageQueue,
+    chat_id: ChatId,
+    thread_id: Option<ThreadId>,
+    sla: chrono::Duration,
+) -> Result<usize, Error> {
+    let overdue = tickets::take_overdue(pool, sla).await?;
+
+    if overdue.is_empty() {
+        return Ok(0);
+    }
+
+    let now = Utc::now();
+    let lines = overdue
+        .iter()
+        .map(|ticket| {
+            format!(
+                "• Ticket #{} ({}), waiting for {}",
+                ticket.id,
+                ticket.status,
+                tickets::format_age(now - ticket.waiting_since)
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    queue
+        .push(OutboundMessage::new(
+            chat_id,
+            format!(
+                "🚨 SLA Alert\n\nThese consultations have waited longer than {} for an answer:\n\n{}",
+                tickets::format_age(sla),
+                lines
+            ),
+        ).thread_id(thread_id))
+        .await?;
+
+    Ok(overdue.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repositories::MemoryStore;
+    use chrono::NaiveDate;
+
+    fn store() -> MemoryStore {
+        MemoryStore::with_medicines([Medicine {
+            id: 1,
+            name: "Paracetamol".to_string(),
+            stock: 3,
+            expiry_date: NaiveDate::from_ymd_opt(2030, 1, 1).unwrap(),
+        }])
+    }
+
+    #[tokio::test]
+    async fn placing_an_order_takes_it_out_of_stock() {
+        let store = store();
+
+        let (order, medicine) = place_order(&store, &store, "42".to_string(), 1, 2)
+            .await
+            .unwrap();
+
+        assert_eq!(medicine.name, "Paracetamol");
+        assert_eq!(order.user_id, "42");
+        assert_eq!(order.quantity, 2);
+        assert_eq!(order.status, "pending");
+        assert_eq!(store.find(1).await.unwrap().unwrap().stock, 1);
+        assert_eq!(store.orders().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn orders_beyond_the_stock_are_refused() {
+        let store = store();
+
+        let error = place_order(&store, &store, "42".to_string(), 1, 5)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            error,
+            AppError::InsufficientStock {
+                available: 3,
+                requested: 5,
+                ..
+            }
+        ));
+        assert_eq!(store.find(1).await.unwrap().unwrap().stock, 3);
+        assert!(store.orders().is_empty());
+    }
+
+    #[tokio::test]
+    async fn orders_for_unknown_medicines_are_refused() {
+        let store = store();
+
+        let error = place_order(&store, &store, "42".to_string(), 7, 1)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(error, AppError::NotFound(Entity::Medicine)));
+        assert!(store.orders().is_empty());
+    }
+}
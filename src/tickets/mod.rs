@@ -0,0 +1,220 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+use std::fmt;
+use teloxide::types::ChatId;
+
+/// The lifecycle of a consultation ticket.
+///
+/// ```text
+/// open ──► assigned ──► awaiting_patient ──► closed
+///              ▲               │
+///              └───────────────┘  (patient replies)
+/// ```
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Eq)]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+pub enum TicketStatus {
+    /// Created but no pharmacist has been assigned yet.
+    Open,
+    /// A pharmacist is responsible and the patient is waiting for them.
+    Assigned,
+    /// The pharmacist answered and is waiting for the patient.
+    AwaitingPatient,
+    /// The consultation is finished.
+    Closed,
+}
+
+impl fmt::Display for TicketStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TicketStatus::Open => "open",
+            TicketStatus::Assigned => "assigned",
+            TicketStatus::AwaitingPatient => "awaiting patient",
+            TicketStatus::Closed => "closed",
+        })
+    }
+}
+
+/// A consultation ticket tracking one anonymous conversation.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct Ticket {
+    pub id: i64,
+    pub conversation_id: i64,
+    pub status: TicketStatus,
+    pub assigned_chat_id: Option<i64>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// When the patient last wrote without getting an answer yet.
+    pub waiting_since: DateTime<Utc>,
+    pub first_response_at: Option<DateTime<Utc>>,
+    pub closed_at: Option<DateTime<Utc>>,
+    pub sla_alerted_at: Option<DateTime<Utc>>,
+}
+
+impl Ticket {
+    /// The pharmacist responsible for the ticket, if any.
+    pub fn assignee(&self) -> Option<ChatId> {
+        self.assigned_chat_id.map(ChatId)
+    }
+
+    /// Returns `true` if the patient has been waiting longer than `sla`.
+    pub fn is_overdue(&self, sla: Duration, now: DateTime<Utc>) -> bool {
+        matches!(self.status, TicketStatus::Open | TicketStatus::Assigned)
+            && now - self.waiting_since > sla
+    }
+}
+
+/// Opens a ticket for a new conversation.
+///
+/// # Arguments
+///
+/// * `pool` - The database connection pool.
+/// * `conversation_id` - The anonymous conversation the ticket tracks.
+/// * `assignee` - The pharmacist the ticket is assigned to, or `None` to
+///   leave it open for anyone to pick up.
+///
+/// # Returns
+///
+/// Returns the new ticket.
+pub async fn open_ticket(
+    pool: &PgPool,
+    conversation_id: i64,
+    assignee: Option<ChatId>,
+) -> Result<Ticket, sqlx::Error> {
+    let status = match assignee {
+        Some(_) => TicketStatus::Assigned,
+        None => TicketStatus::Open,
+    };
+
+    sqlx::query_as::<_, Ticket>(
+        "INSERT INTO tickets (conversation_id, status, assigned_chat_id)
+         VALUES ($1, $2, $3) RETURNING *",
+    )
+    .bind(conversation_id)
+    .bind(status)
+    .bind(assignee.map(|chat| chat.0))
+    .fetch_one(pool)
+    .await
+}
+
+/// Finds the ticket of a conversation.
+pub async fn find_by_conversation(
+    pool: &PgPool,
+    conversation_id: i64,
+) -> Result<Option<Ticket>, sqlx::Error> {
+    sqlx::query_as::<_, Ticket>("SELECT * FROM tickets WHERE conversation_id = $1")
+        .bind(conversation_id)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Finds a ticket by its ID.
+pub async fn find(pool: &PgPool, id: i64) -> Result<Option<Ticket>, sqlx::Error> {
+    sqlx::query_as::<_, Ticket>("SELECT * FROM tickets WHERE id = $1")
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Records that the pharmacist answered; the ticket now waits for the patient.
+pub async fn record_pharmacist_reply(pool: &PgPool, id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE tickets SET status = $1, updated_at = NOW(),
+             first_response_at = COALESCE(first_response_at, NOW())
+         WHERE id = $2 AND status <> $3",
+    )
+    .bind(TicketStatus::AwaitingPatient)
+    .bind(id)
+    .bind(TicketStatus::Closed)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Records that the patient wrote again; the ticket goes back to the pharmacist.
+///
+/// A closed ticket is reopened, and the SLA timer restarts.
+pub async fn record_patient_reply(pool: &PgPool, id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE tickets SET
+             status = CASE WHEN assigned_chat_id IS NULL THEN $1 ELSE $2 END,
+             updated_at = NOW(), waiting_since = NOW(), closed_at = NULL, sla_alerted_at = NULL
+         WHERE id = $3",
+    )
+    .bind(TicketStatus::Open)
+    .bind(TicketStatus::Assigned)
+    .bind(id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Closes a ticket.
+///
+/// # Returns
+///
+/// Returns the closed ticket, or `None` if it doesn't exist or was already closed.
+pub async fn close(pool: &PgPool, id: i64) -> Result<Option<Ticket>, sqlx::Error> {
+    sqlx::query_as::<_, Ticket>(
+        "UPDATE tickets SET status = $1, updated_at = NOW(), closed_at = NOW()
+         WHERE id = $2 AND status <> $1 RETURNING *",
+    )
+    .bind(TicketStatus::Closed)
+    .bind(id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Lists tickets that aren't closed, oldest first.
+///
+/// # Arguments
+///
+/// * `pool` - The database connection pool.
+/// * `assignee` - Only list tickets assigned to this pharmacist, or all tickets if `None`.
+pub async fn list_active(
+    pool: &PgPool,
+    assignee: Option<ChatId>,
+) -> Result<Vec<Ticket>, sqlx::Error> {
+    sqlx::query_as::<_, Ticket>(
+        "SELECT * FROM tickets
+         WHERE status <> $1 AND ($2::BIGINT IS NULL OR assigned_chat_id = $2)
+         ORDER BY waiting_since",
+    )
+    .bind(TicketStatus::Closed)
+    .bind(assignee.map(|chat| chat.0))
+    .fetch_all(pool)
+    .await
+}
+
+/// Finds tickets that breached the SLA and haven't been reported yet, and
+/// marks them as reported.
+///
+/// # Arguments
+///
+/// * `pool` - The database connection pool.
+/// * `sla` - How long a patient may wait for an answer.
+///
+/// # Returns
+///
+/// Returns the newly overdue tickets.
+pub async fn take_overdue(pool: &PgPool, sla: Duration) -> Result<Vec<Ticket>, sqlx::Error> {
+    sqlx::query_as::<_, Ticket>(
+        "UPDATE tickets SET sla_alerted_at = NOW()
+         WHERE status IN ($1, $2) AND sla_alerted_at IS NULL AND waiting_since < $3
+         RETURNING *",
+    )
+    .bind(TicketStatus::Open)
+    .bind(TicketStatus::Assigned)
+    .bind(Utc::now() - sla)
+    .fetch_all(pool)
+    .await
+}
+
+/// Formats a duration as a short human readable age, e.g. `2h 5m`.
+pub fn format_age(age: Duration) -> String {
+    let minutes = age.num_minutes().max(0);
+    match (minutes / (24 * 60), minutes / 60 % 24, minutes % 60) {
+        (0, 0, m) => format!("{}m", m),
+        (0, h, m) => format!("{}h {}m", h, m),
+        (d, h, _) => format!("{}d {}h", d, h),
+    }
+}
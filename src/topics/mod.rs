@@ -0,0 +1,61 @@
+use teloxide::types::{MessageId, ThreadId};
+
+/// A category of staff notifications that can have its own forum topic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StaffTopic {
+    /// New orders, pending order reminders and daily reports.
+    Orders,
+    /// Expiry alerts.
+    Expiry,
+    /// Low-stock alerts.
+    Inventory,
+    /// Anonymous consultations and SLA alerts.
+    Consultations,
+    /// Moderation logs.
+    Moderation,
+}
+
+/// The forum topics of the staff group chat, by category.
+///
+/// A category without a topic is posted to the general thread, which is also
+/// what happens when the staff group isn't a forum at all.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StaffTopics {
+    pub orders: Option<ThreadId>,
+    pub expiry: Option<ThreadId>,
+    pub inventory: Option<ThreadId>,
+    pub consultations: Option<ThreadId>,
+    pub moderation: Option<ThreadId>,
+}
+
+impl StaffTopics {
+    /// Builds the topics from configured thread IDs, where `0` means the
+    /// general thread.
+    pub fn from_ids(
+        orders: i32,
+        expiry: i32,
+        inventory: i32,
+        consultations: i32,
+        moderation: i32,
+    ) -> Self {
+        let thread = |id: i32| (id != 0).then_some(ThreadId(MessageId(id)));
+        Self {
+            orders: thread(orders),
+            expiry: thread(expiry),
+            inventory: thread(inventory),
+            consultations: thread(consultations),
+            moderation: thread(moderation),
+        }
+    }
+
+    /// Returns the topic notifications of a category are posted to.
+    pub fn thread(&self, topic: StaffTopic) -> Option<ThreadId> {
+        match topic {
+            StaffTopic::Orders => self.orders,
+            StaffTopic::Expiry => self.expiry,
+            StaffTopic::Inventory => self.inventory,
+            StaffTopic::Consultations => self.consultations,
+            StaffTopic::Moderation => self.moderation,
+        }
+    }
+}
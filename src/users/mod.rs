@@ -0,0 +1,66 @@
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use teloxide::types::{User, UserId};
+
+/// A Telegram user the bot has seen.
+///
+/// The Bot API can't look users up by username, so the bot remembers the
+/// users it sees to let staff name them as `@username` in commands.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct KnownUser {
+    pub user_id: i64,
+    pub username: Option<String>,
+    pub first_name: String,
+    pub last_name: Option<String>,
+    pub last_seen_at: DateTime<Utc>,
+}
+
+impl KnownUser {
+    /// The user's Telegram ID.
+    pub fn id(&self) -> UserId {
+        UserId(self.user_id as u64)
+    }
+}
+
+/// Records or refreshes a user the bot has seen.
+///
+/// Any other user holding the same username loses it, since Telegram
+/// usernames are unique but can change hands.
+pub async fn remember(pool: &PgPool, user: &User) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    if let Some(username) = &user.username {
+        sqlx::query(
+            "UPDATE known_users SET username = NULL
+             WHERE LOWER(username) = LOWER($1) AND user_id <> $2",
+        )
+        .bind(username)
+        .bind(user.id.0 as i64)
+        .execute(&mut *tx)
+        .await?;
+    }
+    sqlx::query(
+        "INSERT INTO known_users (user_id, username, first_name, last_name)
+         VALUES ($1, $2, $3, $4)
+         ON CONFLICT (user_id) DO UPDATE
+         SET username = EXCLUDED.username, first_name = EXCLUDED.first_name,
+             last_name = EXCLUDED.last_name, last_seen_at = NOW()",
+    )
+    .bind(user.id.0 as i64)
+    .bind(&user.username)
+    .bind(&user.first_name)
+    .bind(&user.last_name)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await
+}
+
+/// Finds a user by username, with or without the leading `@`.
+pub async fn find_by_username(
+    pool: &PgPool,
+    username: &str,
+) -> Result<Option<KnownUser>, sqlx::Error> {
+    sqlx::query_as::<_, KnownUser>("SELECT * FROM known_users WHERE LOWER(username) = LOWER($1)")
+        .bind(username.trim_start_matches('@'))
+        .fetch_optional(pool)
+        .await
+}
@@ -1,4 +1,4 @@
-use chrono::NaiveDate;
+use chrono::{Duration, NaiveDate};
 
 /// Helper function to format the date
 ///
@@ -32,3 +32,37 @@ pub fn format_date(date: NaiveDate) -> String {
 pub fn escape_markdown(text: &str) -> String {
     text.replace(|c: char| "._*[]()~`>#+-=|{}.!".contains(c), "\\")
 }
+
+/// Helper function to format a duration compactly
+///
+/// This function breaks a duration down into weeks, days, hours, minutes and
+/// seconds, leaving out the units that are zero.
+///
+/// # Arguments
+///
+/// * `duration` - The `Duration` to be formatted; negative durations count as zero
+///
+/// # Returns
+///
+/// A `String` such as "1d 12h" or "2w"
+pub fn format_duration(duration: Duration) -> String {
+    let mut seconds = duration.num_seconds().max(0);
+    let mut parts = Vec::new();
+    for (unit, length) in [
+        ("w", 604_800),
+        ("d", 86_400),
+        ("h", 3_600),
+        ("m", 60),
+        ("s", 1),
+    ] {
+        if seconds >= length {
+            parts.push(format!("{}{}", seconds / length, unit));
+            seconds %= length;
+        }
+    }
+    if parts.is_empty() {
+        "0s".to_string()
+    } else {
+        parts.join(" ")
+    }
+}
@@ -0,0 +1,122 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+use teloxide::types::{ChatId, UserId};
+
+/// A warning given to a member of a chat by a moderator.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct Warning {
+    pub id: i64,
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub reason: Option<String>,
+    pub issued_by: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// What happens to a user after a warning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Escalation {
+    /// The warning is all there is.
+    None,
+    /// The user is muted for a while.
+    Mute(Duration),
+    /// The user is banned for good.
+    Ban,
+}
+
+/// The number of warnings at which a user is muted or banned.
+#[derive(Debug, Clone, Copy)]
+pub struct WarnPolicy {
+    /// Warnings after which every further warning mutes the user; `None`
+    /// never mutes.
+    pub mute_after: Option<i64>,
+    /// How long an automatic mute lasts.
+    pub mute_for: Duration,
+    /// Warnings after which the user is banned; `None` never bans.
+    pub ban_after: Option<i64>,
+}
+
+impl WarnPolicy {
+    /// Decides what happens to a user who now has `count` warnings.
+    ///
+    /// A ban takes precedence over a mute when both thresholds are reached.
+    pub fn escalation(&self, count: i64) -> Escalation {
+        if self.ban_after.is_some_and(|limit| count >= limit) {
+            Escalation::Ban
+        } else if self.mute_after.is_some_and(|limit| count >= limit) {
+            Escalation::Mute(self.mute_for)
+        } else {
+            Escalation::None
+        }
+    }
+}
+
+/// Records a warning.
+///
+/// # Arguments
+///
+/// * `pool` - The database connection pool.
+/// * `chat` - The chat the warning applies to; warnings don't carry over
+///   between chats.
+/// * `user` - The user being warned.
+/// * `reason` - Why the user is warned, if the moderator said.
+/// * `issued_by` - The moderator giving the warning.
+///
+/// # Returns
+///
+/// Returns the number of warnings the user now has in the chat.
+pub async fn add_warning(
+    pool: &PgPool,
+    chat: ChatId,
+    user: UserId,
+    reason: Option<&str>,
+    issued_by: Option<UserId>,
+) -> Result<i64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        "INSERT INTO warnings (chat_id, user_id, reason, issued_by) VALUES ($1, $2, $3, $4)",
+    )
+    .bind(chat.0)
+    .bind(user.0 as i64)
+    .bind(reason)
+    .bind(issued_by.map(|id| id.0 as i64))
+    .execute(&mut *tx)
+    .await?;
+    let (count,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM warnings WHERE chat_id = $1 AND user_id = $2")
+            .bind(chat.0)
+            .bind(user.0 as i64)
+            .fetch_one(&mut *tx)
+            .await?;
+    tx.commit().await?;
+    Ok(count)
+}
+
+/// Lists the warnings of a user in a chat, oldest first.
+pub async fn list_warnings(
+    pool: &PgPool,
+    chat: ChatId,
+    user: UserId,
+) -> Result<Vec<Warning>, sqlx::Error> {
+    sqlx::query_as::<_, Warning>(
+        "SELECT * FROM warnings WHERE chat_id = $1 AND user_id = $2 ORDER BY created_at, id",
+    )
+    .bind(chat.0)
+    .bind(user.0 as i64)
+    .fetch_all(pool)
+    .await
+}
+
+/// Removes every warning of a user in a chat.
+///
+/// # Returns
+///
+/// Returns the number of warnings removed.
+pub async fn clear_warnings(pool: &PgPool, chat: ChatId, user: UserId) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM warnings WHERE chat_id = $1 AND user_id = $2")
+        .bind(chat.0)
+        .bind(user.0 as i64)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
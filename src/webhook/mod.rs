@@ -0,0 +1,175 @@
+use std::{convert::Infallible, net::SocketAddr};
+use teloxide::{
+    prelude::*,
+    update_listeners::{
+        webhooks::{self, Options},
+        UpdateListener,
+    },
+};
+use thiserror::Error;
+use tokio::net::TcpListener;
+use url::Url;
+
+use crate::Error;
+
+/// An invalid webhook configuration, reported at startup.
+#[derive(Debug, Error)]
+pub enum WebhookError {
+    #[error("invalid webhook URL {url:?}: {reason}")]
+    InvalidUrl { url: String, reason: String },
+    #[error("invalid webhook listen address {0:?}; expected e.g. 0.0.0.0:8080")]
+    InvalidAddress(String),
+    #[error("invalid webhook secret token; use 1 to 256 characters from A-Z, a-z, 0-9, _ and -")]
+    InvalidSecret,
+    #[error("a webhook secret token is required when the webhook isn't registered with Telegram")]
+    MissingSecret,
+}
+
+/// How updates are received in webhook mode.
+#[derive(Debug, Clone)]
+pub struct WebhookSettings {
+    /// The public URL Telegram posts updates to; its path is the one served.
+    pub url: Url,
+    /// The local address the HTTP server listens on.
+    pub address: SocketAddr,
+    /// The value Telegram sends in the `X-Telegram-Bot-Api-Secret-Token`
+    /// header; `None` generates a random one when registering.
+    pub secret_token: Option<String>,
+    /// Whether the webhook is registered with Telegram on start and removed on
+    /// shutdown. Without it, e.g. to post recorded updates locally, the
+    /// secret token must be given.
+    pub register: bool,
+}
+
+impl WebhookSettings {
+    /// Parses and validates the webhook configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The public URL of the webhook; empty selects long polling.
+    /// * `address` - The local address to listen on, e.g. `0.0.0.0:8080`.
+    /// * `secret_token` - The expected secret header, or empty.
+    /// * `register` - Whether to register the webhook with Telegram.
+    ///
+    /// # Returns
+    ///
+    /// Returns `None` when no URL is configured, the settings, or a
+    /// [`WebhookError`] describing the first problem found.
+    pub fn from_config(
+        url: &str,
+        address: &str,
+        secret_token: &str,
+        register: bool,
+    ) -> Result<Option<Self>, WebhookError> {
+        let url = url.trim();
+        if url.is_empty() {
+            return Ok(None);
+        }
+
+        let url = Url::parse(url).map_err(|e| WebhookError::InvalidUrl {
+            url: url.to_string(),
+            reason: e.to_string(),
+        })?;
+        let address = address
+            .trim()
+            .parse()
+            .map_err(|_| WebhookError::InvalidAddress(address.to_string()))?;
+
+        let secret_token = secret_token.trim();
+        let secret_token = if secret_token.is_empty() {
+            if !register {
+                return Err(WebhookError::MissingSecret);
+            }
+            None
+        } else if is_valid_secret(secret_token) {
+            Some(secret_token.to_string())
+        } else {
+            return Err(WebhookError::InvalidSecret);
+        };
+
+        Ok(Some(Self {
+            url,
+            address,
+            secret_token,
+            register,
+        }))
+    }
+}
+
+/// Whether a secret token is one Telegram accepts.
+fn is_valid_secret(token: &str) -> bool {
+    (1..=256).contains(&token.len())
+        && token
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+/// Starts the HTTP server that receives updates from Telegram.
+///
+/// Requests to the webhook path without the right
+/// `X-Telegram-Bot-Api-Secret-Token` header are answered with
+/// `401 Unauthorized` and never reach the dispatcher.
+///
+/// # Arguments
+///
+/// * `bot` - The Bot instance used to register and remove the webhook.
+/// * `settings` - The validated webhook settings.
+///
+/// # Returns
+///
+/// Returns the update listener to dispatch from, or an error if the address
+/// couldn't be bound or Telegram rejected the webhook.
+///
+/// # Note
+///
+/// The server shuts down when the listener is stopped, e.g. on Ctrl+C, after
+/// which the webhook is removed again if it was registered.
+pub async fn listen(
+    bot: Bot,
+    settings: WebhookSettings,
+) -> Result<impl UpdateListener<Err = Infallible>, Error> {
+    let WebhookSettings {
+        url,
+        address,
+        secret_token,
+        register,
+    } = settings;
+
+    let mut options = Options::new(address, url.clone());
+    if let Some(secret_token) = secret_token {
+        options = options.secret_token(secret_token);
+    }
+
+    // Bind first, so a busy port stops the bot before Telegram is told about it
+    let tcp_listener = TcpListener::bind(address).await?;
+
+    if register {
+        let secret_token = options.get_or_gen_secret_token().to_string();
+        bot.set_webhook(url.clone())
+            .secret_token(secret_token)
+            .await?;
+    }
+
+    let (listener, stop, router) = webhooks::axum_no_setup(options);
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(tcp_listener, router)
+            .with_graceful_shutdown(stop)
+            .await
+        {
+            log::error!("Webhook server failed: {}", e);
+        }
+        if register {
+            if let Err(e) = bot.delete_webhook().await {
+                log::error!("Failed to remove the webhook: {}", e);
+            }
+        }
+    });
+
+    log::info!(
+        "Receiving updates at {} on {}{}",
+        url,
+        address,
+        if register { "" } else { " (not registered)" }
+    );
+    Ok(listener)
+}
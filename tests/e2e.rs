@@ -0,0 +1,460 @@
+use axum::{
+    body::Bytes,
+    extract::{Path, State as AxumState},
+    routing::post,
+    Json, Router,
+};
+use chrono::{NaiveDate, Utc};
+use chrono_tz::Tz;
+use envconfig::Envconfig;
+use serde_json::{json, Value};
+use sqlx::postgres::PgPoolOptions;
+use std::{
+    collections::{HashMap, HashSet},
+    ops::ControlFlow,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use teloxide::{
+    dispatching::UpdateHandler,
+    prelude::*,
+    types::{Me, UpdateKind},
+};
+use tokio::net::TcpListener;
+use url::Url;
+
+use telepharma::{
+    captcha::Captcha,
+    config::Config,
+    dialogues::{self, State, StorageKind},
+    filters::SpamFilter,
+    handlers::{schema, Services},
+    jobs::{JobRegistry, JobSchedules},
+    models::Medicine,
+    queue::MessageQueue,
+    repositories::{MedicineRepo, MemoryStore, Repositories},
+    roles::Role,
+    Error,
+};
+
+/// The ID of the bot itself, as reported by the fake `getMe`.
+const BOT_ID: u64 = 42;
+
+/// How long to wait for a message sent by the outbound queue.
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A request the bot made to the fake Bot API.
+#[derive(Debug, Clone)]
+struct ApiCall {
+    /// The method, e.g. `sendMessage`.
+    method: String,
+    /// The JSON parameters of the request.
+    params: Value,
+}
+
+impl ApiCall {
+    fn chat_id(&self) -> Option<i64> {
+        self.params["chat_id"].as_i64()
+    }
+
+    fn text(&self) -> &str {
+        self.params["text"].as_str().unwrap_or_default()
+    }
+}
+
+/// What the fake Bot API has seen, and how it answers.
+#[derive(Clone, Default)]
+struct FakeApi {
+    calls: Arc<Mutex<Vec<ApiCall>>>,
+    /// Users `getChatMember` reports as admins allowed to restrict members.
+    admins: Arc<Mutex<HashSet<u64>>>,
+    next_message_id: Arc<AtomicI32>,
+}
+
+impl FakeApi {
+    /// Serves the fake Bot API on a free local port.
+    ///
+    /// # Returns
+    ///
+    /// Returns the URL to hand to [`Bot::set_api_url`].
+    async fn serve(&self) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let router = Router::new()
+            .route("/:token/:method", post(answer_call))
+            .with_state(self.clone());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        format!("http://{}", address).parse().unwrap()
+    }
+
+    fn calls(&self, method: &str) -> Vec<ApiCall> {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|call| call.method == method)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Records a Bot API request and answers it the way Telegram would.
+async fn answer_call(
+    AxumState(api): AxumState<FakeApi>,
+    Path((_token, method)): Path<(String, String)>,
+    body: Bytes,
+) -> Json<Value> {
+    // teloxide names methods in upper camel case, e.g. "SendMessage"
+    let mut chars = method.chars();
+    let method = match chars.next() {
+        Some(first) => first.to_ascii_lowercase().to_string() + chars.as_str(),
+        None => method,
+    };
+    let params: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+
+    let result = match method.as_str() {
+        "getMe" => me_json(),
+        "sendMessage" => json!({
+            "message_id": api.next_message_id.fetch_add(1, Ordering::SeqCst) + 1,
+            "date": Utc::now().timestamp(),
+            "chat": chat_json(params["chat_id"].as_i64().unwrap_or_default()),
+            "text": params["text"],
+        }),
+        "copyMessage" => json!({
+            "message_id": api.next_message_id.fetch_add(1, Ordering::SeqCst) + 1,
+        }),
+        "getChatMember" => {
+            let user = params["user_id"].as_u64().unwrap_or_default();
+            member_json(user, api.admins.lock().unwrap().contains(&user))
+        }
+        _ => json!(true),
+    };
+
+    api.calls.lock().unwrap().push(ApiCall { method, params });
+    Json(json!({ "ok": true, "result": result }))
+}
+
+fn me_json() -> Value {
+    json!({
+        "id": BOT_ID,
+        "is_bot": true,
+        "first_name": "Pharmacy",
+        "username": "pharmacy_test_bot",
+        "can_join_groups": true,
+        "can_read_all_group_messages": false,
+        "supports_inline_queries": false,
+        "can_connect_to_business": false,
+        "has_main_web_app": false,
+    })
+}
+
+/// Group chats have negative IDs, like on Telegram.
+fn chat_json(id: i64) -> Value {
+    if id < 0 {
+        json!({ "id": id, "type": "supergroup", "title": "Pharmacy" })
+    } else {
+        json!({ "id": id, "type": "private", "first_name": "Test" })
+    }
+}
+
+fn user_json(id: u64) -> Value {
+    json!({ "id": id, "is_bot": false, "first_name": format!("User{}", id), "language_code": "en" })
+}
+
+fn member_json(user: u64, admin: bool) -> Value {
+    if admin {
+        json!({
+            "user": user_json(user),
+            "status": "administrator",
+            "can_be_edited": false,
+            "is_anonymous": false,
+            "can_manage_chat": true,
+            "can_change_info": true,
+            "can_delete_messages": true,
+            "can_manage_video_chats": true,
+            "can_invite_users": true,
+            "can_restrict_members": true,
+            "can_promote_members": false,
+        })
+    } else {
+        json!({ "user": user_json(user), "status": "member" })
+    }
+}
+
+/// Picks a random ID, so no test relies on particular users or chats.
+fn unique_id() -> u64 {
+    1_000_000_000 + rand::random::<u64>() % 1_000_000_000
+}
+
+/// The bot wired up as in `main`, talking to a fake Bot API.
+///
+/// Runs without a database: the repositories live in a [`MemoryStore`], the
+/// outbound queue keeps its messages in memory and dialogues use the
+/// in-memory storage. The pool is never connected, so a flow that still
+/// reaches Postgres fails the test rather than passing silently.
+struct Harness {
+    api: FakeApi,
+    handler: UpdateHandler<Error>,
+    dependencies: DependencyMap,
+    store: MemoryStore,
+    next_update_id: AtomicI32,
+}
+
+impl Harness {
+    /// Starts the bot against a fake Bot API.
+    async fn start(medicines: Vec<Medicine>) -> Self {
+        let api = FakeApi::default();
+        let bot = Bot::new("1234:TEST").set_api_url(api.serve().await);
+        let me: Me = serde_json::from_value(me_json()).unwrap();
+
+        let staff_chat = -(unique_id() as i64);
+        let config = Config::init_from_hashmap(&HashMap::from([
+            ("TELEGRAM_BOT_TOKEN".to_string(), "1234:TEST".to_string()),
+            (
+                "DATABASE_URL".to_string(),
+                "postgres://localhost/unused".to_string(),
+            ),
+            ("PHARMACY_GROUP_CHAT_ID".to_string(), staff_chat.to_string()),
+        ]))
+        .unwrap();
+
+        let pool = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_secs(1))
+            .connect_lazy(&config.database_url)
+            .unwrap();
+        let store = MemoryStore::with_medicines(medicines);
+        let repositories = Repositories::memory(store.clone());
+        let queue = MessageQueue::start_in_memory(bot.clone(), config.queue_settings());
+        let captcha = Captcha::new(bot.clone(), pool.clone(), config.captcha_timeout());
+        let jobs = JobRegistry::new(
+            config.job_context(pool.clone(), repositories.clone(), queue.clone()),
+            JobSchedules::from_config(Tz::UTC, "").unwrap(),
+        );
+        let spam_filter = SpamFilter::new(config.filter_rules().unwrap());
+        let storage = dialogues::storage::<State>(StorageKind::Memory, pool.clone(), None);
+
+        let mut dependencies = Services {
+            config: Arc::new(config),
+            pool,
+            repositories,
+            queue,
+            jobs,
+            spam_filter,
+            captcha,
+            storage,
+        }
+        .dependencies();
+        dependencies.insert(bot);
+        dependencies.insert(me);
+
+        Self {
+            api,
+            handler: schema(),
+            dependencies,
+            store,
+            next_update_id: AtomicI32::new(1),
+        }
+    }
+
+    /// Runs an update through the handlers, as the dispatcher would.
+    async fn send(&self, message: Value) {
+        // Parsed from text, as updates from Telegram are; teloxide doesn't
+        // parse updates from a `Value`
+        let update = json!({
+            "update_id": self.next_update_id.fetch_add(1, Ordering::SeqCst),
+            "message": message,
+        });
+        let update: Update = serde_json::from_str(&update.to_string()).unwrap();
+        assert!(
+            !matches!(update.kind, UpdateKind::Error(_)),
+            "malformed update {}",
+            update.id.0
+        );
+
+        let mut dependencies = self.dependencies.clone();
+        dependencies.insert(update);
+        match self.handler.dispatch(dependencies).await {
+            ControlFlow::Break(result) => result.unwrap(),
+            ControlFlow::Continue(_) => panic!("no handler took the update"),
+        }
+    }
+
+    /// The texts of the messages the bot sent to a chat, oldest first.
+    fn messages_to(&self, chat: i64) -> Vec<String> {
+        self.api
+            .calls("sendMessage")
+            .iter()
+            .filter(|call| call.chat_id() == Some(chat))
+            .map(|call| call.text().to_string())
+            .collect()
+    }
+
+    /// Waits for the outbound queue to send a message containing `text`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the request that sent it.
+    async fn wait_for_message(&self, text: &str) -> ApiCall {
+        let deadline = tokio::time::Instant::now() + QUEUE_TIMEOUT;
+        loop {
+            let sent = self.api.calls("sendMessage");
+            if let Some(call) = sent.iter().find(|call| call.text().contains(text)) {
+                return call.clone();
+            }
+            if tokio::time::Instant::now() > deadline {
+                panic!("no message containing {:?} was sent, got {:?}", text, sent);
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+}
+
+/// A text message from a user in their private chat with the bot.
+fn private_message(user: u64, text: &str) -> Value {
+    json!({
+        "message_id": rand::random::<u16>() as i32 + 1,
+        "from": user_json(user),
+        "chat": chat_json(user as i64),
+        "date": Utc::now().timestamp(),
+        "text": text,
+    })
+}
+
+/// A text message from a user in a group.
+fn group_message(chat: i64, user: u64, text: &str) -> Value {
+    json!({
+        "message_id": rand::random::<u16>() as i32 + 1,
+        "from": user_json(user),
+        "chat": chat_json(chat),
+        "date": Utc::now().timestamp(),
+        "text": text,
+    })
+}
+
+fn paracetamol(stock: i32) -> Medicine {
+    Medicine {
+        id: 1,
+        name: "Paracetamol".to_string(),
+        stock,
+        expiry_date: NaiveDate::from_ymd_opt(2030, 1, 1).unwrap(),
+    }
+}
+
+#[tokio::test]
+async fn inventory_lists_every_medicine() {
+    let harness = Harness::start(vec![paracetamol(40)]).await;
+    let customer = unique_id();
+
+    harness.send(private_message(customer, "/inventory")).await;
+
+    let replies = harness.messages_to(customer as i64);
+    assert_eq!(replies.len(), 1, "{:?}", replies);
+    assert!(replies[0].starts_with("*Available medicines:*"));
+    assert!(replies[0].contains("Paracetamol"));
+    assert!(replies[0].contains("Stock: 40 units"));
+}
+
+#[tokio::test]
+async fn order_is_confirmed_and_sent_for_approval() {
+    let harness = Harness::start(vec![paracetamol(40)]).await;
+    let customer = unique_id();
+
+    harness.send(private_message(customer, "/order")).await;
+
+    let replies = harness.messages_to(customer as i64);
+    assert_eq!(replies.len(), 1, "{:?}", replies);
+    assert!(replies[0].starts_with("Order placed successfully"));
+
+    let orders = harness.store.orders();
+    assert_eq!(orders.len(), 1);
+    assert_eq!(orders[0].user_id, customer.to_string());
+    let medicine = harness.store.find(1).await.unwrap().unwrap();
+    assert_eq!(medicine.stock, 38);
+
+    // The approval request goes through the outbound queue to whoever is on
+    // duty, which is the staff group unless the rota names a pharmacist
+    let approval = harness
+        .wait_for_message(&format!("New order #{} awaiting approval", orders[0].id))
+        .await;
+    assert!(approval.text().ends_with("2 × Paracetamol"));
+}
+
+#[tokio::test]
+async fn order_beyond_the_stock_is_refused() {
+    let harness = Harness::start(vec![paracetamol(1)]).await;
+    let customer = unique_id();
+
+    harness.send(private_message(customer, "/order")).await;
+
+    assert_eq!(
+        harness.messages_to(customer as i64),
+        ["Insufficient stock: only 1 left of Paracetamol."]
+    );
+    assert!(harness.store.orders().is_empty());
+}
+
+#[tokio::test]
+async fn anonymous_message_reaches_the_pharmacist() {
+    let harness = Harness::start(vec![]).await;
+    let pharmacist = unique_id();
+    let customer = unique_id();
+
+    // The pharmacist creates a link and shares it
+    harness.send(private_message(pharmacist, "/message")).await;
+    let link = harness.messages_to(pharmacist as i64).remove(0);
+    let token = link
+        .split("?start=")
+        .nth(1)
+        .and_then(|rest| rest.split_whitespace().next())
+        .expect("the reply should contain the link");
+
+    // The customer opens it and writes
+    harness
+        .send(private_message(customer, &format!("/start {}", token)))
+        .await;
+    harness
+        .send(private_message(customer, "Can I take this with ibuprofen?"))
+        .await;
+
+    let relayed = harness.messages_to(pharmacist as i64);
+    assert_eq!(relayed.len(), 2, "{:?}", relayed);
+    assert!(relayed[1].starts_with("You have a new anonymous message:"));
+    assert!(relayed[1].contains("Can I take this with ibuprofen?"));
+    assert!(!relayed[1].contains(&customer.to_string()));
+
+    let replies = harness.messages_to(customer as i64);
+    assert_eq!(replies[0], "Send your message to the pharmacist:");
+    assert!(replies[1].starts_with("Message sent to the pharmacist!"));
+}
+
+#[tokio::test]
+async fn ban_by_reply_bans_the_author() {
+    let harness = Harness::start(vec![]).await;
+    let group = -(unique_id() as i64);
+    let pharmacist = unique_id();
+    let spammer = unique_id();
+    harness.store.set_role(UserId(pharmacist), Role::Pharmacist);
+    harness
+        .api
+        .admins
+        .lock()
+        .unwrap()
+        .extend([pharmacist, BOT_ID]);
+
+    let mut ban = group_message(group, pharmacist, "/ban 1d");
+    ban["reply_to_message"] = group_message(group, spammer, "Cheap pills, DM me");
+    harness.send(ban).await;
+
+    let bans = harness.api.calls("banChatMember");
+    assert_eq!(bans.len(), 1, "{:?}", bans);
+    assert_eq!(bans[0].chat_id(), Some(group));
+    assert_eq!(bans[0].params["user_id"].as_u64(), Some(spammer));
+    assert!(bans[0].params["until_date"].is_i64());
+
+    let replies = harness.messages_to(group);
+    assert_eq!(replies.len(), 1, "{:?}", replies);
+    assert!(replies[0].contains("has been banned"));
+}